@@ -1,11 +1,21 @@
-use axum::{extract::{State, Json}, response::IntoResponse, http::StatusCode};
-use std::{collections::{HashSet, HashMap}, sync::atomic::Ordering};
+use axum::{extract::{State, Json, Path, Query, Request}, middleware::Next, response::IntoResponse, http::StatusCode};
+use std::{collections::{HashSet, HashMap}, sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
 use futures::{stream, StreamExt};
 use rustc_hash::FxHashMap;
 
-use crate::AppState;
+use crate::{AppState, Route, Storage, READY_PROBE_FILE, MAX_CACHE_SIZE};
+use crate::storage::{FileBackend, StorageBackend};
 use crate::models::{self, *};
-use crate::logic::{execute_trade_logic, pricing::PricingEngine, environment};
+use crate::logic::{execute_trade_logic, pricing::{IdleRecovery, PricingEngine}, environment};
+
+// 单次导入允许的最大玩家数
+const MAX_IMPORT_PLAYERS: usize = 10_000;
+// 单次导入允许的最大记录总数 (所有玩家、所有物品合计)
+const MAX_IMPORT_RECORDS: usize = 1_000_000;
+// 单次行情查询允许的阶梯报价数量
+const MAX_PRICE_TIERS: usize = 16;
+// 当前响应契约版本 (未指定版本时返回完整字段)
+pub const LATEST_API_VERSION: u32 = 2;
 
 // =========================================================================
 // 1. 错误处理与验证
@@ -15,17 +25,211 @@ use crate::logic::{execute_trade_logic, pricing::PricingEngine, environment};
 pub enum ApiError {
     #[error("请求参数错误: {0}")]
     BadRequest(String),
+    #[error("请求体过大")]
+    PayloadTooLarge,
+    #[error("玩家历史正忙且没有可用的价格快照，请稍后重试")]
+    HistoryBusy,
+    #[error("物品 {item_id} 价格波动过大已熔断，请在 {retry_after_secs} 秒后重试")]
+    CircuitOpen { item_id: String, retry_after_secs: i64 },
+    #[error("管理令牌无效")]
+    Unauthorized,
+    #[error("{0}")]
+    NotFound(String),
+    #[error("余额不足: 需要 {required:.2}，可用 {available:.2}")]
+    InsufficientFunds { required: f64, available: f64 },
+    #[error("confirm required")]
+    ConfirmRequired(Box<TradeResponse>),
+    #[error("批量请求共 {size} 行，超过上限 {max}")]
+    BatchTooLarge { size: usize, max: usize },
+    #[error("{action}冷却中：刚完成反向交易，请在 {retry_after_ms} 毫秒后重试")]
+    ArbitrageCooldown { action: &'static str, retry_after_ms: i64 },
+    #[error("定价超时 ({0} 毫秒)")]
+    Timeout(u64),
+    #[error("stale catalog: {0}")]
+    StaleCatalog(String),
+    #[error("同步代次 {generation} 已过期 (当前 {current})")]
+    StaleSync { generation: u64, current: u64 },
+    #[error("服务正在关闭，暂停接受新的成交")]
+    Draining,
+    #[error("只读模式：拒绝一切写入")]
+    ReadOnly,
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
+        // 附带报价，客户端确认后带 confirmed 重发
+        if let Self::ConfirmRequired(quote) = &self {
+            let body = serde_json::json!({ "error": self.to_string(), "quote": quote });
+            return (StatusCode::CONFLICT, Json(body)).into_response();
+        }
         let status = match self {
             Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::PayloadTooLarge | Self::BatchTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::HistoryBusy | Self::Draining | Self::ReadOnly => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            Self::CircuitOpen { .. } | Self::ArbitrageCooldown { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::InsufficientFunds { .. } => StatusCode::PAYMENT_REQUIRED,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::ConfirmRequired(_) | Self::StaleSync { .. } | Self::StaleCatalog(_) => StatusCode::CONFLICT,
         };
         (status, Json(serde_json::json!({ "error": self.to_string() }))).into_response()
     }
 }
 
+/// 将框架层的 413 统一为标准错误信封
+pub async fn normalize_payload_too_large(resp: axum::response::Response) -> axum::response::Response {
+    if resp.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return ApiError::PayloadTooLarge.into_response();
+    }
+    resp
+}
+
+/// strict-json 特性下将请求体反序列化失败 (框架层的 422) 统一为带字段名的 400 错误信封
+pub async fn normalize_json_rejection(resp: axum::response::Response) -> axum::response::Response {
+    if !cfg!(feature = "strict-json") || resp.status() != StatusCode::UNPROCESSABLE_ENTITY {
+        return resp;
+    }
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap_or_default();
+    ApiError::BadRequest(String::from_utf8_lossy(&body).into_owned()).into_response()
+}
+
+/// 管理接口鉴权：要求请求头 X-Admin-Token 与 admin_token 匹配；未配置令牌时管理接口一律拒绝
+pub async fn require_admin(State(state): State<AppState>, req: Request, next: Next) -> axum::response::Response {
+    let expected = state.config.read().admin_token.clone();
+    let provided = req.headers().get("x-admin-token").and_then(|v| v.to_str().ok());
+    if expected.is_empty() || provided != Some(expected.as_str()) {
+        return ApiError::Unauthorized.into_response();
+    }
+    next.run(req).await
+}
+
+/// 只读模式 (启动时由环境变量开启) 下拒绝写入型路由，读取照常
+pub async fn reject_when_read_only(State(state): State<AppState>, req: Request, next: Next) -> axum::response::Response {
+    if state.read_only.load(Ordering::Relaxed) {
+        return ApiError::ReadOnly.into_response();
+    }
+    next.run(req).await
+}
+
+// =========================================================================
+// 1.1 响应版本整形
+// =========================================================================
+
+/// 旧版客户端 (`?v=1` 或请求头 X-Api-Version: 1) 只收到该版本契约内的字段；
+/// 带宽受限的客户端 (`?fields=minimal`) 只收到成交响应的必要字段
+pub async fn shape_response_version(req: Request, next: Next) -> axum::response::Response {
+    let version = requested_version(&req).filter(|v| *v < LATEST_API_VERSION);
+    let minimal = query_param(&req, "fields") == Some("minimal");
+    let path = req.uri().path().to_string();
+    let resp = next.run(req).await;
+    if version.is_none() && !minimal {
+        return resp;
+    }
+    reshape_response(resp, version, minimal, &path).await
+}
+
+fn query_param<'a>(req: &'a Request, key: &str) -> Option<&'a str> {
+    req.uri().query()?.split('&').find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+}
+
+fn requested_version(req: &Request) -> Option<u32> {
+    let from_query = query_param(req, "v").and_then(|v| v.parse().ok());
+    from_query.or_else(|| {
+        req.headers().get("x-api-version")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+    })
+}
+
+/// 按版本与字段模式裁剪 JSON 响应体；非 JSON 响应原样返回
+async fn reshape_response(resp: axum::response::Response, version: Option<u32>, minimal: bool, path: &str) -> axum::response::Response {
+    let (mut parts, body) = resp.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "响应体读取失败").into_response();
+    };
+    let Ok(mut value) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+        return axum::response::Response::from_parts(parts, axum::body::Body::from(bytes));
+    };
+    if version.is_some_and(|v| v <= 1) {
+        trim_to_v1(path, &mut value);
+    }
+    // 错误信封不裁剪，客户端仍能看到 error 字段
+    if minimal && parts.status.is_success() {
+        trim_to_minimal(path, &mut value);
+    }
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    axum::response::Response::from_parts(parts, axum::body::Body::from(value.to_string()))
+}
+
+/// v1 契约：行情不含 stale / tiers / effectiveLambda，成交响应不含 tradeId
+fn trim_to_v1(path: &str, value: &mut serde_json::Value) {
+    match path {
+        "/calculate_sell" | "/calculate_buy" => {
+            if let Some(resp) = value.as_object_mut() {
+                resp.remove("tradeId");
+                resp.remove("quoteEnvIndex");
+                resp.remove("envFloored");
+                resp.remove("serverLoad");
+                resp.remove("payout");
+                resp.remove("payoutCurrency");
+            }
+            return;
+        }
+        "/batch_sell" | "/batch_buy" => {
+            if let Some(batch) = value.as_object_mut() {
+                for key in ["succeeded", "failed", "totalValue"] { batch.remove(key); }
+            }
+            for resp in value["results"].as_array_mut().into_iter().flatten().filter_map(|r| r.as_object_mut()) {
+                resp.remove("tradeId");
+                resp.remove("quoteEnvIndex");
+                resp.remove("envFloored");
+                resp.remove("serverLoad");
+                resp.remove("payout");
+                resp.remove("payoutCurrency");
+            }
+            return;
+        }
+        "/api/market/prices" => {}
+        _ => return,
+    }
+    if let Some(quote) = value.as_object_mut() {
+        quote.remove("stale");
+        quote.remove("envIndexBase");
+        quote.remove("envComputedAt");
+        quote.remove("envFloored");
+        quote.remove("serverLoad");
+        quote.remove("tiers");
+        quote.remove("truncated");
+        quote.remove("nextCursor");
+    }
+    if let Some(items) = value["items"].as_object_mut() {
+        for status in items.values_mut().filter_map(|s| s.as_object_mut()) {
+            status.remove("effectiveLambda");
+            status.remove("displayPrice");
+            status.remove("priceLabel");
+            status.remove("explanation");
+        }
+    }
+}
+
+/// 精简模式下成交响应保留的字段
+const MINIMAL_TRADE_FIELDS: [&str; 4] = ["success", "message", "totalPrice", "tradeId"];
+
+fn trim_to_minimal(path: &str, value: &mut serde_json::Value) {
+    let responses = match path {
+        "/calculate_sell" | "/calculate_buy" => std::slice::from_mut(value),
+        "/batch_sell" | "/batch_buy" => match value["results"].as_array_mut() {
+            Some(results) => results.as_mut_slice(),
+            None => return,
+        },
+        _ => return,
+    };
+    for resp in responses.iter_mut().filter_map(|r| r.as_object_mut()) {
+        resp.retain(|key, _| MINIMAL_TRADE_FIELDS.contains(&key.as_str()));
+    }
+}
+
 impl TradeRequest {
     fn validate(&self) -> Result<(), ApiError> {
         if self.amount.abs() <= 1e-10 { 
@@ -38,25 +242,115 @@ impl TradeRequest {
     }
 }
 
+/// 单笔交易量低于物品配置的下限时拒绝交易
+fn check_min_amount(item: Option<&MarketItem>, req: &TradeRequest) -> Result<(), ApiError> {
+    match item.and_then(|i| i.min_trade_amount) {
+        Some(min) if req.amount.abs() < min => Err(ApiError::BadRequest(
+            format!("物品 {} 单笔交易量不得少于 {}", req.item_id, min)
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// 已同步物品的有效基准价非正 (如 NaN 被 sanitize 归零) 时暂停交易，不再按客户端携带的 base_price 结算
+fn check_tradable(item: Option<&MarketItem>, req: &TradeRequest) -> Result<(), ApiError> {
+    match item {
+        Some(item) if !(item.effective_base_price(chrono::Utc::now().timestamp_millis()) > 0.0) => Err(ApiError::BadRequest(
+            format!("物品 {} 的基准价无效，暂停交易", req.item_id)
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// require_known_item 下拒绝未同步的物品，以及与服务端参数不一致的过期目录
+fn check_catalog(config: &AppConfig, item: Option<&MarketItem>, req: &TradeRequest) -> Result<(), ApiError> {
+    if !config.require_known_item { return Ok(()); }
+    let Some(item) = item else {
+        return Err(ApiError::NotFound(format!("未知物品 {}", req.item_id)));
+    };
+    let tol = config.catalog_tolerance.max(0.0);
+    let close = |client: f64, server: f64| (client - server).abs() <= tol * server.abs().max(1.0);
+    if !close(req.base_price, item.base_price) || !close(req.decay_lambda, item.lambda) {
+        return Err(ApiError::StaleCatalog(format!(
+            "物品 {} 服务端参数为 base_price={}, lambda={}，请重新同步", item.id, item.base_price, item.lambda
+        )));
+    }
+    Ok(())
+}
+
+/// 反套利冷却：同一玩家同一物品在 arbitrage_cooldown_ms 内不能做反向交易 (预览不受限)
+fn check_arbitrage(state: &AppState, config: &AppConfig, req: &TradeRequest, is_buy: bool) -> Result<(), ApiError> {
+    if req.is_preview || config.arbitrage_cooldown_ms <= 0 { return Ok(()); }
+    let key = (req.player_id.clone(), req.item_id.clone());
+    let Some(&(last_buy, last_sell)) = state.trade_sides.read().get(&key) else { return Ok(()); };
+    let opposite = if is_buy { last_sell } else { last_buy };
+    let remaining = opposite + config.arbitrage_cooldown_ms - chrono::Utc::now().timestamp_millis();
+    if opposite > 0 && remaining > 0 {
+        return Err(ApiError::ArbitrageCooldown { action: if is_buy { "买入" } else { "卖出" }, retry_after_ms: remaining });
+    }
+    Ok(())
+}
+
+// 反套利记录达到该条数 (之后每翻一倍) 时清理已过冷却期的条目
+const TRADE_SIDES_PRUNE_AT: usize = 1_024;
+
+/// 记录成交方向的时间，供反套利冷却判断；冷却关闭时不记录
+/// 条数每到 TRADE_SIDES_PRUNE_AT 的 2 的幂倍时清理双向都已过冷却期的条目，均摊开销为常数
+fn note_trade_side(state: &AppState, cooldown_ms: i64, record: &TransactionRecord, is_buy: bool) {
+    if cooldown_ms <= 0 { return; }
+    let mut sides = state.trade_sides.write();
+    let entry = sides.entry((record.player_id.clone(), record.item_id.clone())).or_default();
+    if is_buy { entry.0 = record.timestamp; } else { entry.1 = record.timestamp; }
+    if sides.len() >= TRADE_SIDES_PRUNE_AT && sides.len().is_power_of_two() {
+        let expired_before = chrono::Utc::now().timestamp_millis() - cooldown_ms;
+        sides.retain(|_, &mut (buy, sell)| buy.max(sell) > expired_before);
+    }
+}
+
+/// 物品处于熔断冷却期时拒绝交易
+fn check_breaker(state: &AppState, item_id: &str) -> Result<(), ApiError> {
+    let now = chrono::Utc::now().timestamp();
+    match state.item_breakers.read().get(item_id) {
+        Some(&until) if until > now => Err(ApiError::CircuitOpen {
+            item_id: item_id.to_string(),
+            retry_after_secs: until - now,
+        }),
+        _ => Ok(()),
+    }
+}
+
 // =========================================================================
 // 2. 交易核心路由 (Trade Handlers)
 // =========================================================================
 
 pub async fn handle_sell(s: State<AppState>, j: Json<TradeRequest>) -> impl IntoResponse {
-    process_trade(s, j, false).await
+    s.metrics.route_requests.hit(Route::Sell);
+    process_trade(s, j, TradeAction::Sell).await
 }
 
 pub async fn handle_buy(s: State<AppState>, j: Json<TradeRequest>) -> impl IntoResponse {
-    process_trade(s, j, true).await
+    s.metrics.route_requests.hit(Route::Buy);
+    process_trade(s, j, TradeAction::Buy).await
 }
 
 async fn process_trade(
     State(state): State<AppState>, 
     Json(req): Json<TradeRequest>, 
-    is_buy: bool
+    default_action: TradeAction
 ) -> impl IntoResponse {
+    match execute_trade(&state, req, default_action).await {
+        Ok(resp) => Json(resp).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// 交易主流程 (HTTP 与 WebSocket 共用)，请求未携带 action 时使用 `default_action`
+pub(crate) async fn execute_trade(state: &AppState, req: TradeRequest, default_action: TradeAction) -> Result<TradeResponse, ApiError> {
+    state.metrics.request_rate.hit(chrono::Utc::now().timestamp());
     // 1. 输入验证
-    if let Err(e) = req.validate() { return e.into_response(); }
+    req.validate()?;
+    check_writable(state, req.is_preview)?;
+    check_breaker(state, &req.item_id)?;
 
     // 2. 获取状态快照
     let config = state.config.read().clone();
@@ -64,28 +358,88 @@ async fn process_trade(
     let player_history = state.player_histories.read()
         .get(&req.player_id).cloned().unwrap_or_default();
 
-    // 3. [新增] 获取当前物品的持久化状态 n (解决重启重置问题)
-    let current_n = {
-        let market = state.market_cache.read();
-        market.iter()
-            .find(|i| i.id == req.item_id)
-            .map(|i| i.n)
-            .unwrap_or(0.0)
-    };
+    // 3. [新增] 获取当前物品的持久化状态 (n 解决重启重置问题、服务端 lambda、分类)
+    let market_item = find_market_item(state, &req.item_id);
+    check_catalog(&config, market_item.as_ref(), &req)?;
+    check_min_amount(market_item.as_ref(), &req)?;
+    check_tradable(market_item.as_ref(), &req)?;
+    let action = req.action.clone().unwrap_or(default_action);
+    check_arbitrage(state, &config, &req, action.is_buy())?;
+
+    // 4. 执行纯计算逻辑
+    let started = Instant::now();
+    let (resp, record) = with_deadline(&config, req.is_preview, execute_trade_logic(
+        &req, &config, &holidays, &player_history, &action,
+        &state.env_cache, &state.http_client, &state.mojang_limiter,
+        market_item.as_ref(),
+    )).await?;
+    state.metrics.trade_pricing_latency.record(started.elapsed());
+
+    // 5. 结算余额 (余额不足的买入在此被拒绝，不落盘)，分配成交序号后异步持久化
+    let mut resp = TradeResponse { server_load: server_load(state, &config), ..resp };
+    if let Some(mut r) = record {
+        check_confirmation(&config, market_item.as_ref(), &req, &r, &resp)?;
+        settle_balance(state, &r, action.is_buy())?;
+        note_trade_side(state, config.arbitrage_cooldown_ms, &r, action.is_buy());
+        assign_trade_id(state, &mut resp, &mut r);
+        tokio::spawn(persist_transaction(state.clone(), r, action.is_buy()));
+    }
+
+    Ok(resp)
+}
+
+/// 只读模式与关闭前的排空阶段只放行预览 (不产生写入)
+fn check_writable(state: &AppState, is_preview: bool) -> Result<(), ApiError> {
+    if is_preview {
+        return Ok(());
+    }
+    if state.read_only.load(Ordering::Relaxed) {
+        return Err(ApiError::ReadOnly);
+    }
+    if state.draining.load(Ordering::Acquire) {
+        return Err(ApiError::Draining);
+    }
+    Ok(())
+}
 
-    // 4. 执行纯计算逻辑 (传入 current_n)
-    let (resp, record) = execute_trade_logic(
-        &req, &config, &holidays, &player_history, is_buy, 
-        &state.env_cache, &state.http_client,
-        current_n // <--- 关键参数
-    ).await;
+/// 按预览 / 成交分别限时执行定价
+async fn with_deadline<T>(config: &AppConfig, is_preview: bool, fut: impl std::future::Future<Output = T>) -> Result<T, ApiError> {
+    let ms = if is_preview { config.preview_timeout_ms } else { config.trade_timeout_ms };
+    tokio::time::timeout(Duration::from_millis(ms), fut).await.map_err(|_| ApiError::Timeout(ms))
+}
 
-    // 5. 异步持久化
-    if let Some(r) = record {
-        tokio::spawn(persist_transaction(state, r));
+/// 大额成交 (超过物品或全局的 confirm_threshold) 需请求携带 confirmed，否则退回报价
+fn check_confirmation(config: &AppConfig, item: Option<&MarketItem>, req: &TradeRequest, record: &TransactionRecord, quote: &TradeResponse) -> Result<(), ApiError> {
+    let threshold = item.and_then(|i| i.confirm_threshold)
+        .or((config.confirm_threshold > 0.0).then_some(config.confirm_threshold));
+    match threshold {
+        Some(limit) if record.total_price > limit && !req.confirmed => Err(ApiError::ConfirmRequired(Box::new(quote.clone()))),
+        _ => Ok(()),
     }
+}
+
+/// 服务端负载 (0.0–1.0)：写入通道占用率与近一秒请求速率中较高者
+pub(crate) fn server_load(state: &AppState, config: &AppConfig) -> f64 {
+    let fill = 1.0 - state.tx.capacity() as f64 / state.tx.max_capacity() as f64;
+    let rate = if config.load_reference_rps > 0.0 {
+        state.metrics.request_rate.per_sec(chrono::Utc::now().timestamp()) as f64 / config.load_reference_rps
+    } else {
+        0.0
+    };
+    fill.max(rate).clamp(0.0, 1.0).round_2()
+}
+
+/// 为即将落盘的成交分配单调递增的序号，并作为 tradeId 返回
+fn assign_trade_id(state: &AppState, resp: &mut TradeResponse, record: &mut TransactionRecord) {
+    let seq = state.trade_seq.fetch_add(1, Ordering::Relaxed) + 1;
+    state.dirty.trade_seq.store(true, Ordering::Relaxed);
+    record.seq = seq;
+    resp.trade_id = Some(seq);
+}
 
-    Json(resp).into_response()
+/// 已同步物品的当前状态副本 (未同步时为 None)
+fn find_market_item(state: &AppState, item_id: &str) -> Option<MarketItem> {
+    state.market_cache.read().iter().find(|i| i.id == item_id).cloned()
 }
 
 // =========================================================================
@@ -96,59 +450,260 @@ pub async fn get_market_prices(
     State(state): State<AppState>,
     Json(payload): Json<MarketPriceRequest>,
 ) -> impl IntoResponse {
+    state.metrics.request_rate.hit(chrono::Utc::now().timestamp());
+    state.metrics.route_requests.hit(Route::Quote);
+    let started = Instant::now();
+    let quote = quote_market_page(&state, payload.item_ids, &payload.quantities, payload.cursor.as_deref(), payload.explain).await;
+    state.metrics.market_snapshot_latency.record(started.elapsed());
+    match quote {
+        Ok(mut quote) => {
+            if payload.format_price {
+                let config = state.config.read();
+                for status in quote["items"].as_object_mut().into_iter().flat_map(|m| m.values_mut()) {
+                    if let Some(price) = status["price"].as_f64() {
+                        status["priceLabel"] = format_price_label(price, &config.price_thousands_separator, &config.price_currency_suffix).into();
+                    }
+                }
+            }
+            Json(quote).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// 两位小数、整数部分按千位分组，后缀为空时不加空格
+fn format_price_label(value: f64, separator: &str, suffix: &str) -> String {
+    let fixed = format!("{:.2}", value.abs());
+    let (int_part, frac_part) = fixed.split_once('.').unwrap_or((fixed.as_str(), "00"));
+    let mut grouped = String::new();
+    for (i, digit) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 { grouped.push_str(separator); }
+        grouped.push(digit);
+    }
+    let sign = if value < 0.0 && fixed != "0.00" { "-" } else { "" };
+    let number = format!("{}{}.{}", sign, grouped, frac_part);
+    if suffix.is_empty() { number } else { format!("{} {}", number, suffix) }
+}
+
+/// 计算行情快照 (HTTP 与 WebSocket 共用)，`item_ids` 为空表示全部物品
+/// `quantities` 非空时按当前库存附带每个物品的阶梯报价 (降级到快照时不返回)
+/// 玩家历史读锁在等待期限内拿不到且没有可用快照时返回 HistoryBusy，不阻塞运行时线程
+pub(crate) async fn quote_market(state: &AppState, item_ids: Vec<String>, quantities: &[f64]) -> Result<serde_json::Value, ApiError> {
+    quote_market_page(state, item_ids, quantities, None, false).await
+}
+
+/// 按物品 ID 排序分页的行情快照：超过 max_items_per_response 时截断并返回 nextCursor (本页最后一个 ID)
+/// `explain` 为真时每个物品附带由同一组定价分量拼成的 explanation (不进入快照)
+pub(crate) async fn quote_market_page(state: &AppState, item_ids: Vec<String>, quantities: &[f64], cursor: Option<&str>, explain: bool) -> Result<serde_json::Value, ApiError> {
+    if quantities.len() > MAX_PRICE_TIERS {
+        return Err(ApiError::BadRequest(format!("阶梯报价最多 {} 档", MAX_PRICE_TIERS)));
+    }
+    if quantities.iter().any(|q| !q.is_finite() || *q <= 0.0) {
+        return Err(ApiError::BadRequest("阶梯数量必须为正数".into()));
+    }
+
     let config = state.config.read().clone();
     let market_items = state.market_cache.read().clone();
     
-    let (env_index, env_note) = environment::calculate_current_env_index(
+    let (env_index, env_base, env_note) = environment::current_env_components(
         &config, &state.holidays.read(), &state.env_cache
     );
+    // 指数实际计算的时刻 (缓存条目的秒级时间戳，转为毫秒与 serverTime 对齐)
+    let env_computed_at = state.env_cache.read().as_ref().map(|c| c.timestamp * 1000);
 
-    let target_ids: HashSet<String> = if payload.item_ids.is_empty() {
-        market_items.iter().map(|i| i.id.clone()).collect()
-    } else {
-        payload.item_ids.into_iter().collect()
-    };
+    let requested: HashSet<String> = item_ids.into_iter().collect();
+    let mut ordered: Vec<String> = market_items.iter()
+        .map(|i| i.id.clone())
+        .filter(|id| requested.is_empty() || requested.contains(id))
+        .filter(|id| cursor.is_none_or(|c| id.as_str() > c))
+        .collect();
+    ordered.sort_unstable();
+    let cap = config.max_items_per_response;
+    let next_cursor = (cap > 0 && ordered.len() > cap).then(|| ordered[cap - 1].clone());
+    if cap > 0 { ordered.truncate(cap); }
+    let target_ids: HashSet<String> = ordered.into_iter().collect();
 
     let current_time = chrono::Utc::now().timestamp_millis();
     
-    // 计算基于历史的库存
-    let global_history_neff = calculate_global_neff_optimized(&state, &target_ids, &config, current_time).await;
+    // 计算基于历史的库存 (读锁争用时优先降级到价格快照)
+    let global_history_neff = match calculate_global_neff_optimized(state, &target_ids, &config, current_time).await {
+        Some(neff) => neff,
+        None => {
+            let Some(cached) = cached_prices(state, &target_ids, &config, current_time) else {
+                tracing::debug!("⏳ 玩家历史读锁繁忙且无可用快照，返回 503");
+                return Err(ApiError::HistoryBusy);
+            };
+            tracing::debug!("⏳ 玩家历史读锁繁忙，返回价格快照");
+            let mut quote = serde_json::json!({
+                "items": cached,
+                "envIndex": models::round_2(env_index),
+                "envIndexBase": models::round_2(env_base),
+                "envFloored": environment::is_floored(env_index),
+                "envNote": env_note,
+                "envComputedAt": env_computed_at,
+                "serverTime": current_time,
+                "serverLoad": server_load(state, &config),
+                "stale": true
+            });
+            attach_page(&mut quote, next_cursor);
+            return Ok(quote);
+        }
+    };
 
-    let response_items: FxHashMap<String, MarketItemStatus> = market_items.into_iter()
+    let mut tiers: FxHashMap<String, Vec<PriceTier>> = FxHashMap::default();
+    let mut explanations: FxHashMap<String, String> = FxHashMap::default();
+    let mut response_items: FxHashMap<String, MarketItemStatus> = market_items.into_iter()
         .filter(|i| target_ids.contains(&i.id))
-        .map(|item| {
+        .map(|mut item| {
+            item.base_price = item.effective_base_price(current_time);
             let history_n = global_history_neff.get(&item.id).copied().unwrap_or(0.0);
             
-            // [关键公式] N_total = N_history + N_static(持久化) + Iota(偏移) + Global
-            let final_neff = (history_n + item.n + item.iota + config.global_iota).max(0.0);
+            // [关键公式] N_total = N_history + N_static(持久化) + Iota(偏移) + Global + 开市溢价
+            let launch = PricingEngine::launch_iota(item.launch_iota, &config, current_time);
+            let final_neff = item.clamp_n(history_n + item.n + item.iota + config.global_iota + launch, &config);
             
-            let raw_price = env_index * item.base_price * (-item.lambda.abs() * final_neff).exp();
+            let lambda = item.lambda.abs();
+            let item_env = environment::item_env(env_index, env_base, &config, &item);
+            let decay = PricingEngine::exp_neg(lambda * final_neff, &config);
+            let raw_price = item_env * item.base_price * decay * config.global_price_multiplier;
+
+            if !quantities.is_empty() {
+                tiers.insert(item.id.clone(), price_tiers(&item, item_env, final_neff, quantities, &config));
+            }
+            if explain {
+                let multiplier = if config.global_price_multiplier != 1.0 {
+                    format!(" × {}", config.global_price_multiplier)
+                } else {
+                    String::new()
+                };
+                let cap = match item.max_price {
+                    Some(max) if raw_price > max => format!(" → max_price {}", max),
+                    _ => String::new(),
+                };
+                explanations.insert(item.id.clone(), format!(
+                    "Base {} × env {:.4} × decay(n={:.2}, λ={}){} = {:.2}{}",
+                    item.base_price, item_env, final_neff, lambda, multiplier, raw_price, cap
+                ));
+            }
             
-            (item.id, MarketItemStatus::new(
-                raw_price, 
-                raw_price * config.buy_premium, 
+            // 买入现价在乘以溢价之后再封顶
+            (item.id.clone(), MarketItemStatus::new(
+                item.cap_total(raw_price, 1.0), 
+                item.cap_total(raw_price * config.buy_premium, 1.0), 
                 final_neff, 
-                item.base_price
+                item.base_price,
+                lambda
             ))
         })
         .collect();
 
-    Json(serde_json::json!({
+    if config.display_ema_alpha > 0.0 {
+        for (id, status) in response_items.iter_mut() {
+            status.display_price = Some(update_price_ema(state, id, status.price, &config).round_2());
+        }
+    }
+
+    {
+        let mut snapshot = state.price_snapshot.write();
+        for (id, status) in &response_items {
+            snapshot.insert(id.clone(), (current_time, status.clone()));
+        }
+    }
+
+    let mut quote = serde_json::json!({
         "items": response_items,
         "envIndex": models::round_2(env_index),
+        "envIndexBase": models::round_2(env_base),
+        "envFloored": environment::is_floored(env_index),
         "envNote": env_note,
-        "serverTime": current_time
-    }))
+        "envComputedAt": env_computed_at,
+        "serverTime": current_time,
+        "serverLoad": server_load(state, &config),
+        "stale": false
+    });
+    if !quantities.is_empty() {
+        quote["tiers"] = serde_json::json!(tiers);
+    }
+    for (id, text) in explanations {
+        quote["items"][id.as_str()]["explanation"] = text.into();
+    }
+    attach_page(&mut quote, next_cursor);
+    Ok(quote)
+}
+
+fn attach_page(quote: &mut serde_json::Value, next_cursor: Option<String>) {
+    quote["truncated"] = next_cursor.is_some().into();
+    if let Some(cursor) = next_cursor {
+        quote["nextCursor"] = cursor.into();
+    }
+}
+
+/// 将现价并入物品展示价的 EMA 并返回新值 (首次观测直接取现价)
+fn update_price_ema(state: &AppState, item_id: &str, spot: f64, config: &AppConfig) -> f64 {
+    let alpha = config.display_ema_alpha.clamp(0.0, 1.0);
+    let mut emas = state.price_ema.write();
+    let ema = emas.entry(item_id.to_string()).or_insert(spot);
+    *ema += alpha * (spot - *ema);
+    *ema
+}
+
+/// 阶梯报价：每档按积分计算从当前库存起一次性成交该数量的总价
+fn price_tiers(item: &MarketItem, env: f64, n_eff: f64, quantities: &[f64], config: &AppConfig) -> Vec<PriceTier> {
+    let lambda = item.lambda.abs();
+    quantities.iter().map(|&quantity| {
+        let total = |is_buy| PricingEngine::calculate_price(
+            item.base_price, env, n_eff, quantity, lambda,
+            config.buy_premium, config.fresh_mint_surcharge, is_buy, config.allow_negative_n,
+        ) * config.global_price_multiplier;
+        let (sell_total, buy_total) = (item.cap_total(total(false), quantity), item.cap_total(total(true), quantity));
+        PriceTier {
+            quantity,
+            sell_total: sell_total.round_2(),
+            sell_avg: (sell_total / quantity).round_2(),
+            buy_total: buy_total.round_2(),
+            buy_avg: (buy_total / quantity).round_2(),
+        }
+    }).collect()
+}
+
+/// 所有目标物品均有未过期快照时返回快照价格，否则返回 None
+fn cached_prices(
+    state: &AppState,
+    targets: &HashSet<String>,
+    config: &AppConfig,
+    now_ms: i64
+) -> Option<FxHashMap<String, MarketItemStatus>> {
+    let ttl_ms = config.price_snapshot_ttl_secs.max(0) * 1000;
+    let snapshot = state.price_snapshot.read();
+    targets.iter()
+        .map(|id| {
+            snapshot.get(id)
+                .filter(|(ts, _)| now_ms - ts <= ttl_ms)
+                .map(|(_, status)| (id.clone(), status.clone()))
+        })
+        .collect()
+}
+
+/// 在 history_lock_timeout_ms 内轮询玩家历史读锁，拿不到时返回 None (不阻塞运行时线程)
+/// 返回的守卫不得跨 await 持有
+async fn read_histories_bounded<'a>(state: &'a AppState, config: &AppConfig)
+    -> Option<parking_lot::RwLockReadGuard<'a, HashMap<String, PlayerSalesHistory>>> {
+    let deadline = Instant::now() + Duration::from_millis(config.history_lock_timeout_ms);
+    loop {
+        if let Some(guard) = state.player_histories.try_read() { return Some(guard); }
+        if Instant::now() >= deadline { return None; }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
 }
 
 async fn calculate_global_neff_optimized(
     state: &AppState, 
     targets: &HashSet<String>, 
     config: &AppConfig, 
-    ts: i64
-) -> FxHashMap<String, f64> {
+    ts: i64,
+) -> Option<FxHashMap<String, f64>> {
     let history_snapshot: Vec<(String, Vec<SalesRecord>)> = {
-        let histories = state.player_histories.read();
+        let histories = read_histories_bounded(state, config).await?;
         histories.values()
             .flat_map(|h| {
                 h.item_sales.iter()
@@ -158,49 +713,340 @@ async fn calculate_global_neff_optimized(
             .collect()
     };
 
+    // 长时间无成交的物品按空闲时长加速恢复
+    let idle: FxHashMap<String, IdleRecovery> = state.market_cache.read().iter()
+        .filter(|i| targets.contains(&i.id))
+        .map(|i| (i.id.clone(), IdleRecovery::of(i, config, ts)))
+        .collect();
+
     let mut accumulator = FxHashMap::default();
     for (item_id, records) in history_snapshot {
         // 这里只计算历史衰减部分
-        let val = PricingEngine::calculate_history_decay(&records, config, ts);
+        let recovery = idle.get(&item_id).copied().unwrap_or(IdleRecovery::NONE);
+        let val = PricingEngine::history_decay_boosted(&records, config, ts, recovery);
         
         accumulator.entry(item_id)
             .and_modify(|v| *v += val)
             .or_insert(val);
     }
-    accumulator
+    Some(accumulator)
+}
+
+/// 买入后立即卖回的价差 (不落盘)：买入与卖回基于同一份环境与库存快照
+pub async fn roundtrip_quote(
+    State(state): State<AppState>,
+    Json(req): Json<RoundTripRequest>,
+) -> impl IntoResponse {
+    state.metrics.route_requests.hit(Route::Market);
+    match quote_roundtrip(&state, &req).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn quote_roundtrip(state: &AppState, req: &RoundTripRequest) -> Result<serde_json::Value, ApiError> {
+    if !req.amount.is_finite() || req.amount <= 0.0 {
+        return Err(ApiError::BadRequest("交易量必须为正数".into()));
+    }
+    let Some(item) = find_market_item(state, &req.item_id) else {
+        return Err(ApiError::BadRequest(format!("未知物品 {}", req.item_id)));
+    };
+    let config = state.config.read().clone();
+    let (env_index, env_base, _) = environment::current_env_components(&config, &state.holidays.read(), &state.env_cache);
+    let env = environment::item_env(env_index, env_base, &config, &item);
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let targets = HashSet::from([item.id.clone()]);
+    let history_n = calculate_global_neff_optimized(state, &targets, &config, now).await
+        .ok_or(ApiError::HistoryBusy)?
+        .get(&item.id).copied().unwrap_or(0.0);
+    let launch = PricingEngine::launch_iota(item.launch_iota, &config, now);
+    let n_before = item.clamp_n(history_n + item.n + item.iota + config.global_iota + launch, &config);
+    // 买入消耗库存，卖回时从买入后的 n 开始
+    let n_after = config.floor_effective_n(n_before - req.amount);
+
+    let base_price = item.effective_base_price(now);
+    let price = |n, is_buy| item.cap_total(PricingEngine::calculate_price(
+        base_price, env, n, req.amount, item.lambda.abs(),
+        config.buy_premium, config.fresh_mint_surcharge, is_buy, config.allow_negative_n,
+    ) * config.global_price_multiplier, req.amount);
+    let buy_cost = price(n_before, true).round_2();
+    let resale_value = price(n_after, false).round_2();
+    let net_loss = (buy_cost - resale_value).round_2();
+    let loss_pct = if buy_cost > 0.0 { (net_loss / buy_cost * 100.0).round_2() } else { 0.0 };
+
+    Ok(serde_json::json!({
+        "itemId": item.id,
+        "amount": req.amount,
+        "buyCost": buy_cost,
+        "resaleValue": resale_value,
+        "netLoss": net_loss,
+        "lossPct": loss_pct,
+        "nBefore": n_before.round_2(),
+        "nAfter": n_after.round_2(),
+        "envIndex": models::round_2(env)
+    }))
+}
+
+/// 签发环境冻结令牌：有效期内的交易可按当前环境指数定价，不受噪声与缓存窗口影响
+pub async fn issue_env_token(State(state): State<AppState>) -> impl IntoResponse {
+    let config = state.config.read().clone();
+    if config.env_token_secret.is_empty() {
+        return ApiError::NotFound("未启用环境冻结令牌".into()).into_response();
+    }
+    let (env_index, _) = environment::calculate_current_env_index(&config, &state.holidays.read(), &state.env_cache);
+    let expires_at = chrono::Utc::now().timestamp_millis() + config.env_token_ttl_secs.max(1) * 1000;
+    Json(serde_json::json!({
+        "token": crate::logic::env_token::issue(env_index, expires_at, &config.env_token_secret),
+        "envIndex": env_index,
+        "expiresAt": expires_at
+    })).into_response()
+}
+
+/// 客户端离线估价所需的定价参数：现价 = envIndex × basePrice × e^(-λ·effectiveN) × priceMultiplier
+/// 买入价再乘 buyPremium；validUntil 之后环境指数可能变化，客户端应重新拉取
+pub async fn get_market_params(State(state): State<AppState>) -> impl IntoResponse {
+    state.metrics.route_requests.hit(Route::Market);
+    let config = state.config.read().clone();
+    let market_items = state.market_cache.read().clone();
+    let (env_index, env_base, _) = environment::current_env_components(&config, &state.holidays.read(), &state.env_cache);
+    let now = chrono::Utc::now().timestamp_millis();
+    let targets: HashSet<String> = market_items.iter().map(|i| i.id.clone()).collect();
+    let Some(history) = calculate_global_neff_optimized(&state, &targets, &config, now).await else {
+        return ApiError::HistoryBusy.into_response();
+    };
+
+    let items: serde_json::Map<String, serde_json::Value> = market_items.iter().map(|item| {
+        let history_n = history.get(&item.id).copied().unwrap_or(0.0);
+        let launch = PricingEngine::launch_iota(item.launch_iota, &config, now);
+        let effective_n = item.clamp_n(history_n + item.n + item.iota + config.global_iota + launch, &config);
+        (item.id.clone(), serde_json::json!({
+            "basePrice": item.effective_base_price(now),
+            "lambda": item.lambda.abs(),
+            "n": item.n,
+            "iota": item.iota,
+            "effectiveN": effective_n,
+            "envIndex": environment::item_env(env_index, env_base, &config, item),
+            "maxPrice": item.max_price
+        }))
+    }).collect();
+
+    Json(serde_json::json!({
+        "items": items,
+        "envIndex": env_index,
+        "buyPremium": config.buy_premium,
+        "priceMultiplier": config.global_price_multiplier,
+        "serverTime": now,
+        "validUntil": now + config.env_cache_granularity_secs.max(1) * 1000
+    })).into_response()
+}
+
+/// 环境指数时间序列 (按时间正序)
+pub async fn get_env_history(State(state): State<AppState>) -> impl IntoResponse {
+    state.metrics.route_requests.hit(Route::Market);
+    let samples: Vec<serde_json::Value> = state.env_history.read().samples().iter()
+        .map(|&(timestamp, index)| serde_json::json!({ "timestamp": timestamp, "envIndex": index }))
+        .collect();
+    Json(serde_json::json!({ "samples": samples }))
+}
+
+/// 市场整体价格指数：各物品曲线价 (不含环境) 与基准价之比按近 24 小时成交量加权平均
+/// 全部物品都没有成交时等权；1.0 表示整体处于基准价，低于 1 表示整体被压低
+pub async fn get_market_health(State(state): State<AppState>) -> impl IntoResponse {
+    state.metrics.route_requests.hit(Route::Market);
+    let config = state.config.read().clone();
+    let market_items = state.market_cache.read().clone();
+    let (env_index, _) = environment::calculate_current_env_index(&config, &state.holidays.read(), &state.env_cache);
+    let now = chrono::Utc::now().timestamp_millis();
+    let targets: HashSet<String> = market_items.iter().map(|i| i.id.clone()).collect();
+    let Some(history) = calculate_global_neff_optimized(&state, &targets, &config, now).await else {
+        return ApiError::HistoryBusy.into_response();
+    };
+    let volumes: Vec<f64> = {
+        let histories = state.player_histories.read();
+        market_items.iter()
+            .map(|item| crate::logic::tuning::realized_volume(&histories, &item.id, now - 86_400_000))
+            .collect()
+    };
+    let total_volume: f64 = volumes.iter().sum();
+
+    let (weighted, weight_sum) = market_items.iter().zip(&volumes)
+        .map(|(item, volume)| {
+            let history_n = history.get(&item.id).copied().unwrap_or(0.0);
+            let launch = PricingEngine::launch_iota(item.launch_iota, &config, now);
+            let n_eff = item.clamp_n(history_n + item.n + item.iota + config.global_iota + launch, &config);
+            let ratio = PricingEngine::exp_neg(item.lambda.abs() * n_eff, &config);
+            let weight = if total_volume > 0.0 { *volume } else { 1.0 };
+            (ratio * weight, weight)
+        })
+        .fold((0.0, 0.0), |(s, w), (x, wx)| (s + x, w + wx));
+
+    Json(serde_json::json!({
+        "priceIndex": if weight_sum > 0.0 { weighted / weight_sum } else { 1.0 },
+        "envIndex": env_index,
+        "dailyVolume": total_volume,
+        "itemCount": market_items.len(),
+        "serverTime": now
+    })).into_response()
+}
+
+/// 物品有效库存的分解：与行情报价同一公式 (历史衰减 + 持久化 n + 物品 iota + 全局 iota，下限 0)
+pub async fn get_item_neff(State(state): State<AppState>, Path(item_id): Path<String>) -> impl IntoResponse {
+    state.metrics.route_requests.hit(Route::Market);
+    let Some(item) = find_market_item(&state, &item_id) else {
+        return ApiError::NotFound(format!("未知物品 {}", item_id)).into_response();
+    };
+    let config = state.config.read().clone();
+    let now = chrono::Utc::now().timestamp_millis();
+    let targets = HashSet::from([item.id.clone()]);
+    let Some(history) = calculate_global_neff_optimized(&state, &targets, &config, now).await else {
+        return ApiError::HistoryBusy.into_response();
+    };
+    let history_n = history.get(&item.id).copied().unwrap_or(0.0);
+    let launch = PricingEngine::launch_iota(item.launch_iota, &config, now);
+
+    Json(serde_json::json!({
+        "itemId": item.id,
+        "historyContribution": history_n,
+        "staticN": item.n,
+        "itemIota": item.iota,
+        "globalIota": config.global_iota,
+        "launchIota": launch,
+        "floorN": item.floor_n,
+        "maxN": item.max_n,
+        "effectiveN": item.clamp_n(history_n + item.n + item.iota + config.global_iota + launch, &config)
+    })).into_response()
+}
+
+/// 按指数恢复模型估算现价回升到 target_ratio × 基准价所需的秒数；已达到时 seconds 为 null
+pub async fn get_item_recovery(
+    State(state): State<AppState>,
+    Path(item_id): Path<String>,
+    Query(query): Query<RecoveryQuery>,
+) -> impl IntoResponse {
+    state.metrics.route_requests.hit(Route::Market);
+    if !(query.target_ratio > 0.0 && query.target_ratio <= 1.0) {
+        return ApiError::BadRequest("target_ratio 必须在 (0, 1] 之间".into()).into_response();
+    }
+    let Some(item) = find_market_item(&state, &item_id) else {
+        return ApiError::NotFound(format!("未知物品 {}", item_id)).into_response();
+    };
+    let config = state.config.read().clone();
+    if config.recovery_model != RecoveryModel::Exponential {
+        return ApiError::BadRequest("恢复时间估算仅支持指数恢复模型".into()).into_response();
+    }
+    let now = chrono::Utc::now().timestamp_millis();
+    let targets = HashSet::from([item.id.clone()]);
+    let Some(history) = calculate_global_neff_optimized(&state, &targets, &config, now).await else {
+        return ApiError::HistoryBusy.into_response();
+    };
+    let history_n = history.get(&item.id).copied().unwrap_or(0.0);
+    // 开市溢价按当前值计入静态部分 (其后续衰减不参与估算)
+    let static_n = item.n + item.iota + config.global_iota + PricingEngine::launch_iota(item.launch_iota, &config, now);
+    let lambda = item.lambda.abs();
+    let current_ratio = PricingEngine::exp_neg(lambda * item.clamp_n(history_n + static_n, &config), &config);
+    let secs = PricingEngine::recovery_secs(history_n, static_n, &item, query.target_ratio, &config);
+
+    Json(serde_json::json!({
+        "itemId": item.id,
+        "basePrice": item.effective_base_price(now),
+        "currentRatio": current_ratio,
+        "targetRatio": query.target_ratio,
+        "reachable": secs.is_some(),
+        "seconds": secs.filter(|s| *s > 0.0)
+    })).into_response()
 }
 
 // =========================================================================
 // 4. 批量处理
 // =========================================================================
 
-pub async fn handle_batch_sell(
+pub async fn handle_batch_sell(s: State<AppState>, j: Json<BatchTradeRequest>) -> impl IntoResponse {
+    process_batch(s, j, TradeAction::Sell).await
+}
+
+pub async fn handle_batch_buy(s: State<AppState>, j: Json<BatchTradeRequest>) -> impl IntoResponse {
+    process_batch(s, j, TradeAction::Buy).await
+}
+
+/// 批量交易，行未携带 action 时使用 `default_action`
+async fn process_batch(
     State(state): State<AppState>, 
-    Json(batch): Json<BatchTradeRequest>
-) -> impl IntoResponse {
+    Json(batch): Json<BatchTradeRequest>,
+    default_action: TradeAction
+) -> axum::response::Response {
+    state.metrics.request_rate.hit(chrono::Utc::now().timestamp());
+    state.metrics.route_requests.hit(Route::Batch);
+    // 处理前先检查行数，超大批次不占用定价与 Mojang 校验
+    let max = state.config.read().max_batch_size;
+    if batch.requests.is_empty() {
+        return ApiError::BadRequest("批量请求不能为空".into()).into_response();
+    }
+    if batch.requests.len() > max {
+        return ApiError::BatchTooLarge { size: batch.requests.len(), max }.into_response();
+    }
+    if let Err(e) = check_writable(&state, batch.requests.iter().all(|r| r.is_preview)) {
+        return e.into_response();
+    }
+    // 全部为预览时不会产生写入：各玩家历史只复制一次，所有行共用
+    let shared_histories: Option<Arc<HashMap<String, Arc<PlayerSalesHistory>>>> =
+        batch.requests.iter().all(|r| r.is_preview).then(|| {
+            let histories = state.player_histories.read();
+            let shared = batch.requests.iter()
+                .map(|r| (r.player_id.clone(), Arc::new(histories.get(&r.player_id).cloned().unwrap_or_default())))
+                .collect();
+            Arc::new(shared)
+        });
+
     let results = stream::iter(batch.requests)
         .map(|req| {
             let s = state.clone();
+            let shared = shared_histories.clone();
+            let default_action = default_action.clone();
             async move {
-                let (cfg, hols, hist) = (
-                    s.config.read().clone(), 
-                    s.holidays.read().clone(), 
-                    s.player_histories.read().get(&req.player_id).cloned().unwrap_or_default()
-                );
-                
-                // [新增] 获取 n
-                let current_n = {
-                    let market = s.market_cache.read();
-                    market.iter().find(|i| i.id == req.item_id).map(|i| i.n).unwrap_or(0.0)
+                if let Err(e) = check_breaker(&s, &req.item_id) {
+                    return TradeResponse { success: false, message: e.to_string(), ..Default::default() };
+                }
+
+                // 含成交的批次逐行读取，后面的行能看到前面成交后的历史
+                let hist = match shared.as_ref().and_then(|h| h.get(&req.player_id)) {
+                    Some(h) => h.clone(),
+                    None => Arc::new(s.player_histories.read().get(&req.player_id).cloned().unwrap_or_default()),
                 };
+                let (cfg, hols) = (s.config.read().clone(), s.holidays.read().clone());
+                
+                // [新增] 获取物品状态 (n、lambda、分类)
+                let market_item = find_market_item(&s, &req.item_id);
+                if let Err(e) = check_catalog(&cfg, market_item.as_ref(), &req)
+                    .and_then(|_| check_min_amount(market_item.as_ref(), &req))
+                    .and_then(|_| check_tradable(market_item.as_ref(), &req)) {
+                    return TradeResponse { success: false, message: e.to_string(), ..Default::default() };
+                }
                 
-                let (resp, record) = execute_trade_logic(
-                    &req, &cfg, &hols, &hist, false, &s.env_cache, &s.http_client,
-                    current_n
-                ).await;
+                let action = req.action.clone().unwrap_or(default_action);
+                if let Err(e) = check_arbitrage(&s, &cfg, &req, action.is_buy()) {
+                    return TradeResponse { success: false, message: e.to_string(), ..Default::default() };
+                }
+                let started = Instant::now();
+                let priced = with_deadline(&cfg, req.is_preview, execute_trade_logic(
+                    &req, &cfg, &hols, &hist, &action, &s.env_cache, &s.http_client, &s.mojang_limiter,
+                    market_item.as_ref()
+                )).await;
+                let (mut resp, record) = match priced {
+                    Ok(priced) => priced,
+                    Err(e) => return TradeResponse { success: false, message: e.to_string(), ..Default::default() },
+                };
+                s.metrics.trade_pricing_latency.record(started.elapsed());
 
-                if let Some(r) = record { 
-                    persist_transaction(s, r).await; 
+                // 预览行不产生流水 (逐行按 is_preview 判断)
+                if let Some(mut r) = record { 
+                    if let Err(e) = check_confirmation(&cfg, market_item.as_ref(), &req, &r, &resp)
+                        .and_then(|_| settle_balance(&s, &r, action.is_buy())) {
+                        return TradeResponse { success: false, message: e.to_string(), ..resp };
+                    }
+                    note_trade_side(&s, cfg.arbitrage_cooldown_ms, &r, action.is_buy());
+                    assign_trade_id(&s, &mut resp, &mut r);
+                    persist_transaction(s, r, action.is_buy()).await; 
                 }
                 resp
             }
@@ -209,40 +1055,172 @@ pub async fn handle_batch_sell(
         .collect::<Vec<_>>()
         .await;
 
-    Json(BatchTradeResponse { results })
+    Json(BatchTradeResponse::new(results)).into_response()
 }
 
 // =========================================================================
 // 5. 持久化与内存更新
 // =========================================================================
 
-async fn persist_transaction(state: AppState, record: TransactionRecord) {
+/// 启用 enforce_balances 时按成交额记账：卖出入账，买入扣款 (余额不足则拒绝)
+/// 检查与扣款在同一把写锁内完成，并发买入不会透支
+/// 物品设置了结算币种时按换算后的金额记到该币种
+fn settle_balance(state: &AppState, record: &TransactionRecord, is_buy: bool) -> Result<(), ApiError> {
+    if !state.config.read().enforce_balances { return Ok(()); }
+    let currency = record.payout_currency.clone()
+        .or_else(|| find_market_item(state, &record.item_id).and_then(|i| i.currency))
+        .unwrap_or_else(|| DEFAULT_CURRENCY.to_string());
+    let amount = record.payout.unwrap_or(record.total_price);
+    let mut balances = state.player_balances.write();
+    state.dirty.balances.store(true, Ordering::Relaxed);
+    let balance = balances.entry((record.player_id.clone(), currency)).or_insert(0.0);
+    if is_buy {
+        if *balance < amount {
+            return Err(ApiError::InsufficientFunds { required: amount, available: *balance });
+        }
+        *balance -= amount;
+    } else {
+        *balance += amount;
+    }
+    *balance = balance.round_2();
+    Ok(())
+}
+
+/// 客户端上报的玩家名与已记录的不同时，是否采用新名称
+/// 不信任客户端时：在线模式要求与 Mojang 档案一致，离线模式保留首次记录的名称 (仅管理接口可改)
+async fn accept_client_name(state: &AppState, record: &TransactionRecord) -> bool {
+    let (trust, online) = {
+        let config = state.config.read();
+        (config.trust_client_name, config.is_online_mode)
+    };
+    if trust { return true; }
+    let known = state.player_histories.read().get(&record.player_id).map(|h| h.player_name.clone());
+    match known {
+        None => true,
+        Some(name) if name.is_empty() || name == record.player_name => true,
+        Some(_) if !online => false,
+        Some(_) => {
+            let cached = state.profile_names.read().get(&record.player_id).cloned();
+            let profile = match cached {
+                Some(name) if name == record.player_name => Some(name),
+                // 缓存未命中或与新名称不符 (可能刚改过名) 时重新查询
+                _ => crate::logic::fetch_profile_name(&state.http_client, &state.mojang_limiter, &record.player_id).await,
+            };
+            if let Some(name) = &profile {
+                state.profile_names.write().insert(record.player_id.clone(), name.clone());
+            }
+            profile.as_deref() == Some(record.player_name.as_str())
+        }
+    }
+}
+
+/// 每个玩家每个物品保留的记录数上限：物品的 max_records 优先，否则取全局 max_records_per_item
+fn record_cap(item: Option<&MarketItem>, config: &AppConfig) -> usize {
+    item.and_then(|i| i.max_records).unwrap_or(config.max_records_per_item).max(1)
+}
+
+pub(crate) async fn persist_transaction(state: AppState, record: TransactionRecord, is_buy: bool) {
     state.metrics.total_trades.fetch_add(1, Ordering::Relaxed);
+    state.metrics.trade_value.observe(record.total_price);
+    let adopt_name = accept_client_name(&state, &record).await;
+    let lang = state.config.read().log_lang;
+    let max_records = record_cap(state.market_cache.read().iter().find(|i| i.id == record.item_id), &state.config.read());
     
     // 1. 更新玩家交易历史
     {
         let mut histories = state.player_histories.write();
+        state.dirty.players.store(true, Ordering::Relaxed);
         let entry = histories.entry(record.player_id.clone()).or_default();
-        if entry.player_name != record.player_name {
+        if entry.player_name != record.player_name && adopt_name {
             entry.player_name = record.player_name.clone();
+        } else if entry.player_name != record.player_name {
+            tracing::warn!("{}", lang.render(Msg::NameMismatch, &[&record.player_id, &record.player_name, &entry.player_name]));
         }
         let items = entry.item_sales.entry(record.item_id.clone()).or_default();
+        // 批量子交易可能落在同一毫秒，用序号区分
+        let seq = items.iter()
+            .filter(|r| r.timestamp == record.timestamp)
+            .map(|r| r.seq + 1)
+            .max()
+            .unwrap_or(0);
         items.push(SalesRecord {
             timestamp: record.timestamp,
-            amount: if record.action == "SELL" { record.amount } else { -record.amount },
+            amount: if is_buy { -record.amount } else { record.amount },
             env_index: record.env_index,
             price: if record.amount.abs() > 1e-9 { record.total_price / record.amount } else { 0.0 },
+            seq,
         });
-        if items.len() > 100 { items.remove(0); }
+        if items.len() > max_records { items.drain(..items.len() - max_records); }
+    }
+
+    let config = state.config.read().clone();
+    // 1.0 物品最近成交时间 (空闲加速恢复据此判断)；本笔成交结束了一段空闲期时，
+    // 空闲期间加速恢复的效果记入物品的折算额度，避免最近成交时间刷新后 n 回跳
+    if let Some(item) = state.market_cache.write().iter_mut().find(|i| i.id == record.item_id) {
+        PricingEngine::note_trade(item, &config, record.timestamp);
+        state.dirty.market.store(true, Ordering::Relaxed);
+    }
+
+    // 1.1 成交均价并入展示价 EMA (买入价先去掉溢价，回到曲线价口径)
+    if config.display_ema_alpha > 0.0 && record.amount.abs() > 1e-9 {
+        let unit = record.total_price / record.amount;
+        let spot = if is_buy && config.buy_premium > 0.0 { unit / config.buy_premium } else { unit };
+        update_price_ema(&state, &record.item_id, spot, &config);
+    }
+
+    // 1.2 熔断检测：现价按 exp(-λ·Δn) 变化，单笔变动超限则冷却该物品
+    if config.max_price_move_pct > 0.0 {
+        let lambda = state.market_cache.read().iter()
+            .find(|i| i.id == record.item_id)
+            .map(|i| i.lambda.abs());
+        if let Some(lambda) = lambda {
+            let delta_n = if is_buy { -record.amount } else { record.amount };
+            let move_pct = (1.0 - (-lambda * delta_n).exp()).abs() * 100.0;
+            if move_pct > config.max_price_move_pct {
+                let until = chrono::Utc::now().timestamp() + config.item_breaker_cooldown_secs;
+                state.item_breakers.write().insert(record.item_id.clone(), until);
+                let pct = format!("{:.1}", move_pct);
+                tracing::warn!("{}", config.log_lang.render(Msg::BreakerTripped, &[&record.item_id, &pct, &config.item_breaker_cooldown_secs]));
+            }
+        }
     }
 
     // 2. [可选] 如果你需要交易直接改变全局 n (不仅仅是历史记录计算)，在这里更新 market_cache
     // 如果 n 是静态参数，这里不需要动。如果 n 是累积量，这里可以加减。
     // 假设 n 是静态配置带来的基础偏移，我们这里不动它。
 
-    if let Err(_) = state.tx.try_send(record) {
+    // 通知 WebSocket 订阅者该物品价格已变动
+    crate::ws::publish_price_update(&state, record.item_id.clone());
+
+    // 不落盘时由这里维护查询用的内存缓存 (否则由写入任务维护)
+    if !state.config.read().persist_history {
+        let mut cache = state.history_cache.write();
+        cache.push_back(record);
+        if cache.len() > MAX_CACHE_SIZE { cache.pop_front(); }
+        return;
+    }
+    if let Err(dropped) = state.tx.try_send(record) {
         state.metrics.channel_dropped.fetch_add(1, Ordering::Relaxed);
-        tracing::warn!("🔥 写入通道背压过高，丢弃日志以保护 API 响应速度");
+        tracing::warn!("{}", state.config.read().log_lang.text(Msg::ChannelFull));
+        // 改写到死信日志，下次启动时重放
+        append_dead_letter(&state, dropped.into_inner()).await;
+    }
+}
+
+// 死信日志的追加者互斥：并发追加者同时看到空文件时会各自写入文件头
+static DEAD_LETTER_LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
+
+/// 在阻塞线程池中追加一条死信 (峰值时通道已满，不能再占用运行时线程做同步 I/O)
+async fn append_dead_letter(state: &AppState, record: TransactionRecord) {
+    let path = state.config.read().dead_letter_path.clone();
+    if path.is_empty() { return; }
+    let result = tokio::task::spawn_blocking(move || {
+        let _guard = DEAD_LETTER_LOCK.lock();
+        FileBackend.append(&path, &[record])
+    }).await.unwrap_or_else(|e| Err(std::io::Error::other(e)));
+    if let Err(e) = result {
+        state.metrics.write_failures.fetch_add(1, Ordering::Relaxed);
+        tracing::error!("{}", state.config.read().log_lang.render(Msg::DeadLetterFailed, &[&format!("{:?}", e)]));
     }
 }
 
@@ -254,11 +1232,58 @@ pub async fn sync_market(
     State(state): State<AppState>,
     Json(payload): Json<MarketSyncRequest>
 ) -> impl IntoResponse {
-    let new_items = payload.items;
+    // 不在白名单内的物品过滤掉并告警，其余照常同步
+    let (allowlist, lang, lambda_max) = {
+        let config = state.config.read();
+        (config.item_id_allowlist.clone(), config.log_lang, config.lambda_max)
+    };
+    let payload_generation = payload.generation;
+    let (new_items, rejected): (Vec<MarketItem>, Vec<MarketItem>) = payload.items.into_iter()
+        .partition(|i| allowlist.is_empty() || allowlist.iter().any(|p| glob_match(p, &i.id)));
+    if !rejected.is_empty() {
+        let ids: Vec<&str> = rejected.iter().map(|i| i.id.as_str()).collect();
+        tracing::warn!("{}", lang.render(Msg::ItemsNotAllowed, &[&ids.len(), &format!("{:?}", ids)]));
+    }
     let item_count = new_items.len();
+
+    // 同步的价格参数必须是有限数值，换算比例与单价上限还须为正
+    let invalid: Vec<&str> = new_items.iter()
+        .filter(|i| !(i.base_price.is_finite() && i.lambda.is_finite() && i.n.is_finite() && i.iota.is_finite() && i.floor_n.is_finite()
+            && i.max_n.is_none_or(f64::is_finite)
+            && i.conversion_rate.is_none_or(|r| r.is_finite() && r > 0.0)
+            && i.max_price.is_none_or(|p| p.is_finite() && p > 0.0)
+            && i.noise_multiplier.is_none_or(|m| m.is_finite() && m >= 0.0)
+            && i.initial_n.is_none_or(f64::is_finite)
+            && i.base_price_override.is_none_or(|(price, _)| price.is_finite())))
+        .map(|i| i.id.as_str())
+        .collect();
+    if !invalid.is_empty() {
+        return ApiError::BadRequest(format!("物品 {:?} 含非有限数值", invalid)).into_response();
+    }
+    // lambda 为 0 时价格恒定，过大时一笔成交即崩盘；定价处取绝对值会掩盖这类配置错误
+    let out_of_range: Vec<&str> = new_items.iter()
+        .filter(|i| !(i.lambda > 0.0 && i.lambda <= lambda_max))
+        .map(|i| i.id.as_str())
+        .collect();
+    if !out_of_range.is_empty() {
+        return ApiError::BadRequest(format!("物品 {:?} 的 lambda 不在 (0, {}] 范围内", out_of_range, lambda_max)).into_response();
+    }
     
+    let now_ms = chrono::Utc::now().timestamp_millis();
     {
+        // 代次检查与替换在同一把写锁内完成：并发同步串行生效，保留的 n / iota 总是取自当时的最新状态
         let mut cache = state.market_cache.write();
+        let current = state.sync_generation.load(Ordering::Relaxed);
+        let generation = match payload_generation {
+            Some(g) if g <= current => {
+                tracing::warn!("{}", lang.render(Msg::StaleSync, &[&g, &current]));
+                return ApiError::StaleSync { generation: g, current }.into_response();
+            }
+            Some(g) => g,
+            None => current + 1,
+        };
+        state.sync_generation.store(generation, Ordering::Relaxed);
+        state.dirty.market.store(true, Ordering::Relaxed);
         let mut old_state_map: HashMap<String, MarketItem> = cache.drain(..)
             .map(|item| (item.id.clone(), item))
             .collect();
@@ -268,25 +1293,1804 @@ pub async fn sync_market(
                 // [关键] 保留旧状态
                 new_item.n = old_item.n;
                 new_item.iota = old_item.iota;
-            }
+                new_item.last_trade_ms = old_item.last_trade_ms;
+                new_item.idle_credit_ms = old_item.idle_credit_ms;
+                new_item.idle_credit_before_ms = old_item.idle_credit_before_ms;
+                // 自动调参中的物品保留已调整的 lambda
+                if new_item.target_daily_volume.is_some() {
+                    new_item.lambda = old_item.lambda;
+                }
+                // 未携带交易量下限时沿用原值
+                if new_item.min_trade_amount.is_none() {
+                    new_item.min_trade_amount = old_item.min_trade_amount;
+                }
+                // 未携带记录上限时沿用原值
+                if new_item.max_records.is_none() {
+                    new_item.max_records = old_item.max_records;
+                }
+                if new_item.launch_iota.is_none() {
+                    new_item.launch_iota = old_item.launch_iota;
+                }
+                // 保留未到期的限时基准价
+                if new_item.base_price_override.is_none() && old_item.active_base_price_override(now_ms).is_some() {
+                    new_item.base_price_override = old_item.base_price_override;
+                }
+            } else if let Some(initial_n) = new_item.initial_n {
+                // 新物品按指定的初始库存引入
+                new_item.n = initial_n;
+            }
             new_item
         }).collect();
+        // 保留下来的旧状态也可能来自历史 bug
+        models::sanitize_market_items(&mut cache);
     }
     
-    tracing::info!("♻️ 已智能同步 {} 个物品 (状态已保留)", item_count);
+    tracing::info!("{}", lang.render(Msg::MarketSynced, &[&item_count]));
 
     Json(serde_json::json!({ 
         "success": true, 
-        "message": format!("Synced {} items", item_count) 
+        "message": format!("Synced {} items", item_count),
+        "generation": state.sync_generation.load(Ordering::Relaxed)
+    })).into_response()
+}
+
+/// 简单通配匹配：`*` 匹配任意长度字符，其余字符须完全一致
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let [first, middle @ .., last] = parts.as_slice() else { return pattern == text; };
+    let Some(mut rest) = text.strip_prefix(first) else { return false; };
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// 从其他服务器合并玩家历史：按 (时间戳, 序号) 去重，不覆盖已有记录；落盘交给定期快照
+pub async fn import_player_histories(
+    State(state): State<AppState>,
+    Json(payload): Json<HashMap<String, PlayerSalesHistory>>
+) -> impl IntoResponse {
+    if payload.len() > MAX_IMPORT_PLAYERS {
+        return ApiError::BadRequest(format!("单次最多导入 {} 名玩家", MAX_IMPORT_PLAYERS)).into_response();
+    }
+    let total_records: usize = payload.values().flat_map(|h| h.item_sales.values()).map(Vec::len).sum();
+    if total_records > MAX_IMPORT_RECORDS {
+        return ApiError::BadRequest(format!("单次最多导入 {} 条记录 (本次 {} 条)", MAX_IMPORT_RECORDS, total_records)).into_response();
+    }
+
+    let (imported, merged, records_added) = merge_imported_histories(&state, payload);
+    tracing::info!("📥 玩家历史导入完成: 新增 {} 名, 合并 {} 名, 记录 +{}", imported, merged, records_added);
+
+    Json(serde_json::json!({
+        "success": true,
+        "imported": imported,
+        "merged": merged,
+        "recordsAdded": records_added
+    })).into_response()
+}
+
+/// 将记录并入已按 (时间戳, 序号) 排序的序列：同一毫秒内的子交易以序号区分，按该键去重，
+/// 保留最新的 `cap` 条，返回新增条数
+fn merge_sales(target: &mut Vec<SalesRecord>, records: Vec<SalesRecord>, cap: usize) -> usize {
+    let before = target.len();
+    target.extend(records);
+    // 稳定排序后去重保留靠前者，即已有记录优先
+    target.sort_by_key(|r| (r.timestamp, r.seq));
+    target.dedup_by_key(|r| (r.timestamp, r.seq));
+    let added = target.len() - before;
+    if target.len() > cap {
+        target.drain(..target.len() - cap);
+    }
+    added
+}
+
+/// 合并导入的玩家历史，返回 (新增玩家, 合并玩家, 新增记录)
+/// 清洗、截断与合并均在锁外对副本完成；写锁内只补上期间新成交的记录并替换条目
+fn merge_imported_histories(state: &AppState, payload: HashMap<String, PlayerSalesHistory>) -> (usize, usize, usize) {
+    let (mut imported, mut merged, mut records_added) = (0usize, 0usize, 0usize);
+    // 记录数上限先取快照，持有历史写锁期间不再读取配置与市场
+    let (default_cap, caps) = {
+        let config = state.config.read();
+        let caps: FxHashMap<String, usize> = state.market_cache.read().iter()
+            .map(|i| (i.id.clone(), record_cap(Some(i), &config)))
+            .collect();
+        (record_cap(None, &config), caps)
+    };
+    let cap_of = |item_id: &str| caps.get(item_id).copied().unwrap_or(default_cap);
+    let mut existing: HashMap<String, PlayerSalesHistory> = {
+        let histories = state.player_histories.read();
+        payload.keys().filter_map(|id| histories.get(id).map(|h| (id.clone(), h.clone()))).collect()
+    };
+
+    // (玩家, 合并结果, 复制时各物品最新记录的 (时间戳, 序号))
+    let mut prepared = Vec::with_capacity(payload.len());
+    for (player_id, incoming) in payload {
+        let (mut entry, watermarks) = match existing.remove(&player_id) {
+            Some(entry) => {
+                merged += 1;
+                let watermarks: FxHashMap<String, (i64, u32)> = entry.item_sales.iter()
+                    .filter_map(|(id, r)| r.last().map(|last| (id.clone(), (last.timestamp, last.seq))))
+                    .collect();
+                (entry, watermarks)
+            }
+            None => {
+                imported += 1;
+                (PlayerSalesHistory { player_id: player_id.clone(), ..Default::default() }, FxHashMap::default())
+            }
+        };
+        if entry.player_name.is_empty() {
+            entry.player_name = incoming.player_name;
+        }
+        for (item_id, mut records) in incoming.item_sales {
+            // 新老玩家一律丢弃非有限数值的记录，并按与实时成交相同的上限截断
+            records.retain(SalesRecord::is_finite);
+            let cap = cap_of(&item_id);
+            records_added += merge_sales(entry.item_sales.entry(item_id).or_default(), records, cap);
+        }
+        entry.item_sales.retain(|_, r| !r.is_empty());
+        prepared.push((player_id, entry, watermarks));
+    }
+
+    let mut histories = state.player_histories.write();
+    state.dirty.players.store(true, Ordering::Relaxed);
+    for (player_id, mut entry, watermarks) in prepared {
+        // 复制之后才写入的成交 (晚于复制时的最新记录) 不能丢
+        if let Some(current) = histories.get(&player_id) {
+            if !current.player_name.is_empty() {
+                entry.player_name = current.player_name.clone();
+            }
+            for (item_id, records) in &current.item_sales {
+                let mark = watermarks.get(item_id);
+                let fresh: Vec<SalesRecord> = records.iter()
+                    .filter(|r| mark.is_none_or(|m| (r.timestamp, r.seq) > *m))
+                    .cloned()
+                    .collect();
+                if !fresh.is_empty() {
+                    merge_sales(entry.item_sales.entry(item_id.clone()).or_default(), fresh, cap_of(item_id));
+                }
+            }
+        }
+        histories.insert(player_id, entry);
+    }
+    (imported, merged, records_added)
+}
+
+/// 管理接口：删除物品并归档其最终状态 (同名的旧归档被替换)，返回归档快照
+pub async fn delete_item(State(state): State<AppState>, Path(item_id): Path<String>) -> impl IntoResponse {
+    let removed = {
+        let mut market = state.market_cache.write();
+        market.iter().position(|i| i.id == item_id).map(|idx| market.remove(idx))
+    };
+    let Some(item) = removed else {
+        return ApiError::NotFound(format!("物品 {} 不存在", item_id)).into_response();
+    };
+    state.dirty.market.store(true, Ordering::Relaxed);
+    state.price_snapshot.write().remove(&item_id);
+    state.item_breakers.write().remove(&item_id);
+    {
+        let mut archive = state.archived_items.write();
+        archive.retain(|i| i.id != item_id);
+        archive.push(item.clone());
+        state.dirty.archive.store(true, Ordering::Relaxed);
+    }
+    tracing::info!("🗄️ 物品 {} 已删除并归档 (n={:.2}, iota={:.2})", item.id, item.n, item.iota);
+    Json(item).into_response()
+}
+
+/// 管理接口：设置物品的限时基准价，到期后自动恢复 base_price
+pub async fn set_price_override(
+    State(state): State<AppState>,
+    Path(item_id): Path<String>,
+    Json(req): Json<BasePriceOverrideRequest>,
+) -> impl IntoResponse {
+    let now = chrono::Utc::now().timestamp_millis();
+    if !req.price.is_finite() || req.price <= 0.0 || req.expires_at <= now {
+        return ApiError::BadRequest("限时价格必须为正数且到期时间晚于当前".into()).into_response();
+    }
+    let updated = {
+        let mut market = state.market_cache.write();
+        let item = market.iter_mut().find(|i| i.id == item_id);
+        item.map(|item| {
+            item.base_price_override = Some((req.price, req.expires_at));
+            item.clone()
+        })
+    };
+    let Some(item) = updated else {
+        return ApiError::NotFound(format!("物品 {} 不存在", item_id)).into_response();
+    };
+    state.dirty.market.store(true, Ordering::Relaxed);
+    state.price_snapshot.write().remove(&item_id);
+    tracing::info!("🏷️ 物品 {} 限时基准价 {:.2} (原 {:.2})，到期 {}", item.id, req.price, item.base_price, req.expires_at);
+    Json(item).into_response()
+}
+
+/// 管理接口：提前取消物品的限时基准价
+pub async fn clear_price_override(State(state): State<AppState>, Path(item_id): Path<String>) -> impl IntoResponse {
+    let cleared = state.market_cache.write().iter_mut()
+        .find(|i| i.id == item_id)
+        .map(|item| item.base_price_override.take());
+    match cleared {
+        None => ApiError::NotFound(format!("物品 {} 不存在", item_id)).into_response(),
+        Some(previous) => {
+            state.dirty.market.store(true, Ordering::Relaxed);
+            state.price_snapshot.write().remove(&item_id);
+            Json(serde_json::json!({ "itemId": item_id, "cleared": previous.is_some() })).into_response()
+        }
+    }
+}
+
+/// 管理接口：从归档恢复物品 (保留归档时的状态)
+pub async fn restore_item(State(state): State<AppState>, Path(item_id): Path<String>) -> impl IntoResponse {
+    if state.market_cache.read().iter().any(|i| i.id == item_id) {
+        return ApiError::BadRequest(format!("物品 {} 已在市场中", item_id)).into_response();
+    }
+    let restored = {
+        let mut archive = state.archived_items.write();
+        state.dirty.archive.store(true, Ordering::Relaxed);
+        archive.iter().position(|i| i.id == item_id).map(|idx| archive.remove(idx))
+    };
+    let Some(item) = restored else {
+        return ApiError::NotFound(format!("物品 {} 没有归档", item_id)).into_response();
+    };
+    state.market_cache.write().push(item.clone());
+    state.dirty.market.store(true, Ordering::Relaxed);
+    tracing::info!("♻️ 物品 {} 已从归档恢复", item.id);
+    Json(item).into_response()
+}
+
+/// 管理接口：显式修改玩家显示名称 (不受 trust_client_name 限制)
+pub async fn rename_player(State(state): State<AppState>, Json(req): Json<PlayerRenameRequest>) -> impl IntoResponse {
+    if req.player_id.is_empty() || req.player_name.is_empty() {
+        return ApiError::BadRequest("玩家 ID 或名称缺失".into()).into_response();
+    }
+    let previous = {
+        let mut histories = state.player_histories.write();
+        state.dirty.players.store(true, Ordering::Relaxed);
+        let entry = histories.entry(req.player_id.clone()).or_insert_with(|| PlayerSalesHistory {
+            player_id: req.player_id.clone(), ..Default::default()
+        });
+        std::mem::replace(&mut entry.player_name, req.player_name.clone())
+    };
+    tracing::info!("🪪 玩家 {} 名称由管理员修改: {} -> {}", req.player_id, previous, req.player_name);
+    Json(serde_json::json!({ "success": true, "playerId": req.player_id, "previousName": previous })).into_response()
+}
+
+/// 查询玩家各币种余额
+pub async fn get_player_balance(State(state): State<AppState>, Path(player_id): Path<String>) -> impl IntoResponse {
+    let balances: HashMap<String, f64> = state.player_balances.read().iter()
+        .filter(|((pid, _), _)| *pid == player_id)
+        .map(|((_, currency), amount)| (currency.clone(), *amount))
+        .collect();
+    Json(serde_json::json!({ "playerId": player_id, "balances": balances }))
+}
+
+/// 管理接口：增减玩家余额，返回调整后的余额；扣款后余额不得为负 (与买入扣款一致)
+pub async fn grant_balance(State(state): State<AppState>, Json(req): Json<BalanceGrantRequest>) -> impl IntoResponse {
+    if req.player_id.is_empty() || !req.amount.is_finite() {
+        return ApiError::BadRequest("玩家 ID 缺失或金额无效".into()).into_response();
+    }
+    let currency = req.currency.unwrap_or_else(|| DEFAULT_CURRENCY.to_string());
+    let balance = {
+        let mut balances = state.player_balances.write();
+        let key = (req.player_id.clone(), currency.clone());
+        let current = balances.get(&key).copied().unwrap_or(0.0);
+        let updated = (current + req.amount).round_2();
+        if updated < 0.0 {
+            return ApiError::InsufficientFunds { required: -req.amount, available: current }.into_response();
+        }
+        balances.insert(key, updated);
+        state.dirty.balances.store(true, Ordering::Relaxed);
+        updated
+    };
+    tracing::info!("💰 玩家 {} 的 {} 余额调整 {:+.2}，当前 {:.2}", req.player_id, currency, req.amount, balance);
+    Json(serde_json::json!({ "playerId": req.player_id, "currency": currency, "balance": balance })).into_response()
+}
+
+/// 只读状态快照，用于排查定价异常 (玩家历史仅给出汇总，控制响应体积)
+pub async fn dump_state(State(state): State<AppState>) -> impl IntoResponse {
+    let mut config = state.config.read().clone();
+    // 密钥类字段不随快照输出
+    for secret in [&mut config.admin_token, &mut config.env_token_secret] {
+        if !secret.is_empty() {
+            *secret = "***".into();
+        }
+    }
+    let market = state.market_cache.read().clone();
+    let env = state.env_cache.read().clone();
+    let (players, item_series, records) = {
+        let histories = state.player_histories.read();
+        let item_series: usize = histories.values().map(|h| h.item_sales.len()).sum();
+        let records: usize = histories.values()
+            .flat_map(|h| h.item_sales.values())
+            .map(Vec::len)
+            .sum();
+        (histories.len(), item_series, records)
+    };
+
+    Json(serde_json::json!({
+        "config": config,
+        "market": market,
+        "envCache": env,
+        "playerHistories": {
+            "players": players,
+            "itemSeries": item_series,
+            "records": records
+        },
+        "historyCacheSize": state.history_cache.read().len(),
+        "serverTime": chrono::Utc::now().timestamp_millis()
     }))
 }
 
+/// 物品状态统计 (含熔断剩余时间)
+pub async fn get_item_stats(State(state): State<AppState>) -> impl IntoResponse {
+    let now = chrono::Utc::now().timestamp();
+    let breakers = state.item_breakers.read().clone();
+    let items: Vec<serde_json::Value> = state.market_cache.read().iter()
+        .map(|item| {
+            let remaining = breakers.get(&item.id).map(|until| until - now).filter(|r| *r > 0);
+            serde_json::json!({
+                "id": item.id,
+                "n": item.n,
+                "iota": item.iota,
+                "lambda": item.lambda,
+                "breakerTripped": remaining.is_some(),
+                "breakerRemainingSecs": remaining.unwrap_or(0)
+            })
+        })
+        .collect();
+
+    Json(serde_json::json!({ "items": items }))
+}
+
 pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
     let uptime = chrono::Utc::now().timestamp() - state.metrics.start_time;
     Json(serde_json::json!({
         "totalTrades": state.metrics.total_trades.load(Ordering::Relaxed),
         "dropped": state.metrics.channel_dropped.load(Ordering::Relaxed),
+        "writeFailures": state.metrics.write_failures.load(Ordering::Relaxed),
+        "writerRestarts": state.metrics.writer_restarts.load(Ordering::Relaxed),
         "uptime": uptime,
-        "cachedItems": state.market_cache.read().len()
+        "cachedItems": state.market_cache.read().len(),
+        "marketSnapshotLatency": state.metrics.market_snapshot_latency.snapshot(),
+        "tradePricingLatency": state.metrics.trade_pricing_latency.snapshot(),
+        "requestsByRoute": Route::ALL.iter()
+            .map(|&r| (r.label(), state.metrics.route_requests.get(r)))
+            .collect::<HashMap<_, _>>()
     }))
-}
\ No newline at end of file
+}
+
+/// Prometheus 文本格式指标
+pub async fn prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = state.metrics.trade_value.render("economy_trade_value", "Distribution of executed trade total_price");
+    body.push_str(&state.metrics.route_requests.render("economy_requests_total", "Requests received per endpoint"));
+    body.push_str(&state.metrics.trade_pricing_latency.render("economy_trade_pricing_seconds", "Time spent pricing a single trade"));
+    body.push_str(&state.metrics.market_snapshot_latency.render("economy_market_snapshot_seconds", "Time spent building a market quote snapshot"));
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// 配置变更后立即重算全部物品价格：刷新降级用的价格快照，并以当前现价重新播种展示价 EMA
+pub async fn recompute_prices(State(state): State<AppState>) -> impl IntoResponse {
+    state.price_ema.write().clear();
+    let mut prices = serde_json::Map::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let page = match quote_market_page(&state, vec![], &[], cursor.as_deref(), false).await {
+            Ok(page) => page,
+            Err(e) => return e.into_response(),
+        };
+        // 读锁繁忙时拿到的是旧快照，不能当作重算结果
+        if page["stale"] == true {
+            return ApiError::HistoryBusy.into_response();
+        }
+        for (id, status) in page["items"].as_object().into_iter().flatten() {
+            prices.insert(id.clone(), status["price"].clone());
+        }
+        match page["nextCursor"].as_str() {
+            Some(next) => cursor = Some(next.to_string()),
+            None => break,
+        }
+    }
+    tracing::info!("🔄 已重算 {} 个物品的价格", prices.len());
+
+    Json(serde_json::json!({
+        "recomputed": prices.len(),
+        "prices": prices,
+        "serverTime": chrono::Utc::now().timestamp_millis()
+    })).into_response()
+}
+
+/// 单次模拟的最大步数
+const MAX_SIMULATION_STEPS: usize = 1_000;
+
+/// 价格轨迹模拟：按请求中的物品参数回放一串假设的成交，只用临时历史，不触碰实时状态
+pub async fn simulate_sequence(
+    State(state): State<AppState>,
+    Json(req): Json<SimulateSequenceRequest>,
+) -> impl IntoResponse {
+    let config = state.config.read().clone();
+    let item = &req.item;
+    if !(item.base_price.is_finite() && item.base_price > 0.0) {
+        return ApiError::BadRequest("base_price 必须为正数".into()).into_response();
+    }
+    if !(item.lambda > 0.0 && item.lambda <= config.lambda_max) {
+        return ApiError::BadRequest(format!("lambda 不在 (0, {}] 范围内", config.lambda_max)).into_response();
+    }
+    if !(item.n.is_finite() && item.iota.is_finite()) {
+        return ApiError::BadRequest("n 与 iota 必须为有限数值".into()).into_response();
+    }
+    if req.steps.len() > MAX_SIMULATION_STEPS {
+        return ApiError::BadRequest(format!("模拟最多 {} 步", MAX_SIMULATION_STEPS)).into_response();
+    }
+    if let Some(i) = req.steps.iter().position(|s| !(s.amount.is_finite() && s.amount > 0.0) || s.elapsed_ms < 0) {
+        return ApiError::BadRequest(format!("第 {} 步的数量必须为正数且间隔不能为负", i + 1)).into_response();
+    }
+    let env = req.env_index.filter(|e| e.is_finite() && *e > 0.0).unwrap_or(1.0);
+
+    let points: Vec<SimulationPoint> = PricingEngine::simulate_sequence(item, env, &req.steps, &config)
+        .into_iter()
+        .map(|p| SimulationPoint {
+            total_price: p.total_price.round_2(),
+            unit_price: p.unit_price.round_2(),
+            n_before: p.n_before.round_2(),
+            n_after: p.n_after.round_2(),
+            ..p
+        })
+        .collect();
+    Json(serde_json::json!({
+        "itemId": item.id,
+        "envIndex": env,
+        "points": points
+    })).into_response()
+}
+
+/// 就绪探针：数据目录可写且写入通道未饱和时返回 200，否则 503
+pub async fn readiness(State(state): State<AppState>) -> impl IntoResponse {
+    readiness_report(&state, READY_PROBE_FILE)
+}
+
+/// `probe` 为探针文件路径，其所在目录即被检查的数据目录
+/// 只读模式下不写探针文件 (磁盘可写与否与只读服务无关)
+fn readiness_report(state: &AppState, probe: &str) -> axum::response::Response {
+    let now = chrono::Utc::now().timestamp();
+    let read_only = state.read_only.load(Ordering::Relaxed);
+    let disk_ok = read_only || match Storage::atomic_save(probe, &now) {
+        Ok(_) => true,
+        Err(e) => { tracing::warn!("{}", state.config.read().log_lang.render(Msg::ProbeFailed, &[&format!("{:?}", e)])); false }
+    };
+    let channel_ok = state.tx.capacity() > 0;
+
+    let status = if disk_ok && channel_ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(serde_json::json!({
+        "ready": disk_ok && channel_ok,
+        "readOnly": read_only,
+        "diskWritable": disk_ok,
+        "channelAvailable": channel_ok,
+        "channelCapacity": state.tx.capacity()
+    }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYER: &str = "0123456789abcdef0123456789abcdef";
+
+    fn row(amount: f64, is_preview: bool) -> TradeRequest {
+        TradeRequest {
+            player_id: PLAYER.into(), player_name: "Steve".into(), item_id: "stone".into(),
+            amount, base_price: 100.0, decay_lambda: 0.01, manual_env_index: Some(1.0),
+            is_preview,
+            ..Default::default()
+        }
+    }
+
+    async fn body_json<T: serde::de::DeserializeOwned>(resp: axum::response::Response) -> T {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn stone() -> MarketItem {
+        MarketItem { id: "stone".into(), name: "Stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_once_the_writer_channel_is_full() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+
+        let ready = readiness(State(state.clone())).await.into_response();
+        assert_eq!(ready.status(), StatusCode::OK);
+        let body: serde_json::Value = body_json(ready).await;
+        assert_eq!((body["diskWritable"].as_bool(), body["channelAvailable"].as_bool()), (Some(true), Some(true)));
+
+        // 写入线程积压：通道再无空位
+        while state.tx.try_send(TransactionRecord::default()).is_ok() {}
+        let busy = readiness(State(state)).await.into_response();
+        assert_eq!(busy.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = body_json(busy).await;
+        assert_eq!((body["ready"].as_bool(), body["channelAvailable"].as_bool()), (Some(false), Some(false)));
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_when_the_data_dir_is_not_writable() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        // 以普通文件充当数据目录：即使以 root 运行也无法在其下创建探针
+        let dir = std::env::temp_dir().join(format!("economy-core-{}-readonly-dir", std::process::id()));
+        std::fs::write(&dir, b"").unwrap();
+        let probe = dir.join(READY_PROBE_FILE);
+
+        let resp = readiness_report(&state, &probe.to_string_lossy());
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = body_json(resp).await;
+        assert_eq!((body["diskWritable"].as_bool(), body["channelAvailable"].as_bool()), (Some(false), Some(true)));
+        std::fs::remove_file(&dir).unwrap();
+
+        // 只读模式不写探针
+        let probe = std::env::temp_dir().join(format!("economy-core-{}-ro-probe", std::process::id()));
+        state.read_only.store(true, Ordering::Relaxed);
+        let resp = readiness_report(&state, &probe.to_string_lossy());
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = body_json(resp).await;
+        assert_eq!(body["readOnly"].as_bool(), Some(true));
+        assert!(!probe.exists());
+    }
+
+    #[tokio::test]
+    async fn sell_is_priced_settled_and_persisted_end_to_end() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig::default());
+        let resp = handle_sell(State(state.clone()), Json(row(2.0, false))).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let trade: TradeResponse = body_json(resp).await;
+        assert!(trade.success, "{}", trade.message);
+
+        let record = tokio::time::timeout(std::time::Duration::from_secs(1), handles.records.recv()).await.unwrap().unwrap();
+        assert_eq!((record.action.as_str(), record.amount, record.total_price), ("SELL", 2.0, trade.total_price));
+        assert_eq!(state.metrics.total_trades.load(Ordering::Relaxed), 1);
+        let histories = state.player_histories.read();
+        assert_eq!(histories[PLAYER].item_sales["stone"].len(), 1);
+    }
+
+    fn sales(timestamps: impl IntoIterator<Item = i64>) -> Vec<SalesRecord> {
+        timestamps.into_iter().map(|timestamp| SalesRecord { timestamp, amount: 1.0, env_index: 1.0, price: 1.0, seq: 0 }).collect()
+    }
+
+    #[test]
+    fn import_caps_and_sanitizes_new_players_too() {
+        const CAP: usize = 40;
+        let (state, _handles) = AppState::new_for_test(AppConfig { max_records_per_item: CAP, ..Default::default() });
+        let existing = PlayerSalesHistory {
+            player_id: "old".into(), player_name: "Steve".into(),
+            item_sales: FxHashMap::from_iter([("stone".to_string(), sales([10, 20]))]),
+        };
+        state.player_histories.write().insert("old".into(), existing);
+
+        let mut fresh = sales(1..=(CAP as i64 + 3));
+        fresh[0].amount = f64::NAN;
+        let payload = HashMap::from([
+            ("new".to_string(), PlayerSalesHistory {
+                player_name: "Alex".into(),
+                item_sales: FxHashMap::from_iter([("stone".to_string(), fresh)]),
+                ..Default::default()
+            }),
+            ("old".to_string(), PlayerSalesHistory {
+                player_name: "Imposter".into(),
+                item_sales: FxHashMap::from_iter([("stone".to_string(), sales([15, 20]))]),
+                ..Default::default()
+            }),
+        ]);
+
+        assert_eq!(merge_imported_histories(&state, payload), (1, 1, CAP + 3));
+        let histories = state.player_histories.read();
+        let keys = |id: &str| histories[id].item_sales["stone"].iter().map(|r| r.timestamp).collect::<Vec<_>>();
+        // 新玩家：NaN 记录被移除，按 max_records_per_item 只保留最新 CAP 条
+        assert_eq!(keys("new"), (4..=(CAP as i64 + 3)).collect::<Vec<_>>());
+        assert_eq!(histories["new"].player_id, "new");
+        // 老玩家：去重合并，名称不被覆盖
+        assert_eq!(keys("old"), vec![10, 15, 20]);
+        assert_eq!(histories["old"].player_name, "Steve");
+    }
+
+    #[test]
+    fn import_keeps_same_millisecond_sub_trades_and_item_caps() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { max_records_per_item: 2, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { max_records: Some(10), ..stone() }];
+        // 已有同一毫秒内的两笔子交易
+        let existing = vec![
+            SalesRecord { timestamp: 10, amount: 1.0, seq: 0, ..Default::default() },
+            SalesRecord { timestamp: 10, amount: 2.0, seq: 1, ..Default::default() },
+        ];
+        state.player_histories.write().insert("p".into(), PlayerSalesHistory {
+            player_id: "p".into(),
+            item_sales: FxHashMap::from_iter([("stone".to_string(), existing)]),
+            ..Default::default()
+        });
+
+        // 导入重复的 (10, 1)、新的 (10, 2) 和 (20, 0)
+        let incoming = vec![
+            SalesRecord { timestamp: 10, amount: 9.0, seq: 1, ..Default::default() },
+            SalesRecord { timestamp: 10, amount: 3.0, seq: 2, ..Default::default() },
+            SalesRecord { timestamp: 20, amount: 4.0, seq: 0, ..Default::default() },
+        ];
+        let payload = HashMap::from([("p".to_string(), PlayerSalesHistory {
+            item_sales: FxHashMap::from_iter([("stone".to_string(), incoming)]),
+            ..Default::default()
+        })]);
+        assert_eq!(merge_imported_histories(&state, payload), (0, 1, 2));
+
+        // 物品上限 10 覆盖全局的 2：四条全部保留，已有记录优先
+        let histories = state.player_histories.read();
+        let kept: Vec<(i64, u32, f64)> = histories["p"].item_sales["stone"].iter().map(|r| (r.timestamp, r.seq, r.amount)).collect();
+        assert_eq!(kept, vec![(10, 0, 1.0), (10, 1, 2.0), (10, 2, 3.0), (20, 0, 4.0)]);
+    }
+
+    #[tokio::test]
+    async fn websocket_subscriber_receives_push_after_rest_trade() {
+        use axum::routing::{get, post};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (state, mut handles) = AppState::new_for_test(AppConfig::default());
+        tokio::spawn(async move { while handles.records.recv().await.is_some() {} });
+        *state.market_cache.write() = vec![MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() }];
+        let app = axum::Router::new()
+            .route("/calculate_sell", post(handle_sell))
+            .route("/ws", get(crate::ws::ws_handler))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        socket.send(Message::text(r#"{"cmd": "subscribe", "itemIds": ["stone"]}"#)).await.unwrap();
+        let mut next_json = async || -> serde_json::Value {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next()).await.unwrap().unwrap().unwrap();
+            serde_json::from_str(msg.to_text().unwrap()).unwrap()
+        };
+        assert_eq!(next_json().await["type"], "subscribed");
+
+        let resp = reqwest::Client::new().post(format!("http://{addr}/calculate_sell")).json(&row(5.0, false)).send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let push = next_json().await;
+        assert_eq!(push["type"], "priceUpdate");
+        assert!(push["data"]["items"]["stone"]["price"].as_f64().is_some(), "{push}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_dead_letters_share_a_single_header() {
+        let path = std::env::temp_dir().join(format!("economy-core-{}-dead-concurrent.bin", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = std::fs::remove_file(&path);
+        let (state, _handles) = AppState::new_for_test(AppConfig { dead_letter_path: path.clone(), ..Default::default() });
+
+        let writers: Vec<_> = (0..32u64).map(|i| {
+            let state = state.clone();
+            let record = TransactionRecord { seq: i + 1, ..TransactionRecord::new(1, 1.0, 10.0, 10.0, 1.0, "SELL".into(), PLAYER.into(), "Steve".into(), "stone".into()) };
+            tokio::spawn(async move { append_dead_letter(&state, record).await })
+        }).collect();
+        for writer in writers { writer.await.unwrap(); }
+
+        let bytes = std::fs::read(&path).unwrap();
+        let headers = bytes.windows(crate::HISTORY_LOG_HEADER.len()).filter(|w| *w == crate::HISTORY_LOG_HEADER).count();
+        assert_eq!(headers, 1);
+        let mut seqs: Vec<u64> = FileBackend.load_history(&path, usize::MAX).unwrap().iter().map(|r| r.seq).collect();
+        seqs.sort();
+        assert_eq!(seqs, (1..=32).collect::<Vec<_>>());
+        assert_eq!(state.metrics.write_failures.load(Ordering::Relaxed), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sanitized_item_with_zero_base_price_is_not_tradable() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig::default());
+        // 存盘中的 NaN 基准价被 sanitize 归零；客户端仍携带 base_price = 100
+        let mut item = MarketItem { base_price: f64::NAN, ..stone() };
+        assert_eq!(item.sanitize(), ["basePrice"]);
+        *state.market_cache.write() = vec![item];
+
+        let resp = handle_sell(State(state.clone()), Json(row(5.0, false))).await.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(handles.records.try_recv().is_err());
+
+        let batch = BatchTradeRequest { player_id: PLAYER.into(), player_name: "Steve".into(), requests: vec![row(5.0, false)] };
+        let body: BatchTradeResponse = body_json(handle_batch_sell(State(state.clone()), Json(batch)).await.into_response()).await;
+        assert_eq!((body.succeeded, body.failed), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn websocket_quote_follows_the_page_cursor() {
+        use axum::routing::get;
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let (state, _handles) = AppState::new_for_test(AppConfig { max_items_per_response: 2, ..Default::default() });
+        *state.market_cache.write() = ["c", "a", "b"].iter()
+            .map(|id| MarketItem { id: id.to_string(), ..stone() })
+            .collect();
+        let app = axum::Router::new().route("/ws", get(crate::ws::ws_handler)).with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws")).await.unwrap();
+        let mut quote = async |cmd: serde_json::Value| -> serde_json::Value {
+            socket.send(Message::text(cmd.to_string())).await.unwrap();
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), socket.next()).await.unwrap().unwrap().unwrap();
+            serde_json::from_str(msg.to_text().unwrap()).unwrap()
+        };
+        let ids = |page: &serde_json::Value| {
+            let mut ids: Vec<String> = page["data"]["items"].as_object().unwrap().keys().cloned().collect();
+            ids.sort();
+            ids
+        };
+
+        let first = quote(serde_json::json!({ "cmd": "quote" })).await;
+        assert_eq!(ids(&first), ["a", "b"]);
+        let cursor = first["data"]["nextCursor"].clone();
+        let second = quote(serde_json::json!({ "cmd": "quote", "cursor": cursor })).await;
+        assert_eq!(ids(&second), ["c"]);
+        assert_eq!(second["data"]["truncated"], false);
+    }
+
+    #[tokio::test]
+    async fn market_prices_report_the_lambda_actually_used() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        // 服务端 lambda 已被覆盖 (负值按绝对值使用)
+        let items = [("stone", 0.01), ("dirt", -0.05)];
+        *state.market_cache.write() = items.iter()
+            .map(|&(id, lambda)| MarketItem { id: id.into(), base_price: 100.0, lambda, ..Default::default() })
+            .collect();
+
+        let quote = quote_market(&state, vec!["stone".into(), "dirt".into()], &[]).await.unwrap();
+        assert_eq!(quote["items"]["stone"]["effectiveLambda"], 0.01);
+        assert_eq!(quote["items"]["dirt"]["effectiveLambda"], 0.05);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::await_holding_lock)] // 有意在等待期间占住写锁，模拟繁忙的历史记录
+    async fn busy_history_lock_serves_stale_prices_without_blocking_the_runtime() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { history_lock_timeout_ms: 20, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() }];
+        let prices = || {
+            let state = state.clone();
+            // 期限远大于锁等待时间：一旦阻塞在读锁上就会超时失败
+            async move {
+                let request = MarketPriceRequest::default();
+                tokio::time::timeout(Duration::from_secs(1), get_market_prices(State(state), Json(request)))
+                    .await.expect("行情查询不应阻塞在历史读锁上").into_response()
+            }
+        };
+
+        // 没有快照时直接 503
+        let writer = state.player_histories.write();
+        assert_eq!(prices().await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(writer);
+
+        let fresh: serde_json::Value = body_json(prices().await).await;
+        assert_eq!(fresh["stale"], false);
+
+        // 有快照时返回 stale: true 的旧价格
+        let writer = state.player_histories.write();
+        let stale: serde_json::Value = body_json(prices().await).await;
+        assert_eq!(stale["stale"], true);
+        assert_eq!(stale["items"]["stone"]["price"], fresh["items"]["stone"]["price"]);
+        drop(writer);
+    }
+
+
+    #[tokio::test]
+    async fn large_sell_trips_the_item_breaker() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig { max_price_move_pct: 10.0, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+
+        // λ·Δn = 0.2：现价下跌约 18%
+        let first = handle_sell(State(state.clone()), Json(row(20.0, false))).await.into_response();
+        assert_eq!(first.status(), StatusCode::OK);
+        tokio::time::timeout(Duration::from_secs(1), handles.records.recv()).await.unwrap().unwrap();
+
+        let second = handle_sell(State(state.clone()), Json(row(1.0, false))).await.into_response();
+        assert_eq!(second.status(), StatusCode::TOO_MANY_REQUESTS);
+        let stats: serde_json::Value = body_json(get_item_stats(State(state)).await.into_response()).await;
+        assert_eq!(stats["items"][0]["breakerTripped"], true);
+    }
+
+    #[tokio::test]
+    async fn items_sharing_a_category_both_get_the_category_delta() {
+        let config = AppConfig {
+            noise_std: 0.0, weekend_factor: 0.0, holiday_factor: 0.0,
+            category_env_deltas: HashMap::from([("food".to_string(), 0.5)]),
+            ..Default::default()
+        };
+        let (state, _handles) = AppState::new_for_test(config);
+        let food = |id: &str| MarketItem { id: id.into(), category: Some("food".into()), ..stone() };
+        *state.market_cache.write() = vec![food("bread"), food("apple"), stone()];
+
+        let quote = quote_market(&state, vec![], &[]).await.unwrap();
+        let price = |id: &str| quote["items"][id]["price"].as_f64().unwrap();
+        assert_eq!(price("bread"), price("apple"));
+        // 环境指数 ≈ 1.0：分类物品在其之上 +0.5
+        assert!((price("bread") - price("stone") - 50.0).abs() < 1.0, "{quote}");
+    }
+
+    #[tokio::test]
+    async fn price_tiers_follow_the_integral_from_current_n() {
+        let config = AppConfig { noise_std: 0.0, weekend_factor: 0.0, holiday_factor: 0.0, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        *state.market_cache.write() = vec![MarketItem { n: 10.0, ..stone() }];
+
+        let quote = quote_market(&state, vec![], &[1.0, 16.0, 64.0]).await.unwrap();
+        let tiers = quote["tiers"]["stone"].as_array().unwrap();
+        assert_eq!(tiers.len(), 3);
+        assert_eq!(tiers[2]["quantity"], 64.0);
+        // 环境指数 ≈ 1.0：64 档总价 = [10, 74] 上的积分
+        let expected = PricingEngine::integral_revenue(100.0, 1.0, 10.0, 64.0, 0.01);
+        let total = tiers[2]["sellTotal"].as_f64().unwrap();
+        assert!((total - expected).abs() / expected < 1e-3, "{total} vs {expected}");
+
+        let too_many = vec![1.0; MAX_PRICE_TIERS + 1];
+        assert!(matches!(quote_market(&state, vec![], &too_many).await, Err(ApiError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn initial_n_only_applies_to_newly_synced_items() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { n: 5.0, ..stone() }];
+
+        let items = vec![
+            MarketItem { initial_n: Some(50.0), ..stone() },
+            MarketItem { id: "ore".into(), initial_n: Some(20.0), ..stone() },
+            MarketItem { id: "gem".into(), ..stone() },
+        ];
+        let resp = sync_market(State(state.clone()), Json(MarketSyncRequest { items, ..Default::default() })).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let n = |id: &str| state.market_cache.read().iter().find(|i| i.id == id).map(|i| i.n).unwrap();
+        assert_eq!((n("stone"), n("ore"), n("gem")), (5.0, 20.0, 0.0));
+        let quote = quote_market(&state, vec![], &[]).await.unwrap();
+        let price = |id: &str| quote["items"][id]["price"].as_f64().unwrap();
+        assert!(price("ore") < price("gem"));
+    }
+
+    #[tokio::test]
+    async fn roundtrip_resale_is_below_buy_cost() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { buy_premium: 1.25, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { n: 30.0, ..stone() }];
+
+        let req = RoundTripRequest { item_id: "stone".into(), amount: 10.0 };
+        let report: serde_json::Value = body_json(roundtrip_quote(State(state.clone()), Json(req)).await.into_response()).await;
+        let (buy, resale) = (report["buyCost"].as_f64().unwrap(), report["resaleValue"].as_f64().unwrap());
+        assert!(resale < buy, "{report}");
+        assert!(report["netLoss"].as_f64().unwrap() > 0.0);
+        assert_eq!(report["nAfter"], 20.0);
+        // 只是模拟，不产生成交
+        assert!(state.player_histories.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unaffordable_buy_is_rejected_and_balances_track_trades() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { enforce_balances: true, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+        let grant = |amount: f64| grant_balance(State(state.clone()), Json(BalanceGrantRequest { player_id: PLAYER.into(), currency: None, amount }));
+        let balance = || state.player_balances.read()[&(PLAYER.to_string(), DEFAULT_CURRENCY.to_string())];
+
+        // 1 个石头买价 125
+        grant(50.0).await.into_response();
+        let rejected = handle_buy(State(state.clone()), Json(row(1.0, false))).await.into_response();
+        assert_eq!(rejected.status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(balance(), 50.0);
+        assert!(state.player_histories.read().is_empty());
+
+        grant(200.0).await.into_response();
+        let bought: TradeResponse = body_json(handle_buy(State(state.clone()), Json(row(1.0, false))).await.into_response()).await;
+        assert!(bought.success);
+        assert_eq!(balance(), (250.0 - bought.total_price).round_2());
+
+        let sold: TradeResponse = body_json(handle_sell(State(state.clone()), Json(row(1.0, false))).await.into_response()).await;
+        assert_eq!(balance(), (250.0 - bought.total_price + sold.total_price).round_2());
+
+        let shown: serde_json::Value = body_json(get_player_balance(State(state.clone()), Path(PLAYER.to_string())).await.into_response()).await;
+        assert_eq!(shown["balances"][DEFAULT_CURRENCY], balance());
+
+        // 扣款不能把余额扣成负数；恰好扣到 0 允许
+        let before = balance();
+        assert_eq!(grant(-(before + 0.01)).await.into_response().status(), StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(balance(), before);
+        assert_eq!(grant(-before).await.into_response().status(), StatusCode::OK);
+        assert_eq!(balance(), 0.0);
+        // 没有余额记录的玩家同样不能被扣成负数，也不会因此生成记录
+        let stranger = grant_balance(State(state.clone()), Json(BalanceGrantRequest { player_id: "nobody".into(), currency: None, amount: -1.0 }));
+        assert_eq!(stranger.await.into_response().status(), StatusCode::PAYMENT_REQUIRED);
+        assert!(!state.player_balances.read().contains_key(&("nobody".to_string(), DEFAULT_CURRENCY.to_string())));
+    }
+
+    #[tokio::test]
+    async fn global_price_multiplier_scales_trades_and_quotes() {
+        let config = AppConfig { noise_std: 0.0, weekend_factor: 0.0, holiday_factor: 0.0, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        *state.market_cache.write() = vec![stone()];
+        let prices = || {
+            let state = state.clone();
+            async move {
+                let sell: TradeResponse = body_json(handle_sell(State(state.clone()), Json(row(3.0, true))).await.into_response()).await;
+                let buy: TradeResponse = body_json(handle_buy(State(state.clone()), Json(row(3.0, true))).await.into_response()).await;
+                let quote = quote_market(&state, vec![], &[]).await.unwrap();
+                [sell.total_price, buy.total_price, quote["items"]["stone"]["price"].as_f64().unwrap()]
+            }
+        };
+
+        let before = prices().await;
+        // 配置按请求读取，修改后立即生效
+        state.config.write().global_price_multiplier = 1.5;
+        let after = prices().await;
+        for (b, a) in before.iter().zip(after) {
+            assert!((a / b - 1.5).abs() < 1e-3, "{b} -> {a}");
+        }
+    }
+
+    #[tokio::test]
+    async fn trade_below_item_minimum_is_rejected() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { min_trade_amount: Some(1.0), ..stone() }];
+
+        let resp = handle_sell(State(state.clone()), Json(row(0.5, true))).await.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = body_json(resp).await;
+        assert!(body["error"].as_str().unwrap().contains("不得少于 1"));
+        assert_eq!(handle_sell(State(state.clone()), Json(row(1.0, true))).await.into_response().status(), StatusCode::OK);
+
+        // 同步未携带下限时保留原值
+        sync_market(State(state.clone()), Json(MarketSyncRequest { items: vec![stone()], ..Default::default() })).await.into_response();
+        assert_eq!(state.market_cache.read()[0].min_trade_amount, Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn mismatched_name_is_not_adopted_when_client_is_untrusted() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { trust_client_name: false, ..Default::default() });
+        let record = |name: &str| TransactionRecord::new(1, 1.0, 10.0, 10.0, 1.0, "SELL".into(), PLAYER.into(), name.into(), "stone".into());
+        let name = || state.player_histories.read()[PLAYER].player_name.clone();
+
+        persist_transaction(state.clone(), record("Steve"), false).await;
+        persist_transaction(state.clone(), record("Mallory"), false).await;
+        assert_eq!(name(), "Steve");
+
+        // 管理接口仍可显式改名
+        let rename = PlayerRenameRequest { player_id: PLAYER.into(), player_name: "Alex".into() };
+        rename_player(State(state.clone()), Json(rename)).await.into_response();
+        assert_eq!(name(), "Alex");
+
+        state.config.write().trust_client_name = true;
+        persist_transaction(state.clone(), record("Mallory"), false).await;
+        assert_eq!(name(), "Mallory");
+    }
+
+    #[tokio::test]
+    async fn deleted_item_is_archived_and_restored_with_its_state() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { n: 42.0, iota: 3.0, ..stone() }];
+
+        let archived: MarketItem = body_json(delete_item(State(state.clone()), Path("stone".into())).await.into_response()).await;
+        assert_eq!((archived.n, archived.iota), (42.0, 3.0));
+        assert!(state.market_cache.read().is_empty());
+        // 删除不存在的物品不触发市场快照
+        state.dirty.market.store(false, Ordering::Relaxed);
+        let missing = delete_item(State(state.clone()), Path("stone".into())).await.into_response();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert!(!state.dirty.market.load(Ordering::Relaxed));
+
+        let restored = restore_item(State(state.clone()), Path("stone".into())).await.into_response();
+        assert_eq!(restored.status(), StatusCode::OK);
+        assert_eq!(state.market_cache.read()[0].n, 42.0);
+        assert!(state.archived_items.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn display_price_follows_n_jumps_gradually() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { display_ema_alpha: 0.25, noise_std: 0.0, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+        let first = quote_market(&state, vec![], &[]).await.unwrap();
+        assert_eq!(first["items"]["stone"]["displayPrice"], first["items"]["stone"]["price"]);
+
+        // 库存骤增：现价立即下跌，展示价只移动 alpha 的比例
+        state.market_cache.write()[0].n = 50.0;
+        let jumped = quote_market(&state, vec![], &[]).await.unwrap();
+        let (before, price, display) = (
+            first["items"]["stone"]["price"].as_f64().unwrap(),
+            jumped["items"]["stone"]["price"].as_f64().unwrap(),
+            jumped["items"]["stone"]["displayPrice"].as_f64().unwrap(),
+        );
+        assert!(price < before - 30.0, "{price} vs {before}");
+        assert!((display - (before + 0.25 * (price - before))).abs() < 0.02, "{display}");
+
+        // 持续查询后收敛到现价
+        for _ in 0..60 { quote_market(&state, vec![], &[]).await.unwrap(); }
+        let settled = quote_market(&state, vec![], &[]).await.unwrap();
+        assert!((settled["items"]["stone"]["displayPrice"].as_f64().unwrap() - price).abs() < 0.02);
+    }
+
+    #[tokio::test]
+    async fn same_millisecond_sales_stay_distinguishable() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let record = |amount: f64| TransactionRecord::new(1_000, amount, amount * 10.0, 10.0, 1.0, "SELL".into(), PLAYER.into(), "Steve".into(), "stone".into());
+        for amount in [1.0, 2.0, 3.0] {
+            persist_transaction(state.clone(), record(amount), false).await;
+        }
+        persist_transaction(state.clone(), TransactionRecord { timestamp: 2_000, ..record(4.0) }, false).await;
+
+        let histories = state.player_histories.read();
+        let sales = &histories[PLAYER].item_sales["stone"];
+        let keys: Vec<_> = sales.iter().map(|r| (r.timestamp, r.seq)).collect();
+        assert_eq!(keys, [(1_000, 0), (1_000, 1), (1_000, 2), (2_000, 0)]);
+        // 按 (timestamp, seq) 可精确定位同一毫秒内的某一笔
+        let target = sales.iter().find(|r| (r.timestamp, r.seq) == (1_000, 1)).unwrap();
+        assert_eq!(target.amount, 2.0);
+    }
+
+    #[tokio::test]
+    async fn neff_breakdown_sums_to_the_effective_n() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { global_iota: 2.5, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { n: 40.0, iota: 7.0, ..stone() }];
+        let now = chrono::Utc::now().timestamp_millis();
+        for (player, amount) in [("p1", 10.0), ("p2", 5.0)] {
+            let sales = vec![SalesRecord { timestamp: now, amount, ..Default::default() }];
+            let history = PlayerSalesHistory { item_sales: FxHashMap::from_iter([("stone".to_string(), sales)]), ..Default::default() };
+            state.player_histories.write().insert(player.into(), history);
+        }
+
+        let body: serde_json::Value = body_json(get_item_neff(State(state.clone()), Path("stone".into())).await.into_response()).await;
+        let part = |key: &str| body[key].as_f64().unwrap();
+        assert!((part("historyContribution") - 15.0).abs() < 0.01);
+        let sum = part("historyContribution") + part("staticN") + part("itemIota") + part("globalIota") + part("launchIota");
+        assert!((sum - part("effectiveN")).abs() < 1e-9);
+
+        let missing = get_item_neff(State(state), Path("dirt".into())).await.into_response();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn preview_only_batch_writes_no_records() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig::default());
+        let batch = |requests| BatchTradeRequest { player_id: PLAYER.into(), player_name: "Steve".into(), requests };
+
+        let resp = handle_batch_sell(State(state.clone()), Json(batch(vec![row(1.0, true), row(2.0, true)]))).await.into_response();
+        let body: BatchTradeResponse = body_json(resp).await;
+        assert!(body.results.iter().all(|r| r.success && r.trade_id.is_none()));
+        assert!(handles.records.try_recv().is_err());
+        assert_eq!(state.metrics.total_trades.load(Ordering::Relaxed), 0);
+        assert!(state.player_histories.read().is_empty());
+
+        // 混合批次只有非预览行成交
+        let resp = handle_batch_sell(State(state.clone()), Json(batch(vec![row(1.0, true), row(2.0, false)]))).await.into_response();
+        let body: BatchTradeResponse = body_json(resp).await;
+        assert_eq!(body.results.iter().filter(|r| r.trade_id.is_some()).count(), 1);
+        let record = tokio::time::timeout(Duration::from_secs(1), handles.records.recv()).await.unwrap().unwrap();
+        assert_eq!(record.amount, 2.0);
+        assert!(handles.records.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn sync_filters_items_outside_the_allowlist() {
+        let allowlist = vec!["minecraft:*".to_string(), "custom:gem_*_shard".to_string()];
+        let (state, _handles) = AppState::new_for_test(AppConfig { item_id_allowlist: allowlist, ..Default::default() });
+        let items = ["minecraft:stone", "custom:gem_ruby_shard", "custom:gem_ruby", "junk"]
+            .map(|id| MarketItem { id: id.into(), ..stone() });
+
+        let resp = sync_market(State(state.clone()), Json(MarketSyncRequest { items: items.to_vec(), ..Default::default() })).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let ids: Vec<String> = state.market_cache.read().iter().map(|i| i.id.clone()).collect();
+        assert_eq!(ids, ["minecraft:stone", "custom:gem_ruby_shard"]);
+
+        // 未配置白名单时全部接受
+        state.config.write().item_id_allowlist.clear();
+        sync_market(State(state.clone()), Json(MarketSyncRequest { items: items.to_vec(), ..Default::default() })).await.into_response();
+        assert_eq!(state.market_cache.read().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn batch_response_reports_aggregates() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let requests = vec![row(1.0, false), row(0.0, false), row(2.0, true)];
+        let batch = BatchTradeRequest { player_id: PLAYER.into(), player_name: "Steve".into(), requests };
+
+        let body: BatchTradeResponse = body_json(handle_batch_sell(State(state), Json(batch)).await.into_response()).await;
+        assert_eq!((body.results.len(), body.succeeded, body.failed), (3, 2, 1));
+        let expected: f64 = body.results.iter().filter(|r| r.success).map(|r| r.total_price).sum();
+        assert!((body.total_value - expected).abs() < 0.005);
+        assert!(body.total_value > 0.0);
+    }
+
+    #[tokio::test]
+    async fn price_label_is_formatted_on_request() {
+        assert_eq!(format_price_label(1234.5, ",", "coins"), "1,234.50 coins");
+        assert_eq!(format_price_label(1234567.891, " ", ""), "1 234 567.89");
+        assert_eq!(format_price_label(-999.0, ",", "g"), "-999.00 g");
+
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { base_price: 1234.56, lambda: 0.0, ..stone() }];
+        let request = |format_price| MarketPriceRequest { format_price, ..Default::default() };
+        let quote = |format_price| get_market_prices(State(state.clone()), Json(request(format_price)));
+
+        let body: serde_json::Value = body_json(quote(true).await.into_response()).await;
+        let price = body["items"]["stone"]["price"].as_f64().unwrap();
+        assert_eq!(body["items"]["stone"]["priceLabel"], format_price_label(price, ",", "coins"));
+        let plain: serde_json::Value = body_json(quote(false).await.into_response()).await;
+        assert!(plain["items"]["stone"].get("priceLabel").is_none());
+    }
+
+    #[tokio::test]
+    async fn base_price_override_applies_until_expiry() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { lambda: 0.0, ..stone() }];
+        let now = chrono::Utc::now().timestamp_millis();
+        let base_price = || async { quote_market(&state, vec![], &[]).await.unwrap()["items"]["stone"]["basePrice"].as_f64().unwrap() };
+
+        let set = |price, expires_at| set_price_override(State(state.clone()), Path("stone".into()), Json(BasePriceOverrideRequest { price, expires_at }));
+        assert_eq!(set(60.0, now + 60_000).await.into_response().status(), StatusCode::OK);
+        assert_eq!(base_price().await, 60.0);
+        // 成交同样按限时价计算 (客户端携带的 base_price 为 100)
+        let trade: TradeResponse = body_json(handle_sell(State(state.clone()), Json(row(1.0, true))).await.into_response()).await;
+        assert_eq!(trade.total_price, 60.0);
+
+        // 同步保留未到期的限时价
+        sync_market(State(state.clone()), Json(MarketSyncRequest { items: vec![stone()], ..Default::default() })).await.into_response();
+        assert_eq!(base_price().await, 60.0);
+
+        // 到期后恢复原价
+        state.market_cache.write()[0].base_price_override = Some((60.0, now - 1));
+        assert_eq!(base_price().await, 100.0);
+        assert_eq!(set(60.0, now - 1).await.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn large_catalog_is_paginated_by_cursor() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { max_items_per_response: 2, ..Default::default() });
+        *state.market_cache.write() = ["e", "c", "a", "d", "b"].iter()
+            .map(|id| MarketItem { id: id.to_string(), ..stone() })
+            .collect();
+
+        let mut cursor: Option<String> = None;
+        let mut pages = Vec::new();
+        loop {
+            let request = MarketPriceRequest { cursor: cursor.clone(), ..Default::default() };
+            let page: serde_json::Value = body_json(get_market_prices(State(state.clone()), Json(request)).await.into_response()).await;
+            let mut ids: Vec<String> = page["items"].as_object().unwrap().keys().cloned().collect();
+            ids.sort();
+            pages.push(ids);
+            if page["truncated"] == false {
+                assert!(page.get("nextCursor").is_none());
+                break;
+            }
+            cursor = Some(page["nextCursor"].as_str().unwrap().to_string());
+        }
+        assert_eq!(pages, [vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+
+        // 不限制时一次返回全部
+        state.config.write().max_items_per_response = 0;
+        let all = quote_market(&state, vec![], &[]).await.unwrap();
+        assert_eq!((all["items"].as_object().unwrap().len(), &all["truncated"]), (5, &serde_json::json!(false)));
+    }
+
+    #[tokio::test]
+    async fn large_unconfirmed_trade_is_held_until_confirmed() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig { confirm_threshold: 1_000.0, ..Default::default() });
+        let large = TradeRequest { base_price: 600.0, decay_lambda: 0.0, ..row(2.0, false) };
+
+        let held = handle_sell(State(state.clone()), Json(large.clone())).await.into_response();
+        assert_eq!(held.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value = body_json(held).await;
+        assert_eq!(body["error"], "confirm required");
+        assert_eq!(body["quote"]["totalPrice"], 1200.0);
+        assert!(handles.records.try_recv().is_err());
+
+        // 小额交易不受影响
+        let small = handle_sell(State(state.clone()), Json(row(1.0, false))).await.into_response();
+        assert_eq!(small.status(), StatusCode::OK);
+        handles.records.recv().await.unwrap();
+
+        let confirmed = handle_sell(State(state.clone()), Json(TradeRequest { confirmed: true, ..large })).await.into_response();
+        assert_eq!(confirmed.status(), StatusCode::OK);
+        let record = tokio::time::timeout(Duration::from_secs(1), handles.records.recv()).await.unwrap().unwrap();
+        assert_eq!(record.total_price, 1200.0);
+    }
+
+    #[tokio::test]
+    async fn recovery_time_matches_the_exponential_solution() {
+        let config = AppConfig { recovery_delta: 1.0, recovery_tau: 1000.0, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        *state.market_cache.write() = vec![stone()];
+        let now = chrono::Utc::now().timestamp_millis();
+        let sales = vec![SalesRecord { timestamp: now, amount: 100.0, ..Default::default() }];
+        let history = PlayerSalesHistory { item_sales: FxHashMap::from_iter([("stone".to_string(), sales)]), ..Default::default() };
+        state.player_histories.write().insert(PLAYER.into(), history);
+
+        let query = || Query(RecoveryQuery { target_ratio: 0.9 });
+        let body = body_json(get_item_recovery(State(state.clone()), Path("stone".into()), query()).await.into_response()).await;
+        // n=100, λ=0.01, δ=1, τ=1000: t = 1000·ln(100 / (-ln 0.9 / 0.01)) ≈ 2250 秒
+        let expected = 1000.0 * (100.0 / (-(0.9f64).ln() / 0.01)).ln();
+        assert!((body["seconds"].as_f64().unwrap() - expected).abs() < 1.0);
+
+        // 没有历史时价格已在目标之上
+        state.player_histories.write().clear();
+        let body = body_json(get_item_recovery(State(state.clone()), Path("stone".into()), query()).await.into_response()).await;
+        assert!(body["seconds"].is_null());
+        assert_eq!(body["reachable"], true);
+
+        // 静态库存本身已超出目标，永远无法恢复
+        *state.market_cache.write() = vec![MarketItem { n: 50.0, ..stone() }];
+        let body = body_json(get_item_recovery(State(state), Path("stone".into()), query()).await.into_response()).await;
+        assert_eq!(body["reachable"], false);
+    }
+
+    #[tokio::test]
+    async fn record_cap_follows_config_and_item_override() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { max_records_per_item: 150, ..Default::default() });
+        let record = |ts: i64| TransactionRecord::new(ts, 1.0, 10.0, 10.0, 1.0, "SELL".into(), PLAYER.into(), "Steve".into(), "stone".into());
+        for ts in 0..200 {
+            persist_transaction(state.clone(), record(ts), false).await;
+        }
+        let kept = |state: &AppState| state.player_histories.read()[PLAYER].item_sales["stone"].len();
+        assert_eq!(kept(&state), 150);
+
+        // 物品级上限优先于全局配置
+        *state.market_cache.write() = vec![MarketItem { max_records: Some(20), ..stone() }];
+        persist_transaction(state.clone(), record(200), false).await;
+        assert_eq!(kept(&state), 20);
+        assert_eq!(state.player_histories.read()[PLAYER].item_sales["stone"].last().unwrap().timestamp, 200);
+
+        // 高于全局配置的物品级上限同样生效：保留的记录可以超过全局的 150 条
+        *state.market_cache.write() = vec![MarketItem { max_records: Some(300), ..stone() }];
+        for ts in 201..451 {
+            persist_transaction(state.clone(), record(ts), false).await;
+        }
+        assert_eq!(kept(&state), 270);
+        for ts in 451..500 {
+            persist_transaction(state.clone(), record(ts), false).await;
+        }
+        assert_eq!(kept(&state), 300);
+        assert_eq!(state.player_histories.read()[PLAYER].item_sales["stone"][0].timestamp, 200);
+    }
+
+    #[tokio::test]
+    async fn market_params_reproduce_quoted_prices() {
+        let config = AppConfig { env_cache_granularity_secs: 3600, global_price_multiplier: 1.2, global_iota: 3.0, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        *state.market_cache.write() = vec![MarketItem { n: 20.0, iota: 5.0, ..stone() }];
+        let now = chrono::Utc::now().timestamp_millis();
+        let sales = vec![SalesRecord { timestamp: now, amount: 30.0, ..Default::default() }];
+        let history = PlayerSalesHistory { item_sales: FxHashMap::from_iter([("stone".to_string(), sales)]), ..Default::default() };
+        state.player_histories.write().insert(PLAYER.into(), history);
+
+        let params = body_json(get_market_params(State(state.clone())).await.into_response()).await;
+        assert!(params["validUntil"].as_i64().unwrap() > params["serverTime"].as_i64().unwrap());
+        let p = &params["items"]["stone"];
+        let f = |key: &str| p[key].as_f64().unwrap();
+        let price = f("envIndex") * f("basePrice") * (-f("lambda") * f("effectiveN")).exp() * params["priceMultiplier"].as_f64().unwrap();
+
+        let quote = quote_market(&state, vec![], &[]).await.unwrap();
+        let status = &quote["items"]["stone"];
+        assert!((price - status["price"].as_f64().unwrap()).abs() < 0.01);
+        let buy = price * params["buyPremium"].as_f64().unwrap();
+        assert!((buy - status["buyPrice"].as_f64().unwrap()).abs() < 0.01);
+    }
+
+    #[tokio::test]
+    async fn concurrent_syncs_apply_only_the_latest_generation() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { n: 42.0, iota: 3.0, ..stone() }];
+        let partial = MarketSyncRequest { items: vec![MarketItem { base_price: 50.0, ..stone() }], generation: Some(1) };
+        let full = MarketSyncRequest {
+            items: vec![MarketItem { base_price: 80.0, ..stone() }, MarketItem { id: "dirt".into(), ..stone() }],
+            generation: Some(2),
+        };
+
+        let (a, b) = tokio::join!(
+            tokio::spawn(sync_market(State(state.clone()), Json(partial.clone()))),
+            tokio::spawn(sync_market(State(state.clone()), Json(full))),
+        );
+        let statuses = [a.unwrap().into_response().status(), b.unwrap().into_response().status()];
+        assert_eq!(statuses[1], StatusCode::OK);
+
+        // 无论到达顺序如何，最终都是代次 2 的物品集，且 n / iota 沿用同步前的状态
+        let cache = state.market_cache.read().clone();
+        let ids: Vec<&str> = cache.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(ids, ["stone", "dirt"]);
+        assert_eq!((cache[0].base_price, cache[0].n, cache[0].iota), (80.0, 42.0, 3.0));
+        assert_eq!(state.sync_generation.load(Ordering::Relaxed), 2);
+
+        // 过期代次被拒绝
+        let stale = sync_market(State(state.clone()), Json(partial)).await.into_response();
+        assert_eq!(stale.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn stale_catalog_is_rejected_when_items_must_be_known() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { require_known_item: true, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+
+        assert!(execute_trade(&state, row(1.0, true), TradeAction::Sell).await.is_ok());
+        let outdated = TradeRequest { base_price: 120.0, ..row(1.0, true) };
+        let err = execute_trade(&state, outdated.clone(), TradeAction::Sell).await.unwrap_err();
+        assert!(matches!(err, ApiError::StaleCatalog(_)));
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+        let unknown = TradeRequest { item_id: "dirt".into(), ..row(1.0, true) };
+        assert!(matches!(execute_trade(&state, unknown, TradeAction::Sell).await, Err(ApiError::NotFound(_))));
+
+        // 默认不校验
+        state.config.write().require_known_item = false;
+        assert!(execute_trade(&state, outdated, TradeAction::Sell).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn floor_n_damps_prices_without_history() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { env_cache_granularity_secs: 3600, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { floor_n: 50.0, ..stone() }, MarketItem { id: "dirt".into(), ..stone() }];
+
+        let quote = quote_market(&state, vec![], &[]).await.unwrap();
+        let price = |id: &str| quote["items"][id]["price"].as_f64().unwrap();
+        assert!((quote["items"]["stone"]["neff"].as_f64().unwrap() - 50.0).abs() < 1e-9);
+        assert!((price("stone") / price("dirt") - (-0.5f64).exp()).abs() < 1e-3);
+
+        // 交易路径同样受下限约束
+        let resp = execute_trade(&state, row(1.0, true), TradeAction::Sell).await.unwrap();
+        assert!((resp.effective_n - 50.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn filling_the_writer_channel_raises_server_load() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { load_reference_rps: 0.0, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+        let idle = quote_market(&state, vec![], &[]).await.unwrap();
+        assert_eq!(idle["serverLoad"].as_f64().unwrap(), 0.0);
+
+        while state.tx.try_send(TransactionRecord::default()).is_ok() {}
+        let busy = quote_market(&state, vec![], &[]).await.unwrap();
+        assert_eq!(busy["serverLoad"].as_f64().unwrap(), 1.0);
+        let resp = execute_trade(&state, row(1.0, true), TradeAction::Sell).await.unwrap();
+        assert_eq!(resp.server_load, 1.0);
+    }
+
+    #[tokio::test]
+    async fn sub_threshold_moves_do_not_publish_price_events() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { price_event_min_delta_pct: 5.0, env_cache_granularity_secs: 3600, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+        let mut events = state.price_events.subscribe();
+        let record = |amount: f64| TransactionRecord::new(
+            chrono::Utc::now().timestamp_millis(), amount, amount * 100.0, 100.0, 1.0, "SELL".into(), PLAYER.into(), "Steve".into(), "stone".into(),
+        );
+        async fn next_event(events: &mut tokio::sync::broadcast::Receiver<crate::ws::PriceUpdate>) -> bool {
+            tokio::time::timeout(Duration::from_millis(300), events.recv()).await.is_ok()
+        }
+
+        // 首次变动作为基准推送
+        persist_transaction(state.clone(), record(1.0), false).await;
+        assert!(next_event(&mut events).await);
+
+        // 每笔约 1%，累计不足 5%
+        for _ in 0..3 {
+            persist_transaction(state.clone(), record(1.0), false).await;
+            assert!(!next_event(&mut events).await);
+        }
+
+        persist_transaction(state.clone(), record(10.0), false).await;
+        assert!(next_event(&mut events).await);
+    }
+
+    #[tokio::test]
+    async fn oversized_or_empty_batches_are_rejected() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig { max_batch_size: 3, ..Default::default() });
+        let batch = |requests| BatchTradeRequest { player_id: PLAYER.into(), player_name: "Steve".into(), requests };
+
+        let resp = handle_batch_sell(State(state.clone()), Json(batch(vec![row(1.0, false); 4]))).await.into_response();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(handles.records.try_recv().is_err());
+        let empty = handle_batch_sell(State(state.clone()), Json(batch(vec![]))).await.into_response();
+        assert_eq!(empty.status(), StatusCode::BAD_REQUEST);
+
+        let ok = handle_batch_sell(State(state), Json(batch(vec![row(1.0, true); 3]))).await.into_response();
+        assert_eq!(ok.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn market_health_index_is_one_at_base_price() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![stone(), MarketItem { id: "dirt".into(), ..stone() }];
+        let body = body_json(get_market_health(State(state.clone())).await.into_response()).await;
+        assert!((body["priceIndex"].as_f64().unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(body["dailyVolume"].as_f64().unwrap(), 0.0);
+
+        // 只有 stone 有成交：指数完全由 stone 的价格决定
+        let now = chrono::Utc::now().timestamp_millis();
+        let sales = vec![SalesRecord { timestamp: now, amount: 10.0, ..Default::default() }];
+        let history = PlayerSalesHistory { item_sales: FxHashMap::from_iter([("stone".to_string(), sales)]), ..Default::default() };
+        state.player_histories.write().insert(PLAYER.into(), history);
+        let body = body_json(get_market_health(State(state)).await.into_response()).await;
+        assert!((body["priceIndex"].as_f64().unwrap() - (-0.1f64).exp()).abs() < 1e-3);
+        assert_eq!(body["dailyVolume"].as_f64().unwrap(), 10.0);
+    }
+
+    #[tokio::test]
+    async fn slow_preview_times_out_before_slow_trade() {
+        let config = AppConfig { preview_timeout_ms: 50, trade_timeout_ms: 500, ..Default::default() };
+        let slow = || tokio::time::sleep(Duration::from_millis(200));
+
+        let preview = with_deadline(&config, true, slow()).await;
+        assert!(matches!(preview, Err(ApiError::Timeout(50))));
+        assert_eq!(preview.unwrap_err().into_response().status(), StatusCode::REQUEST_TIMEOUT);
+        assert!(with_deadline(&config, false, slow()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn recompute_refreshes_snapshot_after_multiplier_change() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { env_cache_granularity_secs: 3600, max_items_per_response: 1, ..Default::default() });
+        *state.market_cache.write() = vec![stone(), MarketItem { id: "dirt".into(), ..stone() }];
+        quote_market(&state, vec![], &[]).await.unwrap();
+        let snapshot_price = |id: &str| state.price_snapshot.read()[id].1.price;
+        let before = snapshot_price("stone");
+
+        state.config.write().global_price_multiplier = 2.0;
+        let body = body_json(recompute_prices(State(state.clone())).await.into_response()).await;
+        // 分页遍历到全部物品
+        assert_eq!(body["recomputed"], 2);
+        assert!((snapshot_price("stone") - before * 2.0).abs() < 0.02);
+        assert!((snapshot_price("dirt") - before * 2.0).abs() < 0.02);
+    }
+
+    #[tokio::test]
+    async fn selling_right_after_buying_hits_the_arbitrage_cooldown() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { arbitrage_cooldown_ms: 60_000, ..Default::default() });
+        execute_trade(&state, row(1.0, false), TradeAction::Buy).await.unwrap();
+
+        let err = execute_trade(&state, row(1.0, false), TradeAction::Sell).await.unwrap_err();
+        assert!(matches!(err, ApiError::ArbitrageCooldown { retry_after_ms, .. } if retry_after_ms > 0));
+        assert_eq!(err.into_response().status(), StatusCode::TOO_MANY_REQUESTS);
+        // 同向交易与预览不受影响
+        assert!(execute_trade(&state, row(1.0, false), TradeAction::Buy).await.is_ok());
+        assert!(execute_trade(&state, row(1.0, true), TradeAction::Sell).await.is_ok());
+
+        // 冷却结束后可以反向交易
+        state.trade_sides.write().values_mut().for_each(|(buy, _)| *buy -= 60_000);
+        assert!(execute_trade(&state, row(1.0, false), TradeAction::Sell).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn arbitrage_sides_are_pruned_and_skipped_without_cooldown() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let now = chrono::Utc::now().timestamp_millis();
+        let record = |player: usize, timestamp: i64| TransactionRecord {
+            timestamp, ..TransactionRecord::new(0, 1.0, 1.0, 1.0, 1.0, "SELL".into(), format!("p{player}"), "Steve".into(), "stone".into())
+        };
+
+        // 冷却关闭：不记录
+        note_trade_side(&state, 0, &record(0, now), false);
+        assert!(state.trade_sides.read().is_empty());
+
+        // 早已过期的条目在达到清理阈值时被移除，仍在冷却期内的保留
+        for player in 0..TRADE_SIDES_PRUNE_AT - 1 {
+            note_trade_side(&state, 60_000, &record(player, now - 120_000), false);
+        }
+        assert_eq!(state.trade_sides.read().len(), TRADE_SIDES_PRUNE_AT - 1);
+        note_trade_side(&state, 60_000, &record(usize::MAX, now), true);
+        let sides = state.trade_sides.read();
+        assert_eq!(sides.len(), 1);
+        assert!(sides.contains_key(&(format!("p{}", usize::MAX), "stone".to_string())));
+    }
+
+    #[tokio::test]
+    async fn price_stops_dropping_beyond_max_n() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { env_cache_granularity_secs: 3600, ..Default::default() });
+        let capped = MarketItem { max_n: Some(50.0), ..stone() };
+        let price_at = |n: f64| {
+            let (state, item) = (state.clone(), MarketItem { n, ..capped.clone() });
+            async move {
+                *state.market_cache.write() = vec![item];
+                quote_market(&state, vec![], &[]).await.unwrap()["items"]["stone"]["price"].as_f64().unwrap()
+            }
+        };
+
+        let below = price_at(40.0).await;
+        let at_cap = price_at(50.0).await;
+        assert!(below > at_cap);
+        assert_eq!(price_at(80.0).await, at_cap);
+        assert_eq!(price_at(500.0).await, at_cap);
+    }
+
+    #[tokio::test]
+    async fn explanation_arithmetic_matches_the_price() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { env_cache_granularity_secs: 3600, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { n: 12.0, lambda: 0.05, ..stone() }];
+        let request = |explain| MarketPriceRequest { explain, ..Default::default() };
+
+        let body = body_json(get_market_prices(State(state.clone()), Json(request(true))).await.into_response()).await;
+        let status = &body["items"]["stone"];
+        let text = status["explanation"].as_str().unwrap();
+        let numbers: Vec<f64> = text.split(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .filter_map(|s| s.parse().ok())
+            .collect();
+        let [base, env, n, lambda, total] = numbers[..] else { panic!("{text}") };
+        assert!((base * env * (-lambda * n).exp() - total).abs() < 0.01, "{text}");
+        assert!((total - status["price"].as_f64().unwrap()).abs() < 0.006, "{text}");
+
+        let plain = body_json(get_market_prices(State(state), Json(request(false))).await.into_response()).await;
+        assert!(plain["items"]["stone"].get("explanation").is_none());
+    }
+
+    #[tokio::test]
+    async fn market_prices_separate_the_noise_from_the_env_index() {
+        let config = AppConfig { noise_std: 0.05, env_cache_granularity_secs: 3600, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        *state.market_cache.write() = vec![stone()];
+
+        let body = body_json(get_market_prices(State(state.clone()), Json(MarketPriceRequest::default())).await.into_response()).await;
+        let noise = state.env_cache.read().as_ref().unwrap().last_noise;
+        let (index, base) = (body["envIndex"].as_f64().unwrap(), body["envIndexBase"].as_f64().unwrap());
+        // 两者各自保留两位小数
+        assert!((index - base - noise).abs() <= 0.01 + 1e-9, "{index} - {base} vs {noise}");
+        assert!(base >= 0.05 && index >= 0.05);
+    }
+
+    #[tokio::test]
+    async fn converted_payout_is_settled_in_the_payout_currency() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig { enforce_balances: true, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem {
+            currency: Some("gems".into()), payout_currency: Some("coins".into()), conversion_rate: Some(2.5), ..stone()
+        }];
+
+        let sold: TradeResponse = body_json(handle_sell(State(state.clone()), Json(row(1.0, false))).await.into_response()).await;
+        assert!(sold.success);
+        assert_eq!(sold.payout, Some((sold.total_price * 2.5).round_2()));
+        assert_eq!(sold.payout_currency.as_deref(), Some("coins"));
+
+        let balances = state.player_balances.read().clone();
+        assert_eq!(balances[&(PLAYER.to_string(), "coins".to_string())], sold.payout.unwrap());
+        assert!(!balances.contains_key(&(PLAYER.to_string(), "gems".to_string())));
+
+        let record = handles.records.recv().await.unwrap();
+        assert_eq!(record.total_price, sold.total_price);
+        assert_eq!((record.payout, record.payout_currency.as_deref()), (sold.payout, Some("coins")));
+    }
+
+    #[tokio::test]
+    async fn route_counters_increment_independently() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![stone()];
+        for _ in 0..2 {
+            handle_sell(State(state.clone()), Json(row(1.0, true))).await.into_response();
+        }
+        get_market_params(State(state.clone())).await.into_response();
+
+        let metrics: serde_json::Value = body_json(get_metrics(State(state.clone())).await.into_response()).await;
+        let by_route = &metrics["requestsByRoute"];
+        assert_eq!((by_route["sell"].as_u64(), by_route["market"].as_u64()), (Some(2), Some(1)));
+        assert_eq!((by_route["buy"].as_u64(), by_route["batch"].as_u64(), by_route["quote"].as_u64()), (Some(0), Some(0), Some(0)));
+
+        let body = axum::body::to_bytes(prometheus_metrics(State(state)).await.into_response().into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("economy_requests_total{route=\"sell\"} 2"), "{text}");
+        assert!(text.contains("economy_requests_total{route=\"market\"} 1"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn sync_rejects_out_of_range_lambda() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { lambda_max: 0.5, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+        let items = vec![
+            MarketItem { id: "ore".into(), ..stone() },
+            MarketItem { id: "flat".into(), lambda: 0.0, ..stone() },
+            MarketItem { id: "steep".into(), lambda: 2.0, ..stone() },
+            MarketItem { id: "neg".into(), lambda: -0.01, ..stone() },
+        ];
+
+        let resp = sync_market(State(state.clone()), Json(MarketSyncRequest { items, ..Default::default() })).await.into_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err: serde_json::Value = body_json(resp).await;
+        let msg = err["error"].as_str().unwrap();
+        assert!(msg.contains("flat") && msg.contains("steep") && msg.contains("neg") && !msg.contains("ore"));
+        // 整次同步被拒绝，目录保持原样
+        let ids: Vec<String> = state.market_cache.read().iter().map(|i| i.id.clone()).collect();
+        assert_eq!(ids, ["stone"]);
+    }
+
+    #[tokio::test]
+    async fn simulated_sequence_follows_the_hand_computed_trajectory() {
+        let config = AppConfig { recovery_delta: 1.0, recovery_tau: 10.0, buy_premium: 1.25, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        let step = |action, amount, elapsed_ms| SimulationStep { action, amount, elapsed_ms };
+        let req = SimulateSequenceRequest {
+            item: stone(),
+            env_index: None,
+            steps: vec![
+                step(TradeAction::Sell, 10.0, 0),
+                // 10 秒 = 一个 tau，历史衰减为 e^-1
+                step(TradeAction::Sell, 10.0, 10_000),
+                step(TradeAction::Buy, 5.0, 0),
+            ],
+        };
+        let resp = simulate_sequence(State(state.clone()), Json(req)).await.into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = body_json(resp).await;
+        let points = body["points"].as_array().unwrap();
+
+        // 卖出收入 = base/λ·(e^(-λ·n1) - e^(-λ·n2))，买入在此基础上乘以溢价
+        let revenue = |n1: f64, n2: f64| (100.0 / 0.01 * ((-0.01 * n1).exp() - (-0.01 * n2).exp())).round_2();
+        let decayed = 10.0 * (-1.0f64).exp();
+        let expected = [
+            (0.0, 10.0, revenue(0.0, 10.0)),
+            (decayed, decayed + 10.0, revenue(decayed, decayed + 10.0)),
+            (decayed + 10.0, decayed + 5.0, (1.25 * revenue(decayed + 5.0, decayed + 10.0)).round_2()),
+        ];
+        assert_eq!(points.len(), expected.len());
+        for (point, (n_before, n_after, total)) in points.iter().zip(expected) {
+            assert!((point["nBefore"].as_f64().unwrap() - n_before).abs() < 0.01, "{point}");
+            assert!((point["nAfter"].as_f64().unwrap() - n_after).abs() < 0.01, "{point}");
+            assert!((point["totalPrice"].as_f64().unwrap() - total).abs() < 0.02, "{point}");
+        }
+        assert_eq!(points[1]["timeMs"], 10_000);
+
+        // 不影响实时状态
+        assert!(state.player_histories.read().is_empty());
+        assert!(state.market_cache.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_price_caps_the_buy_price_after_the_premium() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { buy_premium: 1.25, env_cache_granularity_secs: 3600, ..Default::default() });
+        // 固定环境指数为 1：n = 0 时卖出现价为 100，买入价 125 被 110 的上限截断
+        *state.env_cache.write() = Some(EnvCache { index: 1.0, base_index: 1.0, timestamp: chrono::Utc::now().timestamp(), ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { max_price: Some(110.0), ..stone() }];
+
+        let quote = quote_market(&state, vec![], &[]).await.unwrap();
+        assert_eq!(quote["items"]["stone"]["price"].as_f64(), Some(100.0));
+        assert_eq!(quote["items"]["stone"]["buyPrice"].as_f64(), Some(110.0));
+
+        let trade: TradeResponse = body_json(handle_buy(State(state.clone()), Json(row(2.0, true))).await.into_response()).await;
+        assert!(trade.success);
+        assert_eq!(trade.total_price, 220.0);
+        // 卖出价低于上限，不受影响
+        let trade: TradeResponse = body_json(handle_sell(State(state.clone()), Json(row(1.0, true))).await.into_response()).await;
+        assert!(trade.total_price < 100.0 && trade.total_price > 99.0);
+
+        // 参数接口与模拟接口同样体现上限
+        let params = body_json(get_market_params(State(state.clone())).await.into_response()).await;
+        assert_eq!(params["items"]["stone"]["maxPrice"].as_f64(), Some(110.0));
+        let req = SimulateSequenceRequest {
+            item: MarketItem { max_price: Some(110.0), ..stone() },
+            env_index: Some(1.0),
+            steps: vec![SimulationStep { action: TradeAction::Buy, amount: 2.0, elapsed_ms: 0 }],
+        };
+        let body = body_json(simulate_sequence(State(state.clone()), Json(req)).await.into_response()).await;
+        assert_eq!(body["points"][0]["totalPrice"].as_f64(), Some(220.0));
+    }
+
+    #[tokio::test]
+    async fn long_idle_item_recovers_faster_than_a_recently_traded_one() {
+        let config = AppConfig { idle_recovery_after_secs: 600.0, idle_recovery_max_boost: 4.0, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config.clone());
+        let now = chrono::Utc::now().timestamp_millis();
+        let hour_ago = now - 3_600_000;
+        // 两者都在一小时前卖出 100；ore 刚刚又有成交 (不计入历史，只刷新最近成交时间)
+        *state.market_cache.write() = vec![
+            MarketItem { last_trade_ms: hour_ago, ..stone() },
+            MarketItem { id: "ore".into(), last_trade_ms: now - 1_000, ..stone() },
+        ];
+        let sales = vec![SalesRecord { timestamp: hour_ago, amount: 100.0, ..Default::default() }];
+        let history = PlayerSalesHistory {
+            item_sales: FxHashMap::from_iter([("stone".to_string(), sales.clone()), ("ore".to_string(), sales)]),
+            ..Default::default()
+        };
+        state.player_histories.write().insert("p1".into(), history);
+
+        let history_n = |id: &'static str| {
+            let state = state.clone();
+            async move {
+                let body: serde_json::Value = body_json(get_item_neff(State(state), Path(id.into())).await.into_response()).await;
+                body["historyContribution"].as_f64().unwrap()
+            }
+        };
+        let (idle, busy) = (history_n("stone").await, history_n("ore").await);
+        // 空闲 3600 秒 / 阈值 600 秒 = 6 倍，被限制为 4 倍
+        let hours = 3600.0 / config.recovery_tau;
+        assert!((busy - 100.0 * (-config.recovery_delta * hours).exp()).abs() < 0.01, "{busy}");
+        assert!((idle - 100.0 * (-config.recovery_delta * hours * 4.0).exp()).abs() < 0.01, "{idle}");
+        assert!(idle < busy);
+
+        // 成交刷新最近成交时间
+        persist_transaction(state.clone(), TransactionRecord::new(now, 1.0, 10.0, 10.0, 1.0, "SELL".into(), PLAYER.into(), "Steve".into(), "stone".into()), false).await;
+        assert_eq!(state.market_cache.read()[0].last_trade_ms, now);
+    }
+
+    #[tokio::test]
+    async fn trade_ending_an_idle_period_does_not_undo_the_recovery() {
+        let config = AppConfig { idle_recovery_after_secs: 600.0, idle_recovery_max_boost: 4.0, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        let now = chrono::Utc::now().timestamp_millis();
+        let hour_ago = now - 3_600_000;
+        *state.market_cache.write() = vec![MarketItem { last_trade_ms: hour_ago, ..stone() }];
+        let sales = vec![SalesRecord { timestamp: hour_ago, amount: 100.0, ..Default::default() }];
+        let history = PlayerSalesHistory { item_sales: FxHashMap::from_iter([("stone".to_string(), sales)]), ..Default::default() };
+        state.player_histories.write().insert("p1".into(), history);
+
+        let history_n = || {
+            let state = state.clone();
+            async move {
+                let body: serde_json::Value = body_json(get_item_neff(State(state), Path("stone".into())).await.into_response()).await;
+                body["historyContribution"].as_f64().unwrap()
+            }
+        };
+        let before = history_n().await;
+
+        // 另一名玩家卖出 1 个结束空闲期：n 只增加这 1 个，不因加速倍数归 1 而跳回未加速的水平
+        let trade = TransactionRecord::new(now, 1.0, 10.0, 10.0, 1.0, "SELL".into(), PLAYER.into(), "Steve".into(), "stone".into());
+        persist_transaction(state.clone(), trade, false).await;
+        let after = history_n().await;
+        assert!(after <= before + 1.0 + 0.01, "{before} -> {after}");
+        assert!(after >= before + 1.0 - 0.01, "{before} -> {after}");
+        // 折算额度记在物品上，其他玩家的成交记录时间戳保持原样
+        assert_eq!(state.player_histories.read()["p1"].item_sales["stone"][0].timestamp, hour_ago);
+        let item = state.market_cache.read()[0].clone();
+        assert_eq!((item.idle_credit_ms, item.idle_credit_before_ms), (3 * 3_600_000, now));
+    }
+
+    #[tokio::test]
+    async fn market_prices_report_when_the_env_index_was_computed() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { env_cache_granularity_secs: 3600, ..Default::default() });
+        *state.market_cache.write() = vec![stone()];
+        let quote = quote_market(&state, vec![], &[]).await.unwrap();
+        let computed_at = quote["envComputedAt"].as_i64().unwrap();
+        assert!(computed_at <= quote["serverTime"].as_i64().unwrap());
+        assert_eq!(computed_at, state.env_cache.read().as_ref().unwrap().timestamp * 1000);
+
+        // 缓存窗口内复用同一条目，计算时刻不变
+        let again = quote_market(&state, vec![], &[]).await.unwrap();
+        assert_eq!(again["envComputedAt"].as_i64(), Some(computed_at));
+    }
+
+    #[tokio::test]
+    async fn noise_multiplier_scales_per_item_price_variance() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { env_cache_granularity_secs: 0, noise_std: 0.1, ..Default::default() });
+        *state.market_cache.write() = vec![
+            MarketItem { noise_multiplier: Some(0.5), ..stone() },
+            MarketItem { id: "gem".into(), noise_multiplier: Some(3.0), ..stone() },
+        ];
+
+        let mut samples: HashMap<&str, Vec<f64>> = HashMap::new();
+        for _ in 0..200 {
+            let quote = quote_market(&state, vec![], &[]).await.unwrap();
+            for id in ["stone", "gem"] {
+                samples.entry(id).or_default().push(quote["items"][id]["price"].as_f64().unwrap());
+            }
+        }
+        let variance = |xs: &[f64]| {
+            let mean = xs.iter().sum::<f64>() / xs.len() as f64;
+            xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / xs.len() as f64
+        };
+        let (staple, speculative) = (variance(&samples["stone"]), variance(&samples["gem"]));
+        // 方差按倍数平方缩放 (36 倍)，留足抽样误差
+        assert!(speculative > staple * 10.0, "staple {staple}, speculative {speculative}");
+    }
+
+    #[tokio::test]
+    async fn batch_buy_prices_rows_on_the_buy_curve() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig { buy_premium: 1.25, ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { n: 50.0, ..stone() }, MarketItem { id: "ore".into(), n: 5.0, ..stone() }];
+        let rows = vec![row(2.0, true), TradeRequest { item_id: "ore".into(), ..row(2.0, true) }];
+        let batch = |rows: Vec<TradeRequest>| BatchTradeRequest { player_id: PLAYER.into(), player_name: "Steve".into(), requests: rows };
+
+        let bought: BatchTradeResponse = body_json(handle_batch_buy(State(state.clone()), Json(batch(rows.clone()))).await.into_response()).await;
+        let sold: BatchTradeResponse = body_json(handle_batch_sell(State(state.clone()), Json(batch(rows.clone()))).await.into_response()).await;
+        assert_eq!((bought.succeeded, sold.succeeded), (2, 2));
+
+        // 与逐笔买入预览一致：各行按各自物品的持久化 n 定价 (结果顺序不保证)
+        let mut singles = Vec::new();
+        for row in rows {
+            let single: TradeResponse = body_json(handle_buy(State(state.clone()), Json(row)).await.into_response()).await;
+            singles.push(single.total_price);
+        }
+        let mut totals: Vec<f64> = bought.results.iter().map(|r| r.total_price).collect();
+        totals.sort_by(f64::total_cmp);
+        singles.sort_by(f64::total_cmp);
+        assert_eq!(totals, singles);
+        assert!(bought.total_value > sold.total_value);
+        assert!(handles.records.try_recv().is_err());
+    }
+}
@@ -1,14 +1,15 @@
 use crate::models::{
-    AppConfig, TradeRequest, TradeResponse, TransactionRecord, 
-    PlayerSalesHistory, EnvCache, Roundable 
+    AppConfig, TradeRequest, TradeResponse, TransactionRecord, MarketItem, Msg,
+    PlayerSalesHistory, EnvCache, Roundable, SubMinimumPolicy, TradeAction, RoundingPolicy 
 };
 use std::collections::HashMap;
 use chrono::{Utc, Local}; 
 use reqwest::StatusCode;
 use parking_lot::RwLock;
+use tokio::sync::Semaphore;
 
 // --- 子模块重新导出 ---
-pub use self::pricing::PricingEngine;
+pub use self::pricing::{IdleRecovery, PricingEngine};
 pub use self::environment::calculate_current_env_index;
 
 // =========================================================================
@@ -32,49 +33,138 @@ struct TradeContext<'a> {
     holidays: &'a HashMap<String, bool>,
     player_history: &'a PlayerSalesHistory,
     env_cache: &'a RwLock<Option<EnvCache>>,
-    // [新增] 接收外部传入的持久化 N 值
-    current_market_n: f64,
+    // 已同步物品的服务端状态：持久化 N 值、lambda (可能已被自动调参修改，优先于客户端携带的值)、分类
+    market_item: Option<&'a MarketItem>,
 }
 
 impl<'a> TradeContext<'a> {
-    async fn execute(self, is_buy: bool, http_client: &reqwest::Client) -> (TradeResponse, Option<TransactionRecord>) {
+    async fn execute(self, action: &TradeAction, http_client: &reqwest::Client, mojang_limiter: &Semaphore) -> (TradeResponse, Option<TransactionRecord>) {
         let now_ms = Utc::now().timestamp_millis();
 
-        // 1. 验证
-        if !validate_player(self.req, self.config.is_online_mode, http_client).await {
+        // 1. 验证 (预览默认跳过，成交必须校验)
+        let skip_auth = self.req.is_preview && self.config.skip_auth_on_preview;
+        if !skip_auth && !validate_player(self.req, self.config, http_client, mojang_limiter).await {
             let mut resp = empty_resp(1.0, 0.0);
             resp.success = false;
-            resp.message = "身份验证失败".into();
+            resp.message = self.config.log_lang.text(Msg::AuthFailed).into();
             return (resp, None);
         }
 
         // 2. 环境
-        let (env_idx, env_note) = self.resolve_env();
+        let (env_idx, env_note) = match self.resolve_env(now_ms) {
+            Ok(env) => env,
+            Err(message) => {
+                let mut resp = empty_resp(1.0, 0.0);
+                resp.message = message;
+                return (resp, None);
+            }
+        };
 
         // 3. 库存 (Effective N)
-        // 计算逻辑：历史衰减 + 持久化 N + 临时偏移 Iota
+        // 计算逻辑：历史衰减 + 持久化 N + 临时偏移 Iota + 开市溢价
         let n_eff = self.calculate_n_eff(now_ms);
 
-        // 4. 定价
-        let total_price = PricingEngine::calculate_price(
-            self.req.base_price, env_idx, n_eff, self.req.amount, 
-            self.req.decay_lambda, self.config.buy_premium, is_buy
-        );
+        // 4. 定价 (已同步物品以服务端 lambda 为准)
+        let lambda = self.market_item.map(|i| i.lambda).unwrap_or(self.req.decay_lambda);
+        let is_buy = action.is_buy();
+        // 已同步物品的限时基准价优先于客户端携带的 base_price
+        let base_price = self.market_item
+            .and_then(|i| i.active_base_price_override(now_ms))
+            .unwrap_or(self.req.base_price);
+        let mut total_price = PricingEngine::calculate_price(
+            base_price, env_idx, n_eff, self.req.amount, 
+            lambda, self.config.buy_premium,
+            self.config.fresh_mint_surcharge, is_buy, self.config.allow_negative_n
+        ) * self.config.global_price_multiplier;
+
+        // 4.0 自定义定价脚本 (在税前调整总额)
+        #[cfg(feature = "scripting")]
+        {
+            let ctx = crate::script::PricingContext {
+                item_id: &self.req.item_id, amount: self.req.amount, is_buy,
+                env_index: env_idx, n_eff, base_price,
+            };
+            total_price = crate::script::adjust_total(self.config, &ctx, total_price);
+        }
+
+        // 4.0.1 单价上限 (买入价在溢价之后封顶)
+        if let Some(item) = self.market_item {
+            total_price = item.cap_total(total_price, self.req.amount);
+        }
+
+        // 4.1 卖出税 (按税前总额判断是否免税)
+        let mut env_note = env_note;
+        let tax_rate = self.config.sell_tax_rate.clamp(0.0, 1.0);
+        if !is_buy && tax_rate > 0.0 {
+            if total_price < self.config.tax_exempt_below {
+                env_note.push_str("+TaxExempt");
+            } else {
+                total_price *= 1.0 - tax_rate;
+                env_note.push_str(&format!("+Tax({:.0}%)", tax_rate * 100.0));
+            }
+        }
+
+        // 5. 响应 (按舍入策略取整)
+        let mut response = build_resp(total_price, self.req.amount, env_idx, n_eff, self.config, is_buy);
+
+        // 5.1 最低成交额 (避免舍入到 0 后“成功但未结算”)
+        if response.total_price < self.config.min_payout {
+            match self.config.sub_minimum_policy {
+                SubMinimumPolicy::Reject => {
+                    response.success = false;
+                    response.message = self.config.log_lang.render(Msg::BelowMinPayout, &[&format!("{:.2}", self.config.min_payout)]);
+                    return (response, None);
+                }
+                SubMinimumPolicy::PayMinimum => {
+                    response = build_resp(self.config.min_payout, self.req.amount, env_idx, n_eff, self.config, is_buy);
+                }
+            }
+        }
 
-        // 5. 响应
-        let mut response = build_resp(total_price, self.req.amount, env_idx, n_eff);
         response.success = true;
-        response.message = format!("交易成功 ({})", env_note);
+        response.message = self.config.log_lang.render(Msg::TradeOk, &[&env_note]);
 
-        let record = self.create_record(&response, env_note, is_buy, now_ms);
+        // 5.2 换算结算币种 (曲线、税费与舍入都按定价币种进行)
+        if let Some((payout, currency)) = self.market_item.and_then(|i| i.payout(response.total_price)) {
+            response.payout = Some(payout);
+            response.payout_currency = Some(currency);
+        }
+
+        let record = self.create_record(&response, env_note, action, now_ms);
 
         (response, record)
     }
 
-    fn resolve_env(&self) -> (f64, String) {
-        match self.req.manual_env_index {
-            Some(m) if m > 0.0 && m.is_finite() => (m, "Manual".into()),
-            _ => calculate_current_env_index(self.config, self.holidays, self.env_cache),
+    /// 环境指数的优先级：有效且未过期的冻结令牌 > manual_env_index > 实时计算
+    /// 无效或过期的令牌被忽略 (回落到后两者)；strict_env_precedence 时令牌与手动指数不一致则拒绝
+    fn resolve_env(&self, now_ms: i64) -> Result<(f64, String), String> {
+        let manual = self.req.manual_env_index.filter(|m| *m > 0.0 && m.is_finite());
+        let category = self.market_item.and_then(|i| i.category.as_deref());
+
+        if let Some(token) = self.req.env_token.as_deref() {
+            match env_token::verify(token, &self.config.env_token_secret, now_ms) {
+                Ok(frozen) => {
+                    if let Some(m) = manual.filter(|m| (m - frozen).abs() > 1e-9) {
+                        if self.config.strict_env_precedence {
+                            return Err(format!("环境令牌 ({}) 与 manual_env_index ({}) 冲突", frozen, m));
+                        }
+                    }
+                    return Ok((environment::apply_category_delta(frozen, self.config, category), "Frozen".into()));
+                }
+                Err(reason) => tracing::debug!("🧊 环境令牌{}，忽略", reason),
+            }
+        }
+
+        match manual {
+            Some(m) => Ok((m, "Manual".into())),
+            None => {
+                let (idx, base, note) = environment::current_env_components(self.config, self.holidays, self.env_cache);
+                let env = match self.market_item {
+                    Some(item) => environment::item_env(idx, base, self.config, item),
+                    None => environment::apply_category_delta(idx, self.config, category),
+                };
+                Ok((env, note))
+            }
         }
     }
 
@@ -86,41 +176,59 @@ impl<'a> TradeContext<'a> {
 
         // [核心逻辑]
         // 1. 计算近期交易的历史衰减值
-        let n_history = PricingEngine::calculate_history_decay(history, self.config, now_ms);
+        let idle = self.market_item.map_or(IdleRecovery::NONE, |i| IdleRecovery::of(i, self.config, now_ms));
+        let n_history = PricingEngine::history_decay_boosted(history, self.config, now_ms, idle);
         
-        // 2. 加上持久化的基础值 (current_market_n) 和 手动偏移 (iota)
-        (n_history + self.current_market_n + iota).max(0.0)
+        // 2. 加上持久化的基础值 (market n)、手动偏移 (iota) 和随时间衰减的开市溢价，并约束到物品的 [floor_n, max_n]
+        let market_n = self.market_item.map_or(0.0, |i| i.n);
+        let launch = PricingEngine::launch_iota(self.market_item.and_then(|i| i.launch_iota), self.config, now_ms);
+        let n = n_history + market_n + iota + launch;
+        self.market_item.map_or(self.config.floor_effective_n(n), |i| i.clamp_n(n, self.config))
     }
 
-    fn create_record(&self, resp: &TradeResponse, note: String, is_buy: bool, ts: i64) -> Option<TransactionRecord> {
+    fn create_record(&self, resp: &TradeResponse, note: String, action: &TradeAction, ts: i64) -> Option<TransactionRecord> {
         if self.req.is_preview || resp.total_price <= 0.0 { return None; }
         
-        Some(TransactionRecord::new(
+        // 字符串 note 保留向后兼容，metadata 供机器解析 (未开启 trade-metadata 时留空)
+        #[cfg(feature = "trade-metadata")]
+        let metadata = HashMap::from([
+            ("auth".to_string(), if self.config.is_online_mode { "OnlineAuth" } else { "OfflineAuth" }.to_string()),
+            ("env".to_string(), note.clone()),
+            ("effective_n".to_string(), resp.effective_n.to_string()),
+        ]);
+
+        // 与报价使用同一个环境指数，不重新读取缓存
+        let record = TransactionRecord::new(
             ts, self.req.amount, resp.total_price, resp.unit_price_avg,
-            resp.env_index, if is_buy { "BUY".into() } else { "SELL".into() },
+            resp.quote_env_index, action.label().to_string(),
             self.req.player_id.clone(), self.req.player_name.clone(), self.req.item_id.clone()
-        ).with_note(note))
+        ).with_note(note);
+        let record = TransactionRecord { payout: resp.payout, payout_currency: resp.payout_currency.clone(), ..record };
+        #[cfg(feature = "trade-metadata")]
+        let record = TransactionRecord { metadata, ..record };
+        Some(record)
     }
 }
 
-// [修改] 函数签名增加 current_market_n
+/// `market_item` 为已同步物品的服务端状态 (未同步的物品为 None，按客户端参数定价)
+/// `mojang_limiter` 为全局共享的 Mojang 请求并发许可
+#[allow(clippy::too_many_arguments)]
 pub async fn execute_trade_logic(
     req: &TradeRequest, config: &AppConfig, holidays: &HashMap<String, bool>,
-    player_history: &PlayerSalesHistory, is_buy: bool,
+    player_history: &PlayerSalesHistory, action: &TradeAction,
     env_cache: &RwLock<Option<EnvCache>>, http_client: &reqwest::Client,
-    current_market_n: f64, // 新增参数
+    mojang_limiter: &Semaphore, market_item: Option<&MarketItem>,
 ) -> (TradeResponse, Option<TransactionRecord>) {
     if req.amount.abs() < constants::EPSILON_AMT || !req.amount.is_finite() {
         let mut resp = empty_resp(1.0, 0.0);
-        resp.message = "交易量无效".into();
+        resp.message = config.log_lang.text(Msg::InvalidAmount).into();
         return (resp, None);
     }
 
     TradeContext { 
-        req, config, holidays, player_history, env_cache, 
-        current_market_n 
+        req, config, holidays, player_history, env_cache, market_item
     }
-    .execute(is_buy, http_client).await
+    .execute(action, http_client, mojang_limiter).await
 }
 
 // =========================================================================
@@ -130,20 +238,97 @@ pub async fn execute_trade_logic(
 pub mod pricing {
     use super::constants;
     // [修复] 将 SalesRecord 移入此处引用，解决 unused import 警告
-    use crate::models::{AppConfig, SalesRecord, Roundable};
+    use crate::models::{AppConfig, MarketItem, SalesRecord, RecoveryModel, SimulationPoint, SimulationStep};
+    use parking_lot::RwLock;
+    use std::sync::Arc;
 
     pub struct PricingEngine;
 
+    /// 某物品的空闲加速恢复：空闲期间按倍数多计恢复时长，外加结束空闲期的成交已折算的额度
+    #[derive(Debug, Clone, Copy)]
+    pub struct IdleRecovery {
+        last_trade_ms: i64,
+        boost: f64,
+        credit_ms: i64,
+        credit_before_ms: i64,
+    }
+
+    impl IdleRecovery {
+        /// 不加速
+        pub const NONE: Self = Self { last_trade_ms: 0, boost: 1.0, credit_ms: 0, credit_before_ms: 0 };
+
+        pub fn of(item: &MarketItem, config: &AppConfig, now_ms: i64) -> Self {
+            Self {
+                last_trade_ms: item.last_trade_ms,
+                boost: PricingEngine::idle_boost(item.last_trade_ms, config, now_ms),
+                credit_ms: item.idle_credit_ms,
+                credit_before_ms: item.idle_credit_before_ms,
+            }
+        }
+
+        /// 时间戳为 `timestamp` 的记录在实际间隔之外额外计入的恢复秒数
+        pub fn extra_secs(&self, timestamp: i64, now_ms: i64) -> f64 {
+            let mut extra = 0.0;
+            // 当前空闲期 (最近成交之后) 按 boost 倍速恢复
+            if self.boost > 1.0 && timestamp <= self.last_trade_ms {
+                extra += (self.boost - 1.0) * (now_ms - self.last_trade_ms).max(0) as f64 / 1000.0;
+            }
+            if timestamp < self.credit_before_ms {
+                extra += self.credit_ms as f64 / 1000.0;
+            }
+            extra
+        }
+    }
+
+    /// e^(-x) 的等距线性插值表，覆盖 x ∈ [0, EXP_TABLE_MAX_X]
+    /// 线性插值误差上界为 h²/8 · max|f''| = h²/8，据此由容差反推步长
+    pub struct ExpTable {
+        tolerance: f64,
+        step: f64,
+        values: Vec<f64>,
+    }
+
+    const EXP_TABLE_MAX_X: f64 = 20.0;
+    // 建表容差的范围：过小时表过大 (约 25 万项封顶)，过大时插值失去意义
+    pub const EXP_TABLE_TOLERANCE_RANGE: std::ops::RangeInclusive<f64> = 1e-9..=0.1;
+    // 最新构建的表 (按限制后的容差)，容差变化时重建
+    static EXP_TABLE: RwLock<Option<Arc<ExpTable>>> = parking_lot::const_rwlock(None);
+
+    impl ExpTable {
+        /// `tolerance` 会被限制到 EXP_TABLE_TOLERANCE_RANGE：高于上限时按上限建表 (误差仍在配置值内)，
+        /// 低于下限时按下限建表，此时误差上界是 1e-9 而非配置值
+        pub fn new(tolerance: f64) -> Self {
+            let tolerance = tolerance.clamp(*EXP_TABLE_TOLERANCE_RANGE.start(), *EXP_TABLE_TOLERANCE_RANGE.end());
+            let step = (8.0 * tolerance).sqrt();
+            let len = (EXP_TABLE_MAX_X / step).ceil() as usize + 1;
+            let values = (0..=len).map(|i| (-(i as f64) * step).exp()).collect();
+            Self { tolerance, step, values }
+        }
+
+        /// 超出表范围时返回 None
+        pub fn eval(&self, x: f64) -> Option<f64> {
+            if !(0.0..=EXP_TABLE_MAX_X).contains(&x) { return None; }
+            let pos = x / self.step;
+            let i = pos.floor() as usize;
+            let (lo, hi) = (self.values[i], *self.values.get(i + 1)?);
+            Some(lo + (hi - lo) * (pos - i as f64))
+        }
+    }
+
     impl PricingEngine {
-        pub fn calculate_price(base: f64, env: f64, n: f64, amt: f64, lambda: f64, premium: f64, is_buy: bool) -> f64 {
-            if is_buy {
-                Self::buy_logic(base * premium, env, n, amt, lambda)
+        #[allow(clippy::too_many_arguments)]
+        pub fn calculate_price(base: f64, env: f64, n: f64, amt: f64, lambda: f64, premium: f64,
+                               surcharge: f64, is_buy: bool, allow_negative_n: bool) -> f64 {
+            if is_buy && allow_negative_n {
+                Self::buy_logic_negative(base * premium, env, n, amt, lambda, surcharge)
+            } else if is_buy {
+                Self::buy_logic(base * premium, env, n, amt, lambda, surcharge)
             } else {
                 Self::integral_revenue(base, env, n, amt, lambda)
             }
         }
 
-        fn buy_logic(base: f64, env: f64, n_eff: f64, amt: f64, lambda: f64) -> f64 {
+        fn buy_logic(base: f64, env: f64, n_eff: f64, amt: f64, lambda: f64, surcharge: f64) -> f64 {
             let n_start = (n_eff - amt).max(0.0);
             let discount_amt = (n_eff - n_start).max(0.0);
             
@@ -152,40 +337,192 @@ pub mod pricing {
                 let p_discount = if discount_amt > constants::EPSILON_AMT {
                     Self::integral_revenue(base, env, n_start, discount_amt, lambda)
                 } else { 0.0 };
-                p_discount + (premium_amt * base * env)
+                // 超出库存的部分按满价并叠加稀缺附加费
+                p_discount + (premium_amt * base * env * (1.0 + surcharge.max(0.0)))
             } else {
                 Self::integral_revenue(base, env, n_start, amt, lambda)
             }
         }
 
+        /// 允许负库存时的买入：整段沿曲线从 n 积分到 n - amt，与卖回时的积分区间一致，
+        /// 落在 0 以下的部分再按其曲线价叠加稀缺附加费，保证买入后立即卖回不会获利
+        fn buy_logic_negative(base: f64, env: f64, n_eff: f64, amt: f64, lambda: f64, surcharge: f64) -> f64 {
+            let n_start = n_eff - amt;
+            let curve = Self::integral_revenue(base, env, n_start, amt, lambda);
+            let short_amt = (-n_start).clamp(0.0, amt);
+            let short = if short_amt > constants::EPSILON_AMT {
+                Self::integral_revenue(base, env, n_start, short_amt, lambda)
+            } else { 0.0 };
+            curve + short * surcharge.max(0.0)
+        }
+
         pub fn integral_revenue(base: f64, env: f64, n1: f64, amt: f64, lambda: f64) -> f64 {
             let p_max = base * env;
             let l = lambda.abs();
 
             if l < constants::LAMBDA_MIN {
-                return (p_max * amt).round_2();
+                return p_max * amt;
             }
 
             let n2 = n1 + amt;
             let revenue = (p_max / l) * ((-l * n1).exp() - (-l * n2).exp());
-            revenue.max(0.0).round_2()
+            // 舍入统一在响应构建阶段按 rounding_policy 处理
+            revenue.max(0.0)
         }
 
         // [拆分] 纯历史衰减计算
         pub fn calculate_history_decay(history: &[SalesRecord], config: &AppConfig, now_ms: i64) -> f64 {
+            Self::history_decay_boosted(history, config, now_ms, IdleRecovery::NONE)
+        }
+
+        /// 计入空闲加速恢复的历史衰减 (空闲期间等价于放大 recovery_delta / 缩短 recovery_window_secs)
+        /// 成交记录的时间戳保持原样，加速部分只体现为额外的恢复时长
+        pub fn history_decay_boosted(history: &[SalesRecord], config: &AppConfig, now_ms: i64, idle: IdleRecovery) -> f64 {
             history.iter().map(|r| {
-                let dt = ((now_ms - r.timestamp) as f64 / 1000.0).max(0.0);
-                let decay = if config.recovery_delta > 0.0 {
-                    (-config.recovery_delta * (dt / config.recovery_tau)).exp()
-                } else { 1.0 };
-                r.amount * decay
+                let dt = ((now_ms - r.timestamp) as f64 / 1000.0).max(0.0) + idle.extra_secs(r.timestamp, now_ms);
+                r.amount * Self::decay_factor(dt, config)
             }).sum()
         }
 
+        /// 成交结束空闲期时，把空闲期间多恢复的时长记入物品的折算额度 (与 last_trade_ms 一同更新)，
+        /// 之后空闲倍数归 1 时此前的记录仍保留已获得的恢复，n 不会回跳
+        /// 多段空闲期的额度累加并对本次成交之前的全部记录生效 (偏向多恢复)
+        pub fn note_trade(item: &mut MarketItem, config: &AppConfig, at_ms: i64) {
+            let boost = Self::idle_boost(item.last_trade_ms, config, at_ms);
+            if boost > 1.0 {
+                let idle_ms = (at_ms - item.last_trade_ms) as f64;
+                item.idle_credit_ms = item.idle_credit_ms.saturating_add(((boost - 1.0) * idle_ms) as i64);
+                item.idle_credit_before_ms = at_ms;
+            }
+            item.last_trade_ms = item.last_trade_ms.max(at_ms);
+        }
+
+        /// 空闲加速倍数：空闲时长 / 阈值，限制在 [1, idle_recovery_max_boost]；未开启或尚无成交时为 1
+        pub fn idle_boost(last_trade_ms: i64, config: &AppConfig, now_ms: i64) -> f64 {
+            let after = config.idle_recovery_after_secs;
+            if !(after > 0.0) || last_trade_ms <= 0 { return 1.0; }
+            let idle_secs = (now_ms - last_trade_ms) as f64 / 1000.0;
+            (idle_secs / after).clamp(1.0, config.idle_recovery_max_boost.max(1.0))
+        }
+
+        /// e^(-x)：启用查表时按插值近似，否则 (或超出表范围、容差非正/NaN 时) 精确计算
+        pub fn exp_neg(x: f64, config: &AppConfig) -> f64 {
+            if config.exp_table_enabled && config.exp_table_tolerance > 0.0 {
+                if let Some(v) = Self::exp_table(config.exp_table_tolerance).eval(x) { return v; }
+            }
+            (-x).exp()
+        }
+
+        fn exp_table(tolerance: f64) -> Arc<ExpTable> {
+            // 按限制后的容差比较，范围外的配置值不会每次都触发重建
+            let tolerance = tolerance.clamp(*EXP_TABLE_TOLERANCE_RANGE.start(), *EXP_TABLE_TOLERANCE_RANGE.end());
+            if let Some(table) = EXP_TABLE.read().as_ref().filter(|t| t.tolerance == tolerance) {
+                return table.clone();
+            }
+            let table = Arc::new(ExpTable::new(tolerance));
+            *EXP_TABLE.write() = Some(table.clone());
+            table
+        }
+
+        /// 单条记录经过 `dt` 秒后的剩余权重
+        pub fn decay_factor(dt: f64, config: &AppConfig) -> f64 {
+            match config.recovery_model {
+                RecoveryModel::Exponential if config.recovery_delta > 0.0 => {
+                    (-config.recovery_delta * (dt / config.recovery_tau)).exp()
+                }
+                RecoveryModel::Exponential => 1.0,
+                RecoveryModel::Linear if config.recovery_window_secs > 0.0 => {
+                    (1.0 - dt / config.recovery_window_secs).max(0.0)
+                }
+                RecoveryModel::Linear => 0.0,
+            }
+        }
+
+        /// 指数恢复模型下，有效库存降到使价格回升至 `target_ratio` 倍基准价所需的秒数
+        /// 只有历史部分随时间衰减：H·e^(-δt/τ) + S ≤ -ln(target)/λ。
+        /// 已达到目标返回 Some(0)，静态部分或库存下限本身已超出 (永远无法恢复) 返回 None
+        pub fn recovery_secs(history_n: f64, static_n: f64, item: &MarketItem, target_ratio: f64, config: &AppConfig) -> Option<f64> {
+            let lambda = item.lambda.abs();
+            if lambda <= 0.0 { return Some(0.0); }
+            let target_n = -target_ratio.ln() / lambda;
+            if item.floor_n > target_n { return None; }
+            // 库存上限只截断高于它的部分，不影响降到目标以下所需的时间
+            if item.clamp_n(history_n + static_n, config) <= target_n { return Some(0.0); }
+            let headroom = target_n - static_n;
+            if headroom <= 0.0 || config.recovery_delta <= 0.0 { return None; }
+            Some(config.recovery_tau / config.recovery_delta * (history_n / headroom).ln())
+        }
+
+        /// 恢复过快 (历史成交几乎立即失去影响) 时返回告警说明
+        pub fn recovery_warning(config: &AppConfig) -> Option<String> {
+            let (label, secs) = match config.recovery_model {
+                RecoveryModel::Exponential if config.recovery_delta > 0.0 => {
+                    ("半衰期", config.recovery_tau * std::f64::consts::LN_2 / config.recovery_delta)
+                }
+                RecoveryModel::Exponential => return None,
+                RecoveryModel::Linear => ("恢复窗口", config.recovery_window_secs),
+            };
+            // NaN 同样视为配置错误
+            (!(secs >= config.min_recovery_secs)).then(|| format!(
+                "历史衰减{}仅 {:.3} 秒 (recovery_tau={}, recovery_delta={})，低于 {} 秒，成交对价格几乎没有影响",
+                label, secs, config.recovery_tau, config.recovery_delta, config.min_recovery_secs
+            ))
+        }
+
+        /// 启用查表但容差不在 EXP_TABLE_TOLERANCE_RANGE 内 (含 NaN) 时给出告警文本
+        pub fn exp_table_warning(config: &AppConfig) -> Option<String> {
+            let tolerance = config.exp_table_tolerance;
+            (config.exp_table_enabled && !EXP_TABLE_TOLERANCE_RANGE.contains(&tolerance)).then(|| format!(
+                "exp_table_tolerance={} 不在 [{:e}, {}] 内，{}",
+                tolerance, EXP_TABLE_TOLERANCE_RANGE.start(), EXP_TABLE_TOLERANCE_RANGE.end(),
+                if tolerance > 0.0 { "将按范围边界建表" } else { "查表不生效，改为精确计算" }
+            ))
+        }
+
+        /// 开市溢价 iota：初始值每经过一个半衰期减半，开市前按初始值计
+        pub fn launch_iota(item_iota: Option<f64>, config: &AppConfig, now_ms: i64) -> f64 {
+            let initial = item_iota.unwrap_or(config.launch_iota);
+            let half_life = config.launch_iota_half_life_secs;
+            if config.launch_at_ms <= 0 || !initial.is_finite() || !(half_life > 0.0) { return 0.0; }
+            let elapsed = ((now_ms - config.launch_at_ms) as f64 / 1000.0).max(0.0);
+            initial * 0.5f64.powf(elapsed / half_life)
+        }
+
+        /// 在临时历史上依次回放假设的成交，返回每一步的成交额与有效库存
+        /// 与实时定价相同的曲线、衰减与库存约束，但不含开市溢价 (依赖真实时钟)；不读写任何实时状态
+        pub fn simulate_sequence(item: &MarketItem, env: f64, steps: &[SimulationStep], config: &AppConfig) -> Vec<SimulationPoint> {
+            let static_n = item.n + item.iota + config.global_iota;
+            let n_at = |history: &[SalesRecord], now| item.clamp_n(Self::calculate_history_decay(history, config, now) + static_n, config);
+
+            let mut history: Vec<SalesRecord> = Vec::with_capacity(steps.len());
+            let mut now = 0i64;
+            steps.iter().map(|step| {
+                now += step.elapsed_ms;
+                let is_buy = step.action.is_buy();
+                let n_before = n_at(&history, now);
+                let total = item.cap_total(Self::calculate_price(
+                    item.base_price, env, n_before, step.amount, item.lambda.abs(),
+                    config.buy_premium, config.fresh_mint_surcharge, is_buy, config.allow_negative_n,
+                ) * config.global_price_multiplier, step.amount);
+                history.push(SalesRecord {
+                    timestamp: now,
+                    amount: if is_buy { -step.amount } else { step.amount },
+                    ..Default::default()
+                });
+                SimulationPoint {
+                    time_ms: now,
+                    total_price: total,
+                    unit_price: total / step.amount,
+                    n_before,
+                    n_after: n_at(&history, now),
+                }
+            }).collect()
+        }
+
         // 保持兼容性的 helper，如果还需要的话
         pub fn calculate_effective_n(history: &[SalesRecord], iota: f64, config: &AppConfig, now_ms: i64) -> f64 {
              let n_history = Self::calculate_history_decay(history, config, now_ms);
-             (n_history + iota).max(0.0)
+             config.floor_effective_n(n_history + iota)
         }
     }
 }
@@ -196,9 +533,9 @@ pub mod pricing {
 
 pub mod environment {
     use super::constants;
-    use crate::models::{AppConfig, EnvCache};
-    use chrono::{Datelike, Local};
-    use std::collections::HashMap;
+    use crate::models::{AppConfig, EnvCache, MarketItem};
+    use chrono::{DateTime, Datelike, Local, TimeZone};
+    use std::collections::{HashMap, VecDeque};
     use parking_lot::RwLock;
     
     // [修复] 将 rand 引入移到 mod 内部作用域，彻底解决 E0425 错误
@@ -207,57 +544,155 @@ pub mod environment {
 
     pub fn calculate_current_env_index(config: &AppConfig, holidays: &HashMap<String, bool>, 
                                        cache: &RwLock<Option<EnvCache>>) -> (f64, String) {
-        let now = Local::now();
+        let (index, _, note) = current_env_components(config, holidays, cache);
+        (index, note)
+    }
+
+    /// 同上，额外返回叠加噪声之前的确定性指数：(指数, 确定性指数, 标签)
+    pub fn current_env_components(config: &AppConfig, holidays: &HashMap<String, bool>,
+                                  cache: &RwLock<Option<EnvCache>>) -> (f64, f64, String) {
+        env_components_at(Local::now(), config, holidays, cache)
+    }
+
+    /// 按给定时刻计算 (测试注入时钟用)
+    pub(crate) fn env_components_at(now: DateTime<Local>, config: &AppConfig, holidays: &HashMap<String, bool>,
+                                    cache: &RwLock<Option<EnvCache>>) -> (f64, f64, String) {
         let ts = now.timestamp();
+        let granularity = config.env_cache_granularity_secs;
 
         if let Some(c) = cache.read().as_ref() {
-            if c.timestamp == ts { return (c.index, c.note.clone()); }
+            if cache_is_fresh(c.timestamp, now, granularity) { return (c.index, c.base_index, c.note.clone()); }
         }
 
         let mut wg = cache.write();
         if let Some(c) = wg.as_ref() {
-            if c.timestamp == ts { return (c.index, c.note.clone()); }
+            if cache_is_fresh(c.timestamp, now, granularity) { return (c.index, c.base_index, c.note.clone()); }
         }
 
-        let (idx, note) = perform_calc(now, config, holidays);
+        let prev_noise = wg.as_ref().map(|c| c.last_noise).unwrap_or(0.0);
+        let (idx, base, note, noise) = perform_calc(now, config, holidays, prev_noise);
         *wg = Some(EnvCache { 
             index: idx, 
             note: note.clone(), 
             timestamp: ts, 
-            last_update: ts 
+            last_update: ts,
+            last_noise: noise,
+            base_index: base,
         });
-        (idx, note)
+        (idx, base, note)
     }
 
-    fn perform_calc(now: chrono::DateTime<Local>, config: &AppConfig, hols: &HashMap<String, bool>) -> (f64, String) {
+    /// 返回 (指数, 确定性指数, 标签, 噪声)，两个指数各自钳制到下限
+    fn perform_calc(now: DateTime<Local>, config: &AppConfig, hols: &HashMap<String, bool>,
+                    prev_noise: f64) -> (f64, f64, String, f64) {
         let mut eps = config.base_env_index;
         let mut tags = Vec::new();
         let ymd = now.format("%Y-%m-%d").to_string();
         let md = now.format("%m-%d").to_string();
 
-        if hols.get(&ymd).copied().unwrap_or(false) {
-            eps -= config.public_holiday_factor;
-            tags.push("Holiday");
+        // 因子按“扣减”语义配置：正数压低指数，负数 (节日繁荣) 抬高指数；标签中记录带符号的实际贡献
+        let mut apply = |tag: &str, factor: f64| {
+            let contribution = if factor.is_finite() { -factor } else { 0.0 };
+            eps += contribution;
+            tags.push(format!("{}({:+.2})", tag, contribution));
+        };
+
+        let is_holiday = hols.get(&ymd).copied().unwrap_or(false);
+        if is_holiday {
+            apply("Holiday", config.public_holiday_factor);
         }
 
         if is_range(&md, &config.winter_start, &config.winter_end) {
-            eps -= config.holiday_factor; tags.push("Winter");
+            apply("Winter", config.holiday_factor);
         } else if is_range(&md, &config.summer_start, &config.summer_end) {
-            eps -= config.holiday_factor; tags.push("Summer");
+            apply("Summer", config.holiday_factor);
+        }
+
+        if now.weekday().number_from_monday() >= 6 && !is_holiday {
+            apply("Weekend", config.weekend_factor);
         }
 
-        if now.weekday().number_from_monday() >= 6 && !hols.get(&ymd).copied().unwrap_or(false) {
-            eps -= config.weekend_factor; tags.push("Weekend");
+        // 指定日期按增量语义配置 (与上面的扣减因子相反)，标签为日期本身
+        if let Some(&delta) = config.special_dates.get(&ymd) {
+            apply(&ymd, -delta);
         }
 
         // [修复] 现在这里的 thread_rng 能够正确被编译器找到了
         let mut r = thread_rng(); 
-        let noise = Normal::new(0.0, config.noise_std.max(0.0001))
+        let shock = Normal::new(0.0, config.noise_std.max(0.0001))
             .unwrap_or_else(|_| Normal::new(0.0, 1.0).unwrap())
             .sample(&mut r);
 
+        // AR(1): noise_t = ρ·noise_{t-1} + sqrt(1-ρ²)·ε，保持方差不变
+        let rho = config.noise_persistence.clamp(0.0, 0.999);
+        let noise = rho * prev_noise + (1.0 - rho * rho).sqrt() * shock;
+
         let note = if tags.is_empty() { "Normal".into() } else { tags.join("+") };
-        ((eps + noise).max(constants::MIN_ENV_INDEX), note)
+        ((eps + noise).max(constants::MIN_ENV_INDEX), eps.max(constants::MIN_ENV_INDEX), note, noise)
+    }
+
+    /// 环境指数的时间序列 (秒级时间戳, 指数)，按条数与最大时长双重保留
+    #[derive(Default)]
+    pub struct EnvHistory {
+        samples: VecDeque<(i64, f64)>,
+    }
+
+    impl EnvHistory {
+        /// 追加样本后先按 env_history_max_len 裁剪，再丢弃早于 env_history_max_age_secs 的样本
+        /// 按时长保留与采样频率无关；`ts` 由调用方给出 (测试可用任意时钟)
+        pub fn push(&mut self, ts: i64, index: f64, config: &AppConfig) {
+            self.samples.push_back((ts, index));
+            while self.samples.len() > config.env_history_max_len.max(1) {
+                self.samples.pop_front();
+            }
+            if config.env_history_max_age_secs > 0 {
+                let cutoff = ts - config.env_history_max_age_secs;
+                while self.samples.front().is_some_and(|&(t, _)| t < cutoff) {
+                    self.samples.pop_front();
+                }
+            }
+        }
+
+        pub fn samples(&self) -> &VecDeque<(i64, f64)> {
+            &self.samples
+        }
+    }
+
+    /// 两个秒级时间戳是否落在同一缓存窗口 (粒度 <= 0 时永不命中)
+    fn same_window(cached: i64, now: i64, granularity: i64) -> bool {
+        granularity > 0 && cached.div_euclid(granularity) == now.div_euclid(granularity)
+    }
+
+    /// 窗口按 UTC 纪元对齐，与本地午夜无关；跨过本地日期时节假日、周末与季节因子都可能变化，必须重算
+    fn cache_is_fresh(cached: i64, now: DateTime<Local>, granularity: i64) -> bool {
+        same_window(cached, now.timestamp(), granularity)
+            && Local.timestamp_opt(cached, 0).single().is_some_and(|c| c.date_naive() == now.date_naive())
+    }
+
+    /// 环境指数是否已处于下限 (被钳制到 MIN_ENV_INDEX)
+    pub fn is_floored(env: f64) -> bool {
+        env <= constants::MIN_ENV_INDEX
+    }
+
+    /// 按物品的噪声倍数缩放 `env` 中相对确定性指数 `base` 的噪声分量
+    pub fn apply_noise_multiplier(env: f64, base: f64, multiplier: Option<f64>) -> f64 {
+        match multiplier {
+            Some(m) if m.is_finite() && m >= 0.0 && m != 1.0 => (base + (env - base) * m).max(constants::MIN_ENV_INDEX),
+            _ => env,
+        }
+    }
+
+    /// 某物品定价使用的环境指数：先缩放噪声，再叠加分类增量
+    pub fn item_env(env: f64, base: f64, config: &AppConfig, item: &MarketItem) -> f64 {
+        apply_category_delta(apply_noise_multiplier(env, base, item.noise_multiplier), config, item.category.as_deref())
+    }
+
+    /// 叠加物品分类的环境增量 (未分类或未配置的分类不变)
+    pub fn apply_category_delta(env: f64, config: &AppConfig, category: Option<&str>) -> f64 {
+        match category.and_then(|c| config.category_env_deltas.get(c)) {
+            Some(delta) if delta.is_finite() => (env + delta).max(constants::MIN_ENV_INDEX),
+            _ => env,
+        }
     }
 
     fn is_range(curr: &str, s: &str, e: &str) -> bool {
@@ -267,32 +702,163 @@ pub mod environment {
 }
 
 // =========================================================================
-// 5. 辅助工具
+// 5. Lambda 自动调参
+// =========================================================================
+
+pub mod tuning {
+    use crate::models::{AppConfig, PlayerSalesHistory};
+    use std::collections::HashMap;
+
+    /// 统计某物品自 `since_ms` 起的实际成交量 (买卖双向取绝对值)
+    pub fn realized_volume(histories: &HashMap<String, PlayerSalesHistory>, item_id: &str, since_ms: i64) -> f64 {
+        histories.values()
+            .filter_map(|h| h.item_sales.get(item_id))
+            .flatten()
+            .filter(|r| r.timestamp >= since_ms)
+            .map(|r| r.amount.abs())
+            .sum()
+    }
+
+    /// 比例控制：成交量超过目标则调高 lambda (价格衰减更快)，反之调低，结果限制在 [lambda_min, lambda_max]
+    pub fn adjust_lambda(lambda: f64, volume: f64, target: f64, config: &AppConfig) -> f64 {
+        if target <= 0.0 || !target.is_finite() { return lambda; }
+        let error = (volume - target) / target;
+        let step = 1.0 + config.lambda_autotune_gain * error.clamp(-1.0, 1.0);
+        let hi = config.lambda_max.max(config.lambda_min);
+        (lambda.abs() * step).clamp(config.lambda_min, hi)
+    }
+}
+
+// =========================================================================
+// 6. 环境冻结令牌
+// =========================================================================
+
+/// 令牌格式 `指数:到期毫秒:HMAC-SHA256(hex)`，签名覆盖前两段
+pub mod env_token {
+    use aws_lc_rs::hmac;
+
+    fn payload(index: f64, expires_at: i64) -> String {
+        format!("{}:{}", index, expires_at)
+    }
+
+    pub fn issue(index: f64, expires_at: i64, secret: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let data = payload(index, expires_at);
+        let tag: String = hmac::sign(&key, data.as_bytes()).as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}:{}", data, tag)
+    }
+
+    /// 返回令牌中冻结的环境指数；签名无效或已过期时返回原因
+    pub fn verify(token: &str, secret: &str, now_ms: i64) -> Result<f64, &'static str> {
+        if secret.is_empty() { return Err("未启用"); }
+        let mut parts = token.splitn(3, ':');
+        let (Some(index), Some(expires_at), Some(tag)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("格式错误");
+        };
+        let (Ok(index), Ok(expires_at)) = (index.parse::<f64>(), expires_at.parse::<i64>()) else {
+            return Err("格式错误");
+        };
+        let tag: Option<Vec<u8>> = (tag.len() % 2 == 0)
+            .then(|| (0..tag.len()).step_by(2).map(|i| u8::from_str_radix(tag.get(i..i + 2)?, 16).ok()).collect())
+            .flatten();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        match tag {
+            Some(tag) if hmac::verify(&key, payload(index, expires_at).as_bytes(), &tag).is_ok() => {}
+            _ => return Err("签名无效"),
+        }
+        if now_ms >= expires_at { return Err("已过期"); }
+        if !(index > 0.0 && index.is_finite()) { return Err("指数无效"); }
+        Ok(index)
+    }
+}
+
+// =========================================================================
+// 7. 辅助工具
 // =========================================================================
 
-fn build_resp(total: f64, amt: f64, env: f64, n_eff: f64) -> TradeResponse {
-    let t = total.abs();
+fn build_resp(total: f64, amt: f64, env: f64, n_eff: f64, config: &AppConfig, is_buy: bool) -> TradeResponse {
+    let t = round_price(total.abs(), config, is_buy);
     TradeResponse {
         success: true,
         message: String::new(),
-        final_price: t.round_2(),
-        total_price: t.round_2(),
+        final_price: t,
+        total_price: t,
         unit_price_avg: if amt.abs() > constants::EPSILON_AMT { (t / amt).round_2() } else { 0.0 },
         env_index: (env * 1000.0).round() / 1000.0,
         effective_n: n_eff.round_2(),
+        trade_id: None,
+        quote_env_index: env,
+        env_floored: environment::is_floored(env),
+        // 由 API 层按当前负载填写
+        server_load: 0.0,
+        payout: None,
+        payout_currency: None,
+    }
+}
+
+/// 按配置的小数位与舍入策略取整 (HouseFavored: 卖出向下、买入向上)
+fn round_price(value: f64, config: &AppConfig, is_buy: bool) -> f64 {
+    let scale = 10f64.powi(config.price_decimals.min(8) as i32);
+    let scaled = value * scale;
+    let rounded = match config.rounding_policy {
+        RoundingPolicy::Nearest => scaled.round(),
+        // 容差避免浮点误差 (如 100.0000000001) 被多取一档
+        RoundingPolicy::HouseFavored if is_buy => (scaled - 1e-6).ceil(),
+        RoundingPolicy::HouseFavored => (scaled + 1e-6).floor(),
+    };
+    rounded / scale
+}
+
+/// 校验玩家身份；开启 log_validation 时逐次记录 UUID (可哈希)、结果来源与耗时
+async fn validate_player(req: &TradeRequest, config: &AppConfig, client: &reqwest::Client, limiter: &Semaphore) -> bool {
+    let started = std::time::Instant::now();
+    let (valid, outcome) = check_player(req, config.is_online_mode, client, limiter).await;
+    if config.log_validation {
+        let id = if config.log_validation_hash_ids { hashed_player_id(&req.player_id) } else { req.player_id.clone() };
+        tracing::info!("🔐 玩家校验 {}: {} ({}，耗时 {:?})", id, if valid { "通过" } else { "失败" }, outcome, started.elapsed());
     }
+    valid
 }
 
-async fn validate_player(req: &TradeRequest, online: bool, client: &reqwest::Client) -> bool {
-    if !online { return req.player_id.len() >= 32; }
+/// 返回 (是否通过, 结果来源)
+async fn check_player(req: &TradeRequest, online: bool, client: &reqwest::Client, limiter: &Semaphore) -> (bool, String) {
+    if !online { return (req.player_id.len() >= 32, "offline".into()); }
+    // 信号量不会被关闭，获取失败时按校验失败处理
+    let Ok(_permit) = limiter.acquire().await else { return (false, "limiter closed".into()); };
     let url = format!("https://sessionserver.mojang.com/session/minecraft/profile/{}", req.player_id.replace("-", ""));
     
-    client.get(&url)
+    match client.get(&url)
           .timeout(std::time::Duration::from_millis(constants::MOJANG_TIMEOUT_MS))
           .send()
           .await
-          .map(|r: reqwest::Response| r.status() == StatusCode::OK)
-          .unwrap_or(false)
+    {
+        Ok(r) if r.status() == StatusCode::OK => (true, "network".into()),
+        Ok(r) => (false, format!("network HTTP {}", r.status().as_u16())),
+        // 错误信息默认带请求 URL (含原始 UUID)，去掉后再记录，避免绕过 log_validation_hash_ids
+        Err(e) => (false, format!("network error: {}", e.without_url())),
+    }
+}
+
+/// 日志脱敏：UUID 的 SHA-256 前 8 字节 (同一玩家的日志仍可关联)
+fn hashed_player_id(player_id: &str) -> String {
+    use aws_lc_rs::digest;
+    let hash = digest::digest(&digest::SHA256, player_id.as_bytes());
+    let hex: String = hash.as_ref()[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+/// 查询 Mojang 档案中的玩家名 (请求失败或档案不存在时为 None)
+pub async fn fetch_profile_name(client: &reqwest::Client, limiter: &Semaphore, player_id: &str) -> Option<String> {
+    let _permit = limiter.acquire().await.ok()?;
+    let url = format!("https://sessionserver.mojang.com/session/minecraft/profile/{}", player_id.replace("-", ""));
+    let resp = client.get(&url)
+        .timeout(std::time::Duration::from_millis(constants::MOJANG_TIMEOUT_MS))
+        .send()
+        .await
+        .ok()
+        .filter(|r| r.status() == StatusCode::OK)?;
+    let profile: serde_json::Value = resp.json().await.ok()?;
+    profile["name"].as_str().map(str::to_string)
 }
 
 fn empty_resp(env: f64, n: f64) -> TradeResponse {
@@ -303,10 +869,624 @@ fn empty_resp(env: f64, n: f64) -> TradeResponse {
         total_price: 0.0, 
         unit_price_avg: 0.0, 
         env_index: env, 
-        effective_n: n 
+        effective_n: n,
+        trade_id: None,
+        quote_env_index: env,
+        env_floored: false,
+        server_load: 0.0,
+        payout: None,
+        payout_currency: None,
     }
 }
 
 impl TransactionRecord {
     fn with_note(mut self, note: String) -> Self { self.note = note.into(); self }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{RecoveryModel, SalesRecord};
+
+    fn preview(item_id: &str, amount: f64, decay_lambda: f64) -> TradeRequest {
+        TradeRequest {
+            player_id: "0123456789abcdef0123456789abcdef".into(), player_name: "Steve".into(), item_id: item_id.into(),
+            amount, base_price: 100.0, decay_lambda,
+            manual_env_index: Some(1.0), is_preview: true,
+            ..Default::default()
+        }
+    }
+
+    async fn quote(req: &TradeRequest, config: &AppConfig, market_item: Option<&MarketItem>, action: TradeAction) -> TradeResponse {
+        let env_cache = RwLock::new(None);
+        execute_trade_logic(
+            req, config, &HashMap::new(), &PlayerSalesHistory::default(), &action,
+            &env_cache, &reqwest::Client::new(), &Semaphore::new(1), market_item,
+        ).await.0
+    }
+
+    /// 真实成交 (离线模式校验，不访问网络)
+    async fn execute(req: &TradeRequest, config: &AppConfig, action: TradeAction) -> (TradeResponse, Option<TransactionRecord>) {
+        let config = AppConfig { is_online_mode: false, ..config.clone() };
+        let req = TradeRequest { player_id: "0123456789abcdef0123456789abcdef".into(), is_preview: false, ..req.clone() };
+        let (req, config) = (&req, &config);
+        let env_cache = RwLock::new(None);
+        execute_trade_logic(
+            req, config, &HashMap::new(), &PlayerSalesHistory::default(), &action,
+            &env_cache, &reqwest::Client::new(), &Semaphore::new(1), None,
+        ).await
+    }
+
+    #[tokio::test]
+    async fn sub_minimum_trade_is_rejected_without_record() {
+        let req = TradeRequest { base_price: 0.001, ..preview("dust", 1.0, 0.0) };
+        let (resp, record) = execute(&req, &AppConfig::default(), TradeAction::Sell).await;
+        assert!(!resp.success);
+        assert!(resp.message.contains("最低结算金额"));
+        assert!(record.is_none());
+    }
+
+    #[tokio::test]
+    async fn sub_minimum_trade_pays_the_minimum_when_configured() {
+        let config = AppConfig { sub_minimum_policy: SubMinimumPolicy::PayMinimum, ..Default::default() };
+        let req = TradeRequest { base_price: 0.001, ..preview("dust", 1.0, 0.0) };
+        let (resp, record) = execute(&req, &config, TradeAction::Sell).await;
+        assert!(resp.success);
+        assert_eq!(resp.total_price, 0.01);
+        assert_eq!(record.map(|r| r.total_price), Some(0.01));
+    }
+
+    #[tokio::test]
+    async fn custom_action_prices_like_its_side_and_keeps_its_label() {
+        let req = preview("stone", 1.0, 0.01);
+        let auction = TradeAction::Custom { label: "AUCTION".into(), prices_as_buy: true };
+        let (custom, record) = execute(&req, &AppConfig::default(), auction).await;
+        let (buy, _) = execute(&req, &AppConfig::default(), TradeAction::Buy).await;
+
+        assert!(custom.success, "{}", custom.message);
+        assert_eq!(custom.total_price, buy.total_price);
+        assert_eq!(record.unwrap().action, "AUCTION");
+        // 线上格式：内置动作为字符串，自定义动作为带标签的对象
+        let parsed: TradeAction = serde_json::from_str(r#"{"CUSTOM": {"label": "AUCTION", "pricesAsBuy": true}}"#).unwrap();
+        assert!(parsed.is_buy());
+        assert_eq!(serde_json::from_str::<TradeAction>(r#""SELL""#).unwrap(), TradeAction::Sell);
+    }
+
+    #[tokio::test]
+    async fn known_item_prices_with_server_lambda() {
+        let config = AppConfig { is_online_mode: false, ..Default::default() };
+        // 客户端仍携带调参前的 lambda
+        let item = MarketItem { id: "stone".into(), n: 10.0, lambda: 0.05, ..Default::default() };
+        let resp = quote(&preview("stone", 5.0, 0.01), &config, Some(&item), TradeAction::Sell).await;
+
+        let expected = PricingEngine::calculate_price(100.0, 1.0, 10.0, 5.0, 0.05, config.buy_premium, config.fresh_mint_surcharge, false, false);
+        assert!((resp.total_price - expected).abs() < 0.01, "{} vs {}", resp.total_price, expected);
+    }
+
+    #[tokio::test]
+    async fn buy_heavy_history_goes_negative_only_when_allowed() {
+        let now = Utc::now().timestamp_millis();
+        let sales = vec![SalesRecord { timestamp: now, amount: -40.0, ..Default::default() }];
+        let history = PlayerSalesHistory { item_sales: [("stone".to_string(), sales)].into_iter().collect(), ..Default::default() };
+        let item = MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() };
+        let sell = |config: AppConfig| {
+            let (history, item) = (history.clone(), item.clone());
+            async move {
+                execute_trade_logic(
+                    &preview("stone", 1.0, 0.01), &config, &HashMap::new(), &history, &TradeAction::Sell,
+                    &RwLock::new(None), &reqwest::Client::new(), &Semaphore::new(1), Some(&item),
+                ).await.0
+            }
+        };
+
+        // 默认：n 截断在 0，卖出价不高于基准价
+        let clamped = sell(AppConfig::default()).await;
+        assert_eq!(clamped.effective_n, 0.0);
+        assert!(clamped.total_price < 100.0, "{}", clamped.total_price);
+
+        // 允许为负：欠下的供给使卖出价高于基准价 (≈ 100·e^0.4)
+        let allowed = sell(AppConfig { allow_negative_n: true, ..Default::default() }).await;
+        assert!(allowed.effective_n < -39.0, "{}", allowed.effective_n);
+        assert!(allowed.total_price > 145.0, "{}", allowed.total_price);
+    }
+
+    #[test]
+    fn buy_then_sell_is_not_profitable_with_negative_n() {
+        let price = |n: f64, amt: f64, surcharge: f64, is_buy: bool|
+            PricingEngine::calculate_price(100.0, 1.0, n, amt, 0.01, 1.0, surcharge, is_buy, true);
+
+        // 从正库存、零库存与已为负的库存起买入，再从买入后的 n 卖回，卖出所得不超过买入花费
+        for n in [30.0, 0.0, -40.0] {
+            for surcharge in [0.0, 0.5] {
+                let cost = price(n, 50.0, surcharge, true);
+                let revenue = price(n - 50.0, 50.0, surcharge, false);
+                assert!(revenue <= cost + 1e-9, "n={n} surcharge={surcharge}: {revenue} > {cost}");
+            }
+        }
+        // 库存充足时与原买入曲线一致
+        let stocked = PricingEngine::calculate_price(100.0, 1.0, 80.0, 50.0, 0.01, 1.0, 0.5, true, false);
+        assert!((price(80.0, 50.0, 0.5, true) - stocked).abs() < 1e-9);
+    }
+
+    #[test]
+    fn over_target_volume_raises_lambda() {
+        let config = AppConfig { lambda_autotune_gain: 0.1, ..Default::default() };
+        let now = 1_000_000;
+        let sales = vec![SalesRecord { timestamp: now, amount: 150.0, ..Default::default() }, SalesRecord { timestamp: now, amount: -50.0, ..Default::default() }];
+        let history = PlayerSalesHistory { item_sales: [("stone".to_string(), sales)].into_iter().collect(), ..Default::default() };
+        let histories = HashMap::from([("p1".to_string(), history)]);
+
+        // 买卖双向计入成交量：200，超出目标 100
+        let volume = tuning::realized_volume(&histories, "stone", now - 1);
+        assert_eq!(volume, 200.0);
+        let raised = tuning::adjust_lambda(0.01, volume, 100.0, &config);
+        assert!(raised > 0.01);
+        assert!(tuning::adjust_lambda(0.01, 20.0, 100.0, &config) < 0.01);
+        // 统计窗口之外的成交不计入
+        assert_eq!(tuning::realized_volume(&histories, "stone", now + 1), 0.0);
+    }
+
+    #[test]
+    fn linear_recovery_restores_fully_after_window() {
+        let exp = AppConfig { recovery_delta: 3.0, recovery_tau: 3600.0, ..Default::default() };
+        let linear = AppConfig { recovery_model: RecoveryModel::Linear, recovery_window_secs: 3600.0, ..Default::default() };
+        let now = 10_000_000;
+        let n_after = |config: &AppConfig, elapsed_secs: i64| {
+            let history = [SalesRecord { timestamp: now - elapsed_secs * 1000, amount: 100.0, ..Default::default() }];
+            PricingEngine::calculate_history_decay(&history, config, now)
+        };
+
+        for (elapsed, linear_n) in [(0, 100.0), (900, 75.0), (1800, 50.0), (3600, 0.0), (7200, 0.0)] {
+            assert!((n_after(&linear, elapsed) - linear_n).abs() < 1e-9, "linear at {elapsed}s");
+            let exp_n = 100.0 * (-3.0 * elapsed as f64 / 3600.0).exp();
+            assert!((n_after(&exp, elapsed) - exp_n).abs() < 1e-9, "exponential at {elapsed}s");
+        }
+        // 指数模型初期恢复更快，但永远不会完全恢复
+        assert!(n_after(&exp, 900) < n_after(&linear, 900));
+        assert!(n_after(&exp, 3600) > n_after(&linear, 3600));
+    }
+
+    #[test]
+    fn fresh_mint_surcharge_applies_only_to_the_excess_over_stock() {
+        let buy = |amount: f64, surcharge: f64| PricingEngine::calculate_price(100.0, 1.0, 10.0, amount, 0.01, 1.0, surcharge, true, false);
+        // 库存 10 之内：附加费不影响价格
+        assert_eq!(buy(10.0, 0.5), buy(10.0, 0.0));
+
+        // 买 15：前 10 个按曲线折价，超出的 5 个按满价 100 再加 50%
+        let discounted = PricingEngine::integral_revenue(100.0, 1.0, 0.0, 10.0, 0.01);
+        assert!((buy(15.0, 0.0) - (discounted + 5.0 * 100.0)).abs() < 1e-9);
+        assert!((buy(15.0, 0.5) - (discounted + 5.0 * 150.0)).abs() < 1e-9);
+        assert!((buy(15.0, 0.5) - buy(15.0, 0.0) - 5.0 * 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn house_favored_rounding_floors_sells_and_ceils_buys() {
+        let house = AppConfig { rounding_policy: RoundingPolicy::HouseFavored, price_decimals: 2, ..Default::default() };
+        assert_eq!(round_price(12.349, &house, false), 12.34);
+        assert_eq!(round_price(12.341, &house, true), 12.35);
+        // 浮点误差不会多取一档
+        assert_eq!(round_price(100.000_000_000_1, &house, true), 100.0);
+        let nearest = AppConfig { price_decimals: 2, ..Default::default() };
+        assert_eq!(round_price(12.349, &nearest, false), 12.35);
+        assert_eq!(round_price(12.341, &nearest, true), 12.34);
+    }
+
+    #[tokio::test]
+    async fn preview_and_execution_round_identically() {
+        let config = AppConfig { rounding_policy: RoundingPolicy::HouseFavored, ..Default::default() };
+        for action in [TradeAction::Sell, TradeAction::Buy] {
+            let req = preview("stone", 3.0, 0.013);
+            let quoted = quote(&req, &config, None, action.clone()).await;
+            let (executed, _) = execute(&req, &config, action).await;
+            assert!(executed.success);
+            assert_eq!(quoted.total_price, executed.total_price);
+        }
+    }
+
+    #[test]
+    fn autotune_clamps_lambda_into_band() {
+        let config = AppConfig { lambda_autotune_gain: 0.1, lambda_min: 0.0001, lambda_max: 0.1, ..Default::default() };
+        // 区间外的值 (如放宽上限前同步的 0.5) 被拉回区间
+        assert_eq!(tuning::adjust_lambda(0.5, 200.0, 100.0, &config), 0.1);
+        assert_eq!(tuning::adjust_lambda(0.5, 0.0, 100.0, &config), 0.1);
+        assert_eq!(tuning::adjust_lambda(0.00001, 0.0, 100.0, &config), 0.0001);
+        // 区间内照常按比例调整并受 lambda_max 约束
+        assert!((tuning::adjust_lambda(0.01, 200.0, 100.0, &config) - 0.011).abs() < 1e-12);
+        assert_eq!(tuning::adjust_lambda(0.1, 200.0, 100.0, &config), 0.1);
+    }
+
+    #[test]
+    fn env_noise_carries_over_between_ticks() {
+        let config = AppConfig { noise_std: 0.0001, noise_persistence: 0.9, ..Default::default() };
+        let cache = RwLock::new(Some(EnvCache { last_noise: 0.5, ..Default::default() }));
+
+        environment::calculate_current_env_index(&config, &HashMap::new(), &cache);
+        // 冲击几乎为零时，新噪声 ≈ ρ·上次噪声
+        let noise = cache.read().as_ref().unwrap().last_noise;
+        assert!((noise - 0.45).abs() < 0.01, "{}", noise);
+    }
+
+    #[test]
+    fn env_base_index_excludes_the_noise_sample() {
+        let config = AppConfig { noise_std: 0.05, base_env_index: 1.0, ..Default::default() };
+        let cache = RwLock::new(None);
+        let (index, base, _) = environment::current_env_components(&config, &HashMap::new(), &cache);
+        let noise = cache.read().as_ref().unwrap().last_noise;
+        assert!((index - base - noise).abs() < 1e-12, "{index} - {base} != {noise}");
+
+        // 确定性部分与叠加噪声后的指数都不低于下限
+        let config = AppConfig { base_env_index: -5.0, ..config };
+        let (index, base, _) = environment::current_env_components(&config, &HashMap::new(), &RwLock::new(None));
+        assert_eq!(base, constants::MIN_ENV_INDEX);
+        assert!(index >= constants::MIN_ENV_INDEX);
+    }
+
+    #[tokio::test]
+    async fn preview_makes_no_mojang_calls() {
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+        // 所有请求经本地代理转发，代理收到连接即计数后断开
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = reqwest::Proxy::all(format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                drop(socket);
+            }
+        });
+        let client = reqwest::Client::builder().proxy(proxy).build().unwrap();
+        let config = AppConfig { is_online_mode: true, ..Default::default() };
+        let (env_cache, history, holidays) = (RwLock::new(None), PlayerSalesHistory::default(), HashMap::new());
+
+        let req = preview("stone", 1.0, 0.01);
+        let (resp, _) = execute_trade_logic(&req, &config, &holidays, &history, &TradeAction::Sell, &env_cache, &client, &Semaphore::new(1), None).await;
+        assert!(resp.success, "{}", resp.message);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // 真实成交仍需校验 (此处 Mojang 不可达，校验失败)
+        let req = TradeRequest { is_preview: false, ..req };
+        let (resp, record) = execute_trade_logic(&req, &config, &holidays, &history, &TradeAction::Sell, &env_cache, &client, &Semaphore::new(1), None).await;
+        assert!(!resp.success);
+        assert!(record.is_none());
+        assert!(calls.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn tiny_recovery_tau_is_flagged() {
+        let broken = AppConfig { recovery_tau: 0.001, ..Default::default() };
+        assert!(PricingEngine::recovery_warning(&broken).is_some_and(|w| w.contains("recovery_tau=0.001")));
+        assert!(PricingEngine::recovery_warning(&AppConfig::default()).is_none());
+
+        let linear = AppConfig { recovery_model: RecoveryModel::Linear, recovery_window_secs: 5.0, ..Default::default() };
+        assert!(PricingEngine::recovery_warning(&linear).is_some());
+    }
+
+    #[test]
+    fn negative_holiday_factor_boosts_the_index() {
+        let config = AppConfig {
+            noise_std: 0.0, holiday_factor: 0.0, weekend_factor: 0.0, public_holiday_factor: -0.2,
+            ..Default::default()
+        };
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let holidays = HashMap::from([(today, true)]);
+
+        let (boosted, note) = environment::calculate_current_env_index(&config, &holidays, &RwLock::new(None));
+        let (normal, _) = environment::calculate_current_env_index(&config, &HashMap::new(), &RwLock::new(None));
+        assert!(boosted > normal + 0.19, "{boosted} vs {normal}");
+        assert!(note.contains("Holiday(+0.20)"), "{note}");
+    }
+
+    #[test]
+    fn special_date_applies_its_delta() {
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let config = AppConfig {
+            noise_std: 0.0, holiday_factor: 0.0, weekend_factor: 0.0,
+            special_dates: HashMap::from([(today.clone(), 0.3), ("1999-12-25".to_string(), -5.0)]),
+            ..Default::default()
+        };
+        let plain = AppConfig { special_dates: HashMap::new(), ..config.clone() };
+
+        let (special, note) = environment::calculate_current_env_index(&config, &HashMap::new(), &RwLock::new(None));
+        let (normal, _) = environment::calculate_current_env_index(&plain, &HashMap::new(), &RwLock::new(None));
+        assert!((special - normal - 0.3).abs() < 0.01, "{special} vs {normal}");
+        assert!(note.contains(&format!("{today}(+0.30)")), "{note}");
+    }
+
+    #[test]
+    fn exp_table_stays_within_tolerance() {
+        let config = AppConfig { exp_table_enabled: true, exp_table_tolerance: 1e-6, ..Default::default() };
+        for k in 0..=25_000 {
+            let x = k as f64 * 0.001;
+            let (approx, exact) = (PricingEngine::exp_neg(x, &config), (-x).exp());
+            assert!((approx - exact).abs() <= 1e-6 + 1e-15, "x={x}: {approx} vs {exact}");
+        }
+        // 超出表范围时精确计算
+        assert_eq!(PricingEngine::exp_neg(30.0, &config), (-30.0f64).exp());
+        assert_eq!(PricingEngine::exp_neg(-0.5, &config), 0.5f64.exp());
+    }
+
+    #[test]
+    fn out_of_range_exp_table_tolerance_is_flagged_and_never_yields_nan() {
+        let with = |tolerance: f64| AppConfig { exp_table_enabled: true, exp_table_tolerance: tolerance, ..Default::default() };
+        assert!(PricingEngine::exp_table_warning(&with(1e-6)).is_none());
+        assert!(PricingEngine::exp_table_warning(&AppConfig { exp_table_enabled: false, ..with(f64::NAN) }).is_none());
+        for tolerance in [f64::NAN, 0.0, -1.0, 1e-12, 0.5] {
+            assert!(PricingEngine::exp_table_warning(&with(tolerance)).is_some(), "{tolerance}");
+        }
+
+        // NaN / 非正容差直接精确计算
+        assert_eq!(PricingEngine::exp_neg(1.5, &with(f64::NAN)), (-1.5f64).exp());
+        assert_eq!(PricingEngine::exp_neg(1.5, &with(0.0)), (-1.5f64).exp());
+        // 过大的容差按上限建表，误差不超过上限
+        let approx = PricingEngine::exp_neg(1.5, &with(0.5));
+        assert!((approx - (-1.5f64).exp()).abs() <= 0.1, "{approx}");
+    }
+
+    #[tokio::test]
+    async fn small_sells_are_exempt_from_sell_tax() {
+        let config = AppConfig { sell_tax_rate: 0.1, tax_exempt_below: 50.0, ..Default::default() };
+        let untaxed = AppConfig::default();
+
+        // 税前约 100：全额计税
+        let large = preview("stone", 1.0, 0.0);
+        let taxed = quote(&large, &config, None, TradeAction::Sell).await;
+        assert_eq!(taxed.total_price, 90.0);
+        assert!(taxed.message.contains("Tax(10%)"), "{}", taxed.message);
+
+        // 税前约 10：免税，价格不变并在说明中标注
+        let small = TradeRequest { base_price: 10.0, ..preview("stone", 1.0, 0.0) };
+        let exempt = quote(&small, &config, None, TradeAction::Sell).await;
+        assert_eq!(exempt.total_price, quote(&small, &untaxed, None, TradeAction::Sell).await.total_price);
+        assert!(exempt.message.contains("TaxExempt"), "{}", exempt.message);
+
+        // 买入不计税
+        let buy = quote(&large, &config, None, TradeAction::Buy).await;
+        assert_eq!(buy.total_price, quote(&large, &untaxed, None, TradeAction::Buy).await.total_price);
+    }
+
+    #[test]
+    fn env_index_is_stable_within_the_granularity_window() {
+        let hourly = AppConfig { env_cache_granularity_secs: 3600, noise_std: 0.5, ..Default::default() };
+        let cache = RwLock::new(None);
+        let first = calculate_current_env_index(&hourly, &HashMap::new(), &cache);
+        let second = calculate_current_env_index(&hourly, &HashMap::new(), &cache);
+        assert_eq!(first, second);
+
+        // 0 表示每次都重新采样噪声
+        let fresh = AppConfig { env_cache_granularity_secs: 0, ..hourly };
+        let samples: Vec<f64> = (0..5).map(|_| calculate_current_env_index(&fresh, &HashMap::new(), &cache).0).collect();
+        assert!(samples.windows(2).any(|w| w[0] != w[1]), "{samples:?}");
+    }
+
+    #[test]
+    fn env_cache_is_recomputed_across_local_midnight() {
+        use chrono::{NaiveDate, TimeZone};
+        let at = |d: u32, h: u32, m: u32, s: u32| {
+            let naive = NaiveDate::from_ymd_opt(2026, 3, d).unwrap().and_hms_opt(h, m, s).unwrap();
+            Local.from_local_datetime(&naive).earliest().unwrap()
+        };
+        // 窗口极长，跨午夜前后必然落在同一窗口内，只有日期变化能触发重算
+        let config = AppConfig {
+            env_cache_granularity_secs: 1 << 40,
+            special_dates: HashMap::from([("2026-03-11".to_string(), 0.3)]),
+            ..Default::default()
+        };
+        let cache = RwLock::new(None);
+        let env = |now| environment::env_components_at(now, &config, &HashMap::new(), &cache).2;
+
+        let before = env(at(10, 23, 59, 50));
+        assert!(!before.contains("2026-03-11"), "{before}");
+        assert_eq!(env(at(10, 23, 59, 55)), before);
+        let after = env(at(11, 0, 0, 5));
+        assert!(after.contains("2026-03-11"), "{after}");
+    }
+
+    #[tokio::test]
+    async fn english_mode_uses_english_messages_and_tags() {
+        let config = AppConfig { log_lang: crate::models::LogLang::En, sell_tax_rate: 0.1, ..Default::default() };
+        let resp = quote(&preview("stone", 1.0, 0.0), &config, None, TradeAction::Sell).await;
+        assert_eq!(resp.message, "Trade succeeded (Manual+Tax(10%))");
+
+        let (_, record) = execute(&preview("stone", 1.0, 0.0), &config, TradeAction::Sell).await;
+        let note = record.unwrap().note;
+        assert!(note.is_ascii(), "{note}");
+
+        let invalid = quote(&preview("stone", 0.0, 0.0), &config, None, TradeAction::Sell).await;
+        assert_eq!(invalid.message, "Invalid trade amount");
+        // 默认 zh 保持原有文案
+        let zh = quote(&preview("stone", 0.0, 0.0), &AppConfig::default(), None, TradeAction::Sell).await;
+        assert_eq!(zh.message, "交易量无效");
+    }
+
+    #[tokio::test]
+    async fn record_keeps_the_env_index_used_for_the_quote() {
+        // 粒度 0：每次读取环境都会重新采样，模拟报价与落盘跨越缓存窗口
+        let config = AppConfig { env_cache_granularity_secs: 0, noise_std: 0.3, ..Default::default() };
+        let req = TradeRequest { manual_env_index: None, ..preview("stone", 1.0, 0.0) };
+        for _ in 0..5 {
+            let (resp, record) = execute(&req, &config, TradeAction::Sell).await;
+            let record = record.unwrap();
+            assert_eq!(record.env_index, resp.quote_env_index);
+            assert_eq!(resp.env_index, (resp.quote_env_index * 1000.0).round() / 1000.0);
+            // 报价总额正是按该指数计算的
+            assert!((resp.total_price - (100.0 * resp.quote_env_index).round_2()).abs() < 0.011);
+        }
+    }
+
+    #[tokio::test]
+    async fn mojang_concurrency_stays_within_the_limit() {
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+        // 本地代理记录同时打开的连接数，每个连接保持一小段时间后断开
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = reqwest::Proxy::all(format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let (open, peak, total) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (o, p, t) = (open.clone(), peak.clone(), total.clone());
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let (o, p) = (o.clone(), p.clone());
+                t.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    p.fetch_max(o.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    o.fetch_sub(1, Ordering::SeqCst);
+                    drop(socket);
+                });
+            }
+        });
+        let client = reqwest::Client::builder().proxy(proxy).build().unwrap();
+        let config = AppConfig { is_online_mode: true, ..Default::default() };
+        let (env_cache, history, holidays) = (RwLock::new(None), PlayerSalesHistory::default(), HashMap::new());
+        let limiter = Semaphore::new(2);
+
+        let req = TradeRequest { is_preview: false, ..preview("stone", 1.0, 0.01) };
+        let trades = (0..8).map(|_| execute_trade_logic(
+            &req, &config, &holidays, &history, &TradeAction::Sell, &env_cache, &client, &limiter, None,
+        ));
+        futures::future::join_all(trades).await;
+        assert!(total.load(Ordering::SeqCst) >= 8);
+        assert!(peak.load(Ordering::SeqCst) <= 2, "{}", peak.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn env_token_takes_precedence_over_manual_until_it_expires() {
+        let config = AppConfig { env_token_secret: "s3cret".into(), ..Default::default() };
+        let now = Utc::now().timestamp_millis();
+        let with_token = |expires_at| TradeRequest {
+            env_token: Some(env_token::issue(1.5, expires_at, "s3cret")),
+            manual_env_index: Some(0.8),
+            ..preview("stone", 1.0, 0.0)
+        };
+
+        // 有效令牌 + 手动指数：令牌优先
+        let frozen = quote(&with_token(now + 60_000), &config, None, TradeAction::Sell).await;
+        assert_eq!((frozen.quote_env_index, frozen.total_price), (1.5, 150.0));
+        assert!(frozen.message.contains("Frozen"));
+
+        // 过期令牌 + 手动指数：回落到手动
+        let expired = quote(&with_token(now - 1), &config, None, TradeAction::Sell).await;
+        assert_eq!((expired.quote_env_index, expired.total_price), (0.8, 80.0));
+
+        // 被篡改的令牌同样被忽略
+        let forged = TradeRequest { env_token: Some(env_token::issue(1.5, now + 60_000, "other")), ..with_token(now) };
+        assert_eq!(quote(&forged, &config, None, TradeAction::Sell).await.quote_env_index, 0.8);
+
+        // 严格模式下两者冲突直接拒绝
+        let strict = AppConfig { strict_env_precedence: true, ..config };
+        let rejected = quote(&with_token(now + 60_000), &strict, None, TradeAction::Sell).await;
+        assert!(!rejected.success);
+        assert!(rejected.message.contains("冲突"), "{}", rejected.message);
+    }
+
+    #[tokio::test]
+    async fn env_floor_is_reported() {
+        // 扣减因子之和远超基准指数，环境指数被钳制到下限
+        let config = AppConfig { base_env_index: -10.0, noise_std: 0.0, ..Default::default() };
+        let req = TradeRequest { manual_env_index: None, ..preview("stone", 1.0, 0.0) };
+        let floored = quote(&req, &config, None, TradeAction::Sell).await;
+        assert!(floored.env_floored);
+        assert_eq!(floored.quote_env_index, constants::MIN_ENV_INDEX);
+
+        let normal = quote(&preview("stone", 1.0, 0.0), &config, None, TradeAction::Sell).await;
+        assert!(!normal.env_floored);
+    }
+
+    #[tokio::test]
+    async fn launch_iota_decays_with_its_half_life() {
+        let launch_at_ms = 1_700_000_000_000;
+        let config = AppConfig { launch_iota: 40.0, launch_iota_half_life_secs: 3600.0, launch_at_ms, ..Default::default() };
+        let at = |hours: i64| PricingEngine::launch_iota(None, &config, launch_at_ms + hours * 3_600_000);
+        assert_eq!(at(0), 40.0);
+        assert!((at(1) - 20.0).abs() < 1e-9);
+        assert!(at(1) > at(2) && at(2) > at(10) && at(10) < 0.1);
+        // 物品级初始值覆盖全局
+        assert!((PricingEngine::launch_iota(Some(8.0), &config, launch_at_ms + 3_600_000) - 4.0).abs() < 1e-9);
+
+        // 报价的有效库存包含当前的开市溢价
+        let item = MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() };
+        let now_config = AppConfig { launch_at_ms: chrono::Utc::now().timestamp_millis(), ..config };
+        let resp = quote(&preview("stone", 1.0, 0.01), &now_config, Some(&item), TradeAction::Sell).await;
+        assert!((resp.effective_n - 40.0).abs() < 0.1);
+    }
+
+    #[cfg(feature = "trade-metadata")]
+    #[tokio::test]
+    async fn record_metadata_round_trips_through_postcard() {
+        let (resp, record) = execute(&preview("stone", 2.0, 0.0), &AppConfig::default(), TradeAction::Sell).await;
+        let record = record.unwrap();
+        assert_eq!(record.metadata["auth"], "OfflineAuth");
+        assert_eq!(record.metadata["env"], "Manual");
+        assert_eq!(record.metadata["effective_n"], resp.effective_n.to_string());
+
+        let bytes = postcard::to_stdvec(&record).unwrap();
+        let decoded: TransactionRecord = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.metadata, record.metadata);
+        assert_eq!(decoded.note, record.note);
+    }
+
+    #[tokio::test]
+    async fn validation_outcome_is_logged_under_the_flag() {
+        use std::sync::{Arc, Mutex};
+        #[derive(Clone, Default)]
+        struct Capture(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+        }
+
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+        // 当前线程运行时：整个测试都在这个订阅者下执行
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let req = preview("stone", 1.0, 0.01);
+        let (client, limiter) = (reqwest::Client::new(), Semaphore::new(1));
+        let quiet = AppConfig { is_online_mode: false, ..Default::default() };
+        assert!(validate_player(&req, &quiet, &client, &limiter).await);
+        assert!(capture.0.lock().unwrap().is_empty());
+
+        let logged = AppConfig { log_validation: true, log_validation_hash_ids: false, ..quiet.clone() };
+        assert!(validate_player(&req, &logged, &client, &limiter).await);
+        let hashed = AppConfig { log_validation_hash_ids: true, ..logged };
+        assert!(validate_player(&req, &hashed, &client, &limiter).await);
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2, "{output}");
+        assert!(lines[0].contains("INFO") && lines[0].contains(&req.player_id) && lines[0].contains("offline"), "{output}");
+        assert!(lines[1].contains(&hashed_player_id(&req.player_id)) && !lines[1].contains(&req.player_id), "{output}");
+
+        // 网络错误：经不可达代理请求必然失败，日志中同样不得出现原始 UUID
+        let unreachable = reqwest::Client::builder().proxy(reqwest::Proxy::all("http://127.0.0.1:1").unwrap()).build().unwrap();
+        let online = AppConfig { is_online_mode: true, ..hashed };
+        assert!(!validate_player(&req, &online, &unreachable, &limiter).await);
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let last = output.lines().last().unwrap();
+        assert!(last.contains("network error") && last.contains(&hashed_player_id(&req.player_id)), "{output}");
+        assert!(!last.contains(&req.player_id) && !last.contains("sessionserver"), "{output}");
+    }
+
+    #[test]
+    fn env_history_trims_samples_older_than_the_max_age() {
+        let config = AppConfig { env_history_max_len: 1_000, env_history_max_age_secs: 3_600, ..Default::default() };
+        let mut history = environment::EnvHistory::default();
+        // 模拟时钟：每 10 分钟一个样本，共 3 小时
+        let start = 1_700_000_000;
+        for i in 0..=18 {
+            history.push(start + i * 600, 1.0 + i as f64 / 100.0, &config);
+        }
+        let times: Vec<i64> = history.samples().iter().map(|&(t, _)| t).collect();
+        assert_eq!(times.first(), Some(&(start + 18 * 600 - 3_600)));
+        assert_eq!(times.len(), 7);
+
+        // 采样间隔变化后按时长保留的窗口不变；条数上限同时生效
+        let capped = AppConfig { env_history_max_len: 3, ..config };
+        history.push(start + 18 * 600 + 60, 2.0, &capped);
+        assert_eq!(history.samples().len(), 3);
+        assert_eq!(history.samples().back(), Some(&(start + 18 * 600 + 60, 2.0)));
+    }
+}
@@ -1,36 +1,263 @@
 mod models;
 mod logic;
 mod api;
+mod ws;
+mod storage;
+#[cfg(feature = "scripting")]
+mod script;
 
-// [修改] 移除了未使用的 'get'，只保留 'post'
-use axum::{routing::post, Router, http::StatusCode};
+use axum::{routing::{delete, get, post}, Router, http::StatusCode, extract::DefaultBodyLimit, middleware};
 use parking_lot::RwLock;
-use std::{collections::{HashMap, VecDeque}, fs, io, net::SocketAddr, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Duration};
-use tokio::{sync::mpsc, signal, task, time};
+use std::{collections::{HashMap, VecDeque}, fs, io::{self, Read, Seek, SeekFrom}, net::SocketAddr, sync::{Arc, atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering}}, time::{Duration, Instant}};
+use tokio::{sync::{broadcast, mpsc, Semaphore}, signal, task, time};
 use tower_http::{cors::CorsLayer, timeout::TimeoutLayer};
 use tracing::{error, info, warn};
 use chrono::Local;
+use rustc_hash::FxHashMap;
 
 use crate::models::*;
+use crate::storage::{FileBackend, StorageBackend};
 
 // --- 核心常量 ---
 const CONFIG_FILE: &str = "config.bin";
 const HISTORY_FILE: &str = "history.bin";
-const PLAYER_DATA_FILE: &str = "player_data.bin";
+pub const PLAYER_DATA_FILE: &str = "player_data.bin";
 // [新增] 用于保存市场物品的实时状态（价格、热度、库存等）
 const MARKET_DATA_FILE: &str = "market_data.bin";
 // [新增] 用于保存环境参数（可选，取决于是否需要持久化环境倍率）
 const ENV_DATA_FILE: &str = "env_data.bin";
+// lambda 自动调参上次执行的毫秒时间戳 (重启后按它续算周期)
+const AUTOTUNE_STATE_FILE: &str = "lambda_autotune.bin";
+// 管理员删除的物品 (保留最终状态，可恢复)
+const ARCHIVED_ITEMS_FILE: &str = "archived_items.bin";
+// 最近分配的成交序号 (重启后续接，保证 tradeId 单调递增)
+const TRADE_SEQ_FILE: &str = "trade_seq.bin";
+// 玩家余额 (仅 enforce_balances 时变动)
+const BALANCES_FILE: &str = "balances.bin";
+// 就绪探针写入的临时文件
+pub const READY_PROBE_FILE: &str = ".ready";
+// 紧急排查用的只读开关 (启动时读取)
+const READ_ONLY_ENV: &str = "ECONOMY_READ_ONLY";
 
 const CHANNEL_CAPACITY: usize = 2_000;
-const MAX_CACHE_SIZE: usize = 1000;
+const PRICE_EVENT_CAPACITY: usize = 1_024;
+pub const MAX_CACHE_SIZE: usize = 1000;
 const BATCH_SIZE: usize = 50;
+// 写入任务 panic 后的重启退避 (逐次翻倍，封顶)
+const WRITER_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const WRITER_RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+const HISTORY_READ_CHUNK: usize = 64 * 1024;
+// 历史日志文件头：魔数 + 版本字节 + 0x00，本身构成一个完整帧，倒序读取时可直接识别
+const HISTORY_LOG_HEADER: [u8; 6] = [b'E', b'C', b'O', b'S', STORE_FORMAT_VERSION as u8, 0];
+const _: () = assert!(STORE_FORMAT_VERSION > 0 && STORE_FORMAT_VERSION <= u8::MAX as u16);
 
 pub struct SystemMetrics {
     pub total_trades: AtomicU64,
     pub write_failures: AtomicU64,
     pub channel_dropped: AtomicU64,
+    // 流水写入任务异常退出后被重启的次数
+    pub writer_restarts: AtomicU64,
     pub start_time: i64,
+    // 行情快照计算耗时 / 单笔交易定价耗时
+    pub market_snapshot_latency: LatencyHistogram,
+    pub trade_pricing_latency: LatencyHistogram,
+    // 成交额分布 (Prometheus: economy_trade_value)
+    pub trade_value: ValueHistogram,
+    // 交易与行情请求的每秒计数 (用于 serverLoad)
+    pub request_rate: RateWindow,
+    // 各接口类别的累计请求数 (Prometheus: economy_requests_total)
+    pub route_requests: RouteCounters,
+}
+
+/// 固定桶的耗时直方图 (微秒)，无锁累加
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+const LATENCY_BUCKETS_US: [u64; 8] = [50, 100, 250, 500, 1_000, 2_500, 10_000, 50_000];
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        let idx = LATENCY_BUCKETS_US.iter().position(|&b| us <= b).unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> serde_json::Value {
+        let buckets: Vec<serde_json::Value> = self.buckets.iter().enumerate()
+            .map(|(i, b)| serde_json::json!({
+                "leUs": LATENCY_BUCKETS_US.get(i).copied(),
+                "count": b.load(Ordering::Relaxed)
+            }))
+            .collect();
+        serde_json::json!({
+            "count": self.count.load(Ordering::Relaxed),
+            "sumUs": self.sum_us.load(Ordering::Relaxed),
+            "buckets": buckets
+        })
+    }
+
+    /// Prometheus 文本格式，单位换算为秒 (桶为累计计数)
+    pub fn render(&self, name: &str, help: &str) -> String {
+        use std::fmt::Write;
+        let mut out = format!("# HELP {name} {help}\n# TYPE {name} histogram\n");
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS_US.get(i).map_or("+Inf".to_string(), |&us| (us as f64 / 1e6).to_string());
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_sum {}", self.sum_us.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{name}_count {}", self.count.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// 固定桶的数值直方图，按 Prometheus 文本格式导出 (桶为累计计数)
+pub struct ValueHistogram {
+    bounds: &'static [f64],
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    // f64 的位模式，CAS 累加
+    sum_bits: AtomicU64,
+}
+
+const TRADE_VALUE_BUCKETS: [f64; 7] = [1.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0];
+
+impl Default for ValueHistogram {
+    fn default() -> Self {
+        Self::new(&TRADE_VALUE_BUCKETS)
+    }
+}
+
+impl ValueHistogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_bits: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if !value.is_finite() { return; }
+        let idx = self.bounds.iter().position(|&b| value <= b).unwrap_or(self.bounds.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum_bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+    }
+
+    /// Prometheus 文本格式 (含 HELP/TYPE 行)
+    pub fn render(&self, name: &str, help: &str) -> String {
+        use std::fmt::Write;
+        let mut out = format!("# HELP {name} {help}\n# TYPE {name} histogram\n");
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = self.bounds.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+            let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_sum {}", f64::from_bits(self.sum_bits.load(Ordering::Relaxed)));
+        let _ = writeln!(out, "{name}_count {}", self.count.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// 按整秒滚动的请求计数：速率取上一完整秒的请求数，无锁
+#[derive(Default)]
+pub struct RateWindow {
+    second: AtomicI64,
+    current: AtomicU64,
+    previous: AtomicU64,
+}
+
+impl RateWindow {
+    pub fn hit(&self, now_secs: i64) {
+        self.roll(now_secs);
+        self.current.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn per_sec(&self, now_secs: i64) -> u64 {
+        self.roll(now_secs);
+        self.previous.load(Ordering::Relaxed)
+    }
+
+    fn roll(&self, now_secs: i64) {
+        let second = self.second.load(Ordering::Relaxed);
+        if now_secs > second && self.second.compare_exchange(second, now_secs, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            let count = self.current.swap(0, Ordering::Relaxed);
+            // 中间有空闲的整秒时上一秒的请求数为 0
+            self.previous.store(if now_secs == second + 1 { count } else { 0 }, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 分别计数的接口类别
+#[derive(Clone, Copy)]
+pub enum Route {
+    Sell,
+    Buy,
+    Batch,
+    // 行情报价 (/api/market/prices)
+    Quote,
+    // 其余行情查询 (参数、健康度、有效库存、往返报价等)
+    Market,
+}
+
+impl Route {
+    pub const ALL: [Route; 5] = [Route::Sell, Route::Buy, Route::Batch, Route::Quote, Route::Market];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Route::Sell => "sell",
+            Route::Buy => "buy",
+            Route::Batch => "batch",
+            Route::Quote => "quote",
+            Route::Market => "market",
+        }
+    }
+}
+
+/// 按接口类别的请求计数，无锁
+#[derive(Default)]
+pub struct RouteCounters {
+    counts: [AtomicU64; Route::ALL.len()],
+}
+
+impl RouteCounters {
+    pub fn hit(&self, route: Route) {
+        self.counts[route as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, route: Route) -> u64 {
+        self.counts[route as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self, name: &str, help: &str) -> String {
+        use std::fmt::Write;
+        let mut out = format!("# HELP {name} {help}\n# TYPE {name} counter\n");
+        for route in Route::ALL {
+            let _ = writeln!(out, "{name}{{route=\"{}\"}} {}", route.label(), self.get(route));
+        }
+        out
+    }
 }
 
 #[derive(Clone)]
@@ -44,101 +271,909 @@ pub struct AppState {
     pub player_histories: Arc<RwLock<HashMap<String, PlayerSalesHistory>>>,
     pub http_client: reqwest::Client,
     pub env_cache: Arc<RwLock<Option<EnvCache>>>,
+    // 物品价格推送 (已构建好的报价消息)，供 WebSocket 连接共享
+    pub price_events: broadcast::Sender<ws::PriceUpdate>,
+    // (玩家, 物品) -> (最近买入, 最近卖出) 的毫秒时间戳，用于反套利冷却
+    pub trade_sides: Arc<RwLock<FxHashMap<(String, String), (i64, i64)>>>,
+    // 各物品最近一次推送出去的现价 (用于推送去抖)
+    pub published_prices: Arc<RwLock<FxHashMap<String, f64>>>,
+    // 最近一次计算出的物品价格 (计算时刻毫秒, 价格)，锁争用时降级使用
+    pub price_snapshot: Arc<RwLock<FxHashMap<String, (i64, MarketItemStatus)>>>,
+    // 物品熔断：item_id -> 解除时间 (秒)
+    pub item_breakers: Arc<RwLock<FxHashMap<String, i64>>>,
+    pub player_balances: Arc<RwLock<PlayerBalances>>,
+    // 最近分配的成交序号
+    pub trade_seq: Arc<AtomicU64>,
+    // Mojang 档案玩家名缓存 (trust_client_name 关闭时用于校验改名)
+    pub profile_names: Arc<RwLock<FxHashMap<String, String>>>,
+    // 已删除物品的归档 (含 n / iota 等累积状态)
+    pub archived_items: Arc<RwLock<Vec<MarketItem>>>,
+    // 物品展示价的指数移动平均
+    pub price_ema: Arc<RwLock<FxHashMap<String, f64>>>,
+    // 自上次快照以来发生过变更的部分
+    pub dirty: Arc<DirtyFlags>,
+    // 全局 Mojang 请求并发许可
+    pub mojang_limiter: Arc<Semaphore>,
+    // 最近一次生效的市场同步代次
+    pub sync_generation: Arc<AtomicU64>,
+    // 关闭前的排空阶段：置位后不再接受新的成交
+    pub draining: Arc<AtomicBool>,
+    // 只读模式 (紧急排查)：拒绝成交、同步与管理端修改，且不写任何快照
+    pub read_only: Arc<AtomicBool>,
+    // 定期采样的环境指数时间序列
+    pub env_history: Arc<RwLock<logic::environment::EnvHistory>>,
+}
+
+/// 各持久化部分的脏标记：修改时置位，快照写盘时清除
+#[derive(Default)]
+pub struct DirtyFlags {
+    pub market: AtomicBool,
+    pub players: AtomicBool,
+    pub balances: AtomicBool,
+    pub archive: AtomicBool,
+    pub trade_seq: AtomicBool,
+}
+
+/// 测试用句柄：捕获写入通道中的流水记录
+#[cfg(any(test, feature = "test-harness"))]
+pub struct TestHandles {
+    pub records: mpsc::Receiver<TransactionRecord>,
+}
+
+#[cfg(any(test, feature = "test-harness"))]
+impl AppState {
+    /// 构建纯内存的确定性状态 (不触碰磁盘、不访问 Mojang)，供集成测试驱动各处理器
+    pub fn new_for_test(mut config: AppConfig) -> (Self, TestHandles) {
+        config.is_online_mode = false;
+        let mojang_permits = config.mojang_max_concurrency.max(1);
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let state = AppState {
+            config: Arc::new(RwLock::new(config)),
+            holidays: Arc::new(RwLock::new(HashMap::new())),
+            tx,
+            history_cache: Arc::new(RwLock::new(VecDeque::new())),
+            market_cache: Arc::new(RwLock::new(Vec::new())),
+            metrics: Arc::new(SystemMetrics {
+                total_trades: AtomicU64::new(0),
+                write_failures: AtomicU64::new(0),
+                channel_dropped: AtomicU64::new(0),
+                writer_restarts: AtomicU64::new(0),
+                start_time: 0,
+                market_snapshot_latency: LatencyHistogram::default(),
+                trade_pricing_latency: LatencyHistogram::default(),
+                trade_value: ValueHistogram::default(),
+                request_rate: RateWindow::default(),
+                route_requests: RouteCounters::default(),
+            }),
+            player_histories: Arc::new(RwLock::new(HashMap::new())),
+            http_client: reqwest::Client::new(),
+            env_cache: Arc::new(RwLock::new(None)),
+            price_events: broadcast::channel(PRICE_EVENT_CAPACITY).0,
+            published_prices: Arc::new(RwLock::new(FxHashMap::default())),
+            trade_sides: Arc::new(RwLock::new(FxHashMap::default())),
+            price_snapshot: Arc::new(RwLock::new(FxHashMap::default())),
+            item_breakers: Arc::new(RwLock::new(FxHashMap::default())),
+            player_balances: Arc::new(RwLock::new(HashMap::new())),
+            trade_seq: Arc::new(AtomicU64::new(0)),
+            profile_names: Arc::new(RwLock::new(FxHashMap::default())),
+            archived_items: Arc::new(RwLock::new(Vec::new())),
+            price_ema: Arc::new(RwLock::new(FxHashMap::default())),
+            dirty: Arc::new(DirtyFlags::default()),
+            mojang_limiter: Arc::new(Semaphore::new(mojang_permits)),
+            sync_generation: Arc::new(AtomicU64::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            read_only: Arc::new(AtomicBool::new(false)),
+            env_history: Arc::new(RwLock::new(Default::default())),
+        };
+        (state, TestHandles { records: rx })
+    }
 }
 
 // =========================================================================
 // 1. 强化存储引擎 (Postcard)
 // =========================================================================
 
-struct Storage;
+pub struct Storage;
 impl Storage {
-    fn load<T: serde::de::DeserializeOwned>(file: &str) -> Option<T> {
-        fs::read(file).ok().and_then(|data| {
-            postcard::from_bytes(&data).ok()
+    /// 读取快照：不存在为 Ok(None)；存在但无法解析时返回错误，调用方不得用默认值覆盖
+    /// 解析后修正其中的非有限数值
+    fn load<T: Persisted>(file: &str) -> io::Result<Option<T>> {
+        let Some(data) = storage::backend().load(file)? else { return Ok(None) };
+        let mut value = Self::decode::<T>(file, &data)?;
+        let fixed = value.sanitize();
+        if fixed > 0 {
+            warn!("🧹 {} 中有 {} 处非有限数值 (NaN/∞)，已修正", file, fixed);
+        }
+        Ok(Some(value))
+    }
+
+    pub fn atomic_save<T: serde::Serialize>(file: &str, data: &T) -> io::Result<()> {
+        Self::save_encoded(file, &Self::encode(data)?)
+    }
+
+    /// 写入已编码的快照：调用方可在持锁期间只做编码，落盘放到锁外
+    pub fn save_encoded(file: &str, bytes: &[u8]) -> io::Result<()> {
+        storage::backend().save(file, bytes)
+    }
+
+    /// 文件头 (魔数 + 版本) + postcard 数据
+    pub fn encode<T: serde::Serialize>(data: &T) -> io::Result<Vec<u8>> {
+        let mut bytes = STORE_MAGIC.to_vec();
+        bytes.extend_from_slice(&STORE_FORMAT_VERSION.to_le_bytes());
+        postcard::to_extend(data, bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// 按文件头选择布局：当前版本直接解析，无文件头按 v0 解析后迁移，其余版本拒绝
+    /// 要求数据被完整消费，避免按错误布局“碰巧”解析成功
+    fn decode<T: Persisted>(file: &str, data: &[u8]) -> io::Result<T> {
+        fn parse<V: serde::de::DeserializeOwned>(payload: &[u8]) -> Result<V, String> {
+            match postcard::take_from_bytes::<V>(payload) {
+                Ok((value, [])) => Ok(value),
+                Ok((_, rest)) => Err(format!("末尾有 {} 字节无法解析", rest.len())),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+
+        let result = match data.strip_prefix(STORE_MAGIC.as_slice()) {
+            Some(rest) => match rest.split_first_chunk::<2>() {
+                Some((version, payload)) if u16::from_le_bytes(*version) == STORE_FORMAT_VERSION => parse::<T>(payload),
+                Some((version, _)) => Err(format!("不支持的格式版本 {} (当前程序为 {})", u16::from_le_bytes(*version), STORE_FORMAT_VERSION)),
+                None => Err("文件头不完整".to_string()),
+            },
+            None => parse::<T::V0>(data).map(|old| {
+                info!("📦 {} 为旧版 (v0) 格式，已迁移，下次保存时写入 v{}", file, STORE_FORMAT_VERSION);
+                T::from_v0(old)
+            }),
+        };
+        result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e)))
+    }
+
+    fn history_log_format(file: &str) -> io::Result<HistoryLogFormat> {
+        let mut f = match fs::File::open(file) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HistoryLogFormat::Missing),
+            Err(e) => return Err(e),
+        };
+        let mut head = Vec::with_capacity(HISTORY_LOG_HEADER.len());
+        f.by_ref().take(HISTORY_LOG_HEADER.len() as u64).read_to_end(&mut head)?;
+        Ok(match head.as_slice() {
+            [] => HistoryLogFormat::Empty,
+            h if h == HISTORY_LOG_HEADER => HistoryLogFormat::Current,
+            _ => HistoryLogFormat::Legacy,
         })
     }
 
-    fn atomic_save<T: serde::Serialize>(file: &str, data: &T) -> io::Result<()> {
-        let temp_path = format!("{}.tmp", file);
-        
-        let bytes = postcard::to_stdvec(data)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        
-        fs::write(&temp_path, bytes)?;
-        fs::rename(&temp_path, file)
+    /// 启动时把 v0 历史日志 (无文件头，postcard 裸记录首尾相接) 转换为当前的 COBS 分帧格式
+    /// 原文件保留为 `<file>.v0.bak`；中途无法解析时返回错误且不改动原文件
+    fn upgrade_history_log(file: &str) -> io::Result<()> {
+        use std::io::Write;
+        match Self::history_log_format(file)? {
+            HistoryLogFormat::Missing | HistoryLogFormat::Current => return Ok(()),
+            HistoryLogFormat::Empty => return fs::write(file, HISTORY_LOG_HEADER),
+            HistoryLogFormat::Legacy => {}
+        }
+
+        let started = Instant::now();
+        let temp_path = format!("{}.migrating", file);
+        let mut out = io::BufWriter::new(fs::File::create(&temp_path)?);
+        out.write_all(&HISTORY_LOG_HEADER)?;
+        let mut converted = 0usize;
+        let mut write_err = None;
+        let dropped = Self::read_legacy_history(file, |record| {
+            if write_err.is_some() { return; }
+            let result = postcard::to_stdvec_cobs(&record)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+                .and_then(|bytes| out.write_all(&bytes));
+            match result {
+                Ok(()) => converted += 1,
+                Err(e) => write_err = Some(e),
+            }
+        });
+        let dropped = match (dropped, write_err) {
+            (Ok(dropped), None) => dropped,
+            (Err(e), _) | (_, Some(e)) => {
+                drop(out);
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        };
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        let backup = format!("{}.v0.bak", file);
+        fs::rename(file, &backup)?;
+        fs::rename(&temp_path, file)?;
+        if dropped > 0 {
+            warn!("⚠️ 旧版历史日志末尾有 {} 字节不完整的记录，已丢弃", dropped);
+        }
+        warn!("📦 已将旧版历史日志转换为 v{} 格式：{} 条流水 (耗时 {:?})，原文件保留为 {}", STORE_FORMAT_VERSION, converted, started.elapsed(), backup);
+        Ok(())
+    }
+
+    /// 顺序读取 v0 历史日志并逐条回调，返回末尾被丢弃的不完整字节数
+    /// 单条记录不会超过一个读取块，积压超过一块仍无法解析即视为损坏
+    fn read_legacy_history(file: &str, mut on_record: impl FnMut(TransactionRecord)) -> io::Result<usize> {
+        let mut f = fs::File::open(file)?;
+        let mut buf: Vec<u8> = Vec::new();
+        let mut start = 0usize;
+        let mut offset = 0u64;
+        let mut chunk = vec![0u8; HISTORY_READ_CHUNK];
+        loop {
+            match postcard::take_from_bytes::<v0::TransactionRecord>(&buf[start..]) {
+                Ok((record, rest)) => {
+                    let used = buf.len() - start - rest.len();
+                    start += used;
+                    offset += used as u64;
+                    on_record(record.into());
+                }
+                Err(postcard::Error::DeserializeUnexpectedEnd) if buf.len() - start <= HISTORY_READ_CHUNK => {
+                    buf.drain(..start);
+                    start = 0;
+                    let n = f.read(&mut chunk)?;
+                    if n == 0 { return Ok(buf.len()); }
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: 旧版历史日志在偏移 {} 处无法解析: {}", file, offset, e),
+                )),
+            }
+        }
+    }
+
+    /// 从追加日志尾部倒序读取最近的流水 (COBS 分帧，0x00 为帧尾)
+    /// 达到 `limit` 条或超出 `budget` 即停止，返回结果按时间正序排列
+    fn load_history_tail(file: &str, limit: usize, budget: Duration) -> VecDeque<TransactionRecord> {
+        let started = Instant::now();
+        let mut records = VecDeque::new();
+
+        // 启动时旧版日志已被转换；转换失败时不按旧布局加载
+        if matches!(Self::history_log_format(file), Ok(HistoryLogFormat::Legacy)) {
+            warn!("⚠️ {} 为旧版格式，本次不加载历史流水", file);
+            return records;
+        }
+
+        let Ok(mut f) = fs::File::open(file) else { return records; };
+        let Ok(mut pos) = f.seek(SeekFrom::End(0)) else { return records; };
+
+        // pending: 尚未解码的字节 (文件顺序)，其末尾总是某帧的结束位置
+        let mut pending: Vec<u8> = Vec::new();
+        let mut chunk = vec![0u8; HISTORY_READ_CHUNK];
+        let mut first_chunk = true;
+        let mut corrupted = 0usize;
+
+        while records.len() < limit {
+            if started.elapsed() >= budget {
+                warn!("⏱️ 历史加载超出预算 ({:?})，跳过约 {} 字节的较早流水", budget, pos + pending.len() as u64);
+                break;
+            }
+
+            // 1. 在已读取的字节中尽可能多地切出完整帧
+            let boundary = pending.len().checked_sub(1)
+                .and_then(|end| pending[..end].iter().rposition(|&b| b == 0));
+            let frame_start = match boundary {
+                Some(i) => i + 1,
+                None if pos == 0 && !pending.is_empty() => 0,
+                None if pos == 0 => break,
+                None => {
+                    // 2. 需要更多字节：向前读取一个块
+                    let len = (pos as usize).min(HISTORY_READ_CHUNK);
+                    pos -= len as u64;
+                    if f.seek(SeekFrom::Start(pos)).and_then(|_| f.read_exact(&mut chunk[..len])).is_err() {
+                        error!("🚨 历史文件读取失败，已恢复 {} 条", records.len());
+                        break;
+                    }
+                    let mut merged = chunk[..len].to_vec();
+                    merged.append(&mut pending);
+                    pending = merged;
+
+                    // 丢弃文件末尾未写完整的半帧
+                    if first_chunk {
+                        match pending.iter().rposition(|&b| b == 0) {
+                            Some(i) => { pending.truncate(i + 1); first_chunk = false; }
+                            None if pos == 0 => pending.clear(),
+                            None => {}
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let frame = pending.split_off(frame_start);
+            if pos == 0 && frame_start == 0 && frame == HISTORY_LOG_HEADER { break; }
+            // 旧版本写入的帧缺少末尾字段，按对应的旧布局 (其余字段相同) 解析
+            match TransactionRecord::decode_cobs(&frame) {
+                Ok(record) => records.push_front(record),
+                Err(_) => corrupted += 1,
+            }
+        }
+
+        if corrupted > 0 {
+            warn!("⚠️ 历史日志中有 {} 帧无法解析，已跳过", corrupted);
+        }
+        info!("📜 已从历史日志恢复 {} 条流水 (耗时 {:?})", records.len(), started.elapsed());
+        records
     }
 }
 
+enum HistoryLogFormat {
+    Missing,
+    Empty,
+    /// 带文件头的 COBS 分帧日志
+    Current,
+    /// v0：无文件头，postcard 裸记录首尾相接
+    Legacy,
+}
+
 // =========================================================================
 // 2. 批量持久化核心 (Batch Writer)
 // =========================================================================
 
+/// 启动 (受监督的) 流水写入任务；persist_history 关闭时丢弃接收端，返回立即结束的句柄
+fn start_history_writer(state: &AppState, rx: mpsc::Receiver<TransactionRecord>, path: String) -> task::JoinHandle<()> {
+    if !state.config.read().persist_history {
+        info!("📭 persist_history 已关闭：成交流水只保留在内存缓存中");
+        return tokio::spawn(async {});
+    }
+    // 只读模式不会产生成交，也不创建或补写流水日志
+    if state.read_only.load(Ordering::Relaxed) {
+        return tokio::spawn(async {});
+    }
+    // 接收端放在共享锁里：写入任务 panic 后由监督任务用同一通道重启
+    let rx = Arc::new(tokio::sync::Mutex::new(rx));
+    let (history_cache, writer_config) = (state.history_cache.clone(), state.config.clone());
+    let writer_metrics = state.metrics.clone();
+    if writer_config.read().storage_backend != StorageBackendKind::File {
+        return tokio::spawn(supervise_writer(
+            move || backend_writer_task(rx.clone(), history_cache.clone(), writer_metrics.clone(), writer_config.clone(), path.clone()),
+            state.metrics.clone(),
+            state.config.clone(),
+            WRITER_RESTART_BACKOFF,
+        ));
+    }
+    tokio::spawn(supervise_writer(
+        move || background_writer_task(rx.clone(), history_cache.clone(), writer_metrics.clone(), writer_config.clone(), path.clone()),
+        state.metrics.clone(),
+        state.config.clone(),
+        WRITER_RESTART_BACKOFF,
+    ))
+}
+
+/// 监督流水写入任务：正常结束 (通道关闭) 时返回；返回 I/O 错误 (如日志文件打不开) 或 panic 时
+/// 记录原因并按指数退避重启。release 配置为 panic = 'abort'，panic 会直接终止进程，
+/// 因此写入任务的可恢复故障一律以 Err 返回，而不是 panic
+async fn supervise_writer<F, Fut>(mut start: F, metrics: Arc<SystemMetrics>, config: Arc<RwLock<AppConfig>>, backoff: Duration)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = io::Result<()>> + Send + 'static,
+{
+    let mut delay = backoff;
+    loop {
+        let cause = match tokio::spawn(start()).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => match e.try_into_panic() {
+                Ok(payload) => payload.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "未知原因".into()),
+                Err(e) => e.to_string(),
+            },
+        };
+        metrics.writer_restarts.fetch_add(1, Ordering::Relaxed);
+        error!("{}", config.read().log_lang.render(Msg::WriterRestarted, &[&cause, &format!("{:?}", delay)]));
+        time::sleep(delay).await;
+        delay = (delay * 2).min(WRITER_RESTART_BACKOFF_MAX);
+    }
+}
+
 async fn background_writer_task(
-    mut rx: mpsc::Receiver<TransactionRecord>,
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<TransactionRecord>>>,
     history_cache: Arc<RwLock<VecDeque<TransactionRecord>>>,
     metrics: Arc<SystemMetrics>,
-) {
+    config: Arc<RwLock<AppConfig>>,
+    path: String,
+) -> io::Result<()> {
     use tokio::io::AsyncWriteExt;
     
-    let file = match tokio::fs::OpenOptions::new().create(true).append(true).open(HISTORY_FILE).await {
-        Ok(f) => f,
-        Err(e) => { error!("🚨 历史文件打开失败: {}", e); return; }
-    };
+    // 持有期间独占接收端；panic 时锁随栈展开释放，重启的任务可继续接收
+    let mut rx = rx.lock().await;
+    let opened = async {
+        let mut f = tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await?;
+        // 新建的日志先写文件头
+        if f.metadata().await?.len() == 0 {
+            f.write_all(&HISTORY_LOG_HEADER).await?;
+        }
+        io::Result::Ok(f)
+    }.await;
+    // 打开失败交给监督任务退避重试，期间流水积压在通道中
+    let file = opened.map_err(|e| io::Error::new(e.kind(), format!("历史文件 {} 打开失败: {}", path, e)))?;
     
     let mut writer = tokio::io::BufWriter::with_capacity(256 * 1024, file);
     let mut batch = Vec::with_capacity(BATCH_SIZE);
     let mut flush_interval = time::interval(Duration::from_millis(500));
 
+    // 通道关闭需显式匹配 None：定时刷盘分支永不失效，select 的 else 分支不会触发
     loop {
         tokio::select! {
-            Some(record) = rx.recv() => {
-                {
-                    let mut cache = history_cache.write();
-                    cache.push_back(record.clone());
-                    if cache.len() > MAX_CACHE_SIZE { cache.pop_front(); }
+            record = rx.recv() => match record {
+                Some(record) => {
+                    cache_record(&history_cache, &record);
+                    batch.push(record);
+                    if batch.len() >= BATCH_SIZE {
+                        flush_batch(&mut batch, &mut writer, &metrics, &config).await;
+                    }
                 }
-
-                batch.push(record);
-                if batch.len() >= BATCH_SIZE {
-                    flush_batch(&mut batch, &mut writer, &metrics).await;
+                None => {
+                    info!("👋 写入通道关闭，正在保存剩余 {} 条记录...", batch.len());
+                    flush_batch(&mut batch, &mut writer, &metrics, &config).await;
+                    let _ = writer.flush().await;
+                    break;
                 }
-            }
+            },
             _ = flush_interval.tick() => {
                 if !batch.is_empty() {
-                    flush_batch(&mut batch, &mut writer, &metrics).await;
+                    flush_batch(&mut batch, &mut writer, &metrics, &config).await;
                 }
             }
-            else => {
-                info!("👋 写入通道关闭，正在保存剩余 {} 条记录...", batch.len());
-                flush_batch(&mut batch, &mut writer, &metrics).await;
-                let _ = writer.flush().await;
-                break;
+        }
+    }
+    Ok(())
+}
+
+/// 启动时把死信日志中的流水 (上次运行因通道已满未能落盘) 重新送入写入通道
+/// 每次最多重放 dead_letter_replay_max 条 (最早的优先)；全部送出后清空死信日志，
+/// 其余部分 (超出上限或通道不可用) 写回死信日志留待下次启动。返回送出的条数
+async fn replay_dead_letters(state: &AppState) -> usize {
+    let (path, max) = {
+        let config = state.config.read();
+        (config.dead_letter_path.clone(), config.dead_letter_replay_max)
+    };
+    if path.is_empty() || max == 0 || !std::path::Path::new(&path).exists() {
+        return 0;
+    }
+    let mut pending = match FileBackend.load_history(&path, usize::MAX) {
+        Ok(records) => records,
+        Err(e) => { warn!("⚠️ 死信日志 {} 读取失败，本次不重放: {:?}", path, e); return 0; }
+    };
+
+    let total = pending.len();
+    let mut replayed = 0usize;
+    while replayed < max {
+        let Some(record) = pending.pop_front() else { break };
+        if let Err(e) = state.tx.send(record).await {
+            pending.push_front(e.0);
+            warn!("⚠️ 写入通道不可用，死信重放中止");
+            break;
+        }
+        replayed += 1;
+    }
+
+    let rewritten = if pending.is_empty() {
+        fs::File::create(&path).map(|_| ())
+    } else {
+        let temp_path = format!("{}.tmp", path);
+        let _ = fs::remove_file(&temp_path);
+        FileBackend.append(&temp_path, pending.make_contiguous()).and_then(|_| fs::rename(&temp_path, &path))
+    };
+    if let Err(e) = rewritten {
+        // 已送出的记录可能在下次启动时再次重放
+        error!("🚨 死信日志 {} 更新失败: {:?}", path, e);
+    }
+    if total > 0 {
+        info!("📬 已从死信日志重放 {} / {} 条流水", replayed, total);
+    }
+    replayed
+}
+
+fn cache_record(history_cache: &RwLock<VecDeque<TransactionRecord>>, record: &TransactionRecord) {
+    let mut cache = history_cache.write();
+    cache.push_back(record.clone());
+    if cache.len() > MAX_CACHE_SIZE { cache.pop_front(); }
+}
+
+/// 非文件后端的流水写入：攒满一批或每 500ms 在阻塞线程池中追加一次 (fsync_policy 仅对文件后端生效)
+async fn backend_writer_task(
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<TransactionRecord>>>,
+    history_cache: Arc<RwLock<VecDeque<TransactionRecord>>>,
+    metrics: Arc<SystemMetrics>,
+    config: Arc<RwLock<AppConfig>>,
+    log: String,
+) -> io::Result<()> {
+    let mut rx = rx.lock().await;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut flush_interval = time::interval(Duration::from_millis(500));
+
+    loop {
+        let closed = tokio::select! {
+            record = rx.recv() => match record {
+                Some(record) => {
+                    cache_record(&history_cache, &record);
+                    batch.push(record);
+                    if batch.len() < BATCH_SIZE { continue; }
+                    false
+                }
+                None => true,
+            },
+            _ = flush_interval.tick() => false,
+        };
+        if closed {
+            info!("👋 写入通道关闭，正在保存剩余 {} 条记录...", batch.len());
+        }
+        if !batch.is_empty() {
+            let records = std::mem::take(&mut batch);
+            let count = records.len() as u64;
+            let log = log.clone();
+            let result = task::spawn_blocking(move || storage::backend().append(&log, &records)).await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            if let Err(e) = result {
+                metrics.write_failures.fetch_add(count, Ordering::Relaxed);
+                error!("{}", config.read().log_lang.render(Msg::BatchWriteFailed, &[&count, &format!("{:?}", e)]));
             }
         }
+        if closed { break; }
     }
+    Ok(())
+}
+
+/// 流水日志的写入目标：写帧、刷到操作系统、fsync 到磁盘
+trait HistorySink {
+    async fn write_frame(&mut self, bytes: &[u8]) -> io::Result<()>;
+    async fn flush_os(&mut self) -> io::Result<()>;
+    async fn sync_disk(&mut self) -> io::Result<()>;
 }
 
+impl HistorySink for tokio::io::BufWriter<tokio::fs::File> {
+    async fn write_frame(&mut self, bytes: &[u8]) -> io::Result<()> {
+        tokio::io::AsyncWriteExt::write_all(self, bytes).await
+    }
+    async fn flush_os(&mut self) -> io::Result<()> {
+        tokio::io::AsyncWriteExt::flush(self).await
+    }
+    async fn sync_disk(&mut self) -> io::Result<()> {
+        self.get_ref().sync_all().await
+    }
+}
+
+/// fsync_policy 与 log_lang 每批读取一次，运行期修改配置后下一批即生效
 async fn flush_batch(
     batch: &mut Vec<TransactionRecord>,
-    writer: &mut tokio::io::BufWriter<tokio::fs::File>,
-    metrics: &Arc<SystemMetrics>
+    writer: &mut impl HistorySink,
+    metrics: &Arc<SystemMetrics>,
+    config: &RwLock<AppConfig>,
 ) {
-    use tokio::io::AsyncWriteExt;
+    let (fsync_policy, lang) = { let c = config.read(); (c.fsync_policy, c.log_lang) };
     for record in batch.drain(..) {
-        if let Ok(bytes) = postcard::to_stdvec(&record) {
-            if let Err(e) = writer.write_all(&bytes).await {
+        if let Ok(bytes) = postcard::to_stdvec_cobs(&record) {
+            if let Err(e) = writer.write_frame(&bytes).await {
                 metrics.write_failures.fetch_add(1, Ordering::Relaxed);
-                error!("❌ 批量写入中单条记录失败: {:?}", e);
+                error!("{}", lang.render(Msg::RecordWriteFailed, &[&format!("{:?}", e)]));
+                continue;
+            }
+            if fsync_policy == FsyncPolicy::PerRecord {
+                sync_history(writer, metrics, lang).await;
+            }
+        }
+    }
+    let _ = writer.flush_os().await;
+    if fsync_policy == FsyncPolicy::PerBatch {
+        sync_history(writer, metrics, lang).await;
+    }
+}
+
+async fn sync_history(writer: &mut impl HistorySink, metrics: &Arc<SystemMetrics>, lang: LogLang) {
+    let result = match writer.flush_os().await {
+        Ok(()) => writer.sync_disk().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        metrics.write_failures.fetch_add(1, Ordering::Relaxed);
+        error!("{}", lang.render(Msg::FsyncFailed, &[&format!("{:?}", e)]));
+    }
+}
+
+// =========================================================================
+// 3. Lambda 自动调参任务
+// =========================================================================
+
+const LAMBDA_AUTOTUNE_PERIOD_MS: i64 = 86_400_000;
+
+/// 距下次调参还需等待的时间 (已超期则立即执行，时钟回拨时最多等一个周期)
+fn autotune_delay(last_run_ms: i64, now_ms: i64) -> Duration {
+    let wait_ms = (last_run_ms + LAMBDA_AUTOTUNE_PERIOD_MS - now_ms).clamp(0, LAMBDA_AUTOTUNE_PERIOD_MS);
+    Duration::from_millis(wait_ms as u64)
+}
+
+async fn lambda_autotune_task(state: AppState) {
+    let save_last_run = |ts: i64| {
+        if let Err(e) = Storage::atomic_save(AUTOTUNE_STATE_FILE, &ts) {
+            warn!("⚠️ 调参时间保存失败: {:?}", e);
+        }
+    };
+    // 上次执行时间持久化，频繁重启不会让 24 小时周期一直从零开始；首次运行以当前时间为起点
+    let mut last_run_ms = match Storage::load::<i64>(AUTOTUNE_STATE_FILE) {
+        Ok(Some(ts)) => ts,
+        other => {
+            if let Err(e) = other {
+                warn!("⚠️ 调参时间读取失败，从现在开始计时: {:?}", e);
+            }
+            let now = chrono::Utc::now().timestamp_millis();
+            save_last_run(now);
+            now
+        }
+    };
+
+    loop {
+        time::sleep(autotune_delay(last_run_ms, chrono::Utc::now().timestamp_millis())).await;
+        last_run_ms = chrono::Utc::now().timestamp_millis();
+        save_last_run(last_run_ms);
+        let config = state.config.read().clone();
+        if !config.lambda_autotune_enabled { continue; }
+        run_lambda_autotune(&state, &config, last_run_ms);
+    }
+}
+
+/// 按过去 24 小时的成交量调整设置了目标量的物品的 lambda，返回调整的物品数
+/// 市场状态标记为脏由定期快照落盘，并作废这些物品的价格快照
+fn run_lambda_autotune(state: &AppState, config: &AppConfig, now_ms: i64) -> usize {
+    let since_ms = now_ms - 86_400_000;
+    let tuned_ids: Vec<String> = {
+        let histories = state.player_histories.read();
+        let mut market = state.market_cache.write();
+        market.iter_mut().filter_map(|item| {
+            let target = item.target_daily_volume?;
+            let volume = logic::tuning::realized_volume(&histories, &item.id, since_ms);
+            let tuned = logic::tuning::adjust_lambda(item.lambda, volume, target, config);
+            info!("🎛️ {} lambda {:.6} -> {:.6} (成交 {:.1} / 目标 {:.1})", item.id, item.lambda, tuned, volume, target);
+            item.lambda = tuned;
+            Some(item.id.clone())
+        }).collect()
+    };
+    if !tuned_ids.is_empty() {
+        state.dirty.market.store(true, Ordering::Relaxed);
+        let mut snapshot = state.price_snapshot.write();
+        for id in &tuned_ids { snapshot.remove(id); }
+    }
+    tuned_ids.len()
+}
+
+// =========================================================================
+// 3.1 定期快照
+// =========================================================================
+
+async fn periodic_snapshot_task(state: AppState) {
+    loop {
+        let secs = state.config.read().snapshot_interval_secs;
+        // 关闭时仍定期检查配置，热更新开启后生效
+        time::sleep(Duration::from_secs(if secs == 0 { 60 } else { secs })).await;
+        if secs == 0 { continue; }
+        let written = write_dirty_snapshots(&state, std::path::Path::new("."));
+        if !written.is_empty() {
+            info!("💾 定期快照已保存: {:?}", written);
+        }
+    }
+}
+
+/// 按 env_history_interval_secs 采样当前环境指数 (关闭时仍定期检查配置)
+async fn env_history_task(state: AppState) {
+    loop {
+        let secs = state.config.read().env_history_interval_secs;
+        time::sleep(Duration::from_secs(if secs == 0 { 60 } else { secs })).await;
+        if secs == 0 { continue; }
+        let config = state.config.read().clone();
+        let (index, _) = logic::calculate_current_env_index(&config, &state.holidays.read(), &state.env_cache);
+        state.env_history.write().push(chrono::Utc::now().timestamp(), index, &config);
+    }
+}
+
+/// 只保存自上次快照以来有变更的部分，返回实际写入的文件；写盘失败的部分保持脏标记，下次重试
+fn write_dirty_snapshots(state: &AppState, dir: &std::path::Path) -> Vec<&'static str> {
+    fn save_if_dirty(flag: &AtomicBool, dir: &std::path::Path, file: &'static str, lang: LogLang, encode: impl FnOnce() -> io::Result<Vec<u8>>) -> bool {
+        if !flag.swap(false, Ordering::AcqRel) {
+            tracing::debug!("💤 {} 无变更，跳过快照", file);
+            return false;
+        }
+        match encode().and_then(|bytes| Storage::save_encoded(&dir.join(file).to_string_lossy(), &bytes)) {
+            Ok(()) => true,
+            Err(e) => {
+                flag.store(true, Ordering::Release);
+                warn!("{}", lang.render(Msg::SnapshotFailed, &[&file, &format!("{:?}", e)]));
+                false
             }
         }
     }
-    let _ = writer.flush().await;
+
+    let dirty = &state.dirty;
+    let lang = state.config.read().log_lang;
+    let mut written = Vec::new();
+    if save_if_dirty(&dirty.market, dir, MARKET_DATA_FILE, lang, || Storage::encode(&*state.market_cache.read())) {
+        written.push(MARKET_DATA_FILE);
+    }
+    if save_if_dirty(&dirty.players, dir, PLAYER_DATA_FILE, lang, || Storage::encode(&*state.player_histories.read())) {
+        written.push(PLAYER_DATA_FILE);
+    }
+    if save_if_dirty(&dirty.balances, dir, BALANCES_FILE, lang, || Storage::encode(&*state.player_balances.read())) {
+        written.push(BALANCES_FILE);
+    }
+    if save_if_dirty(&dirty.archive, dir, ARCHIVED_ITEMS_FILE, lang, || Storage::encode(&*state.archived_items.read())) {
+        written.push(ARCHIVED_ITEMS_FILE);
+    }
+    if save_if_dirty(&dirty.trade_seq, dir, TRADE_SEQ_FILE, lang, || Storage::encode(&state.trade_seq.load(Ordering::Relaxed))) {
+        written.push(TRADE_SEQ_FILE);
+    }
+    written
 }
 
 // =========================================================================
-// 3. 入口与生命周期
+// 4. 入口与生命周期
 // =========================================================================
 
+/// 所有路由共用的外层中间件：响应版本整形、400/413 错误信封、请求体上限、CORS 与请求超时
+fn with_common_layers(app: Router<AppState>, max_body_bytes: usize) -> Router<AppState> {
+    app.layer(middleware::from_fn(api::shape_response_version))
+        .layer(middleware::map_response(api::normalize_json_rejection))
+        .layer(middleware::map_response(api::normalize_payload_too_large))
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(CorsLayer::permissive())
+        .layer(TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(10)))
+}
+
+/// 启动时的成交序号：取持久化的计数与历史流水中最大序号的较大者 (异常退出时计数可能未保存)
+fn initial_trade_seq(saved: Option<u64>, history: &VecDeque<TransactionRecord>) -> u64 {
+    let from_history = history.iter().map(|r| r.seq).max().unwrap_or(0);
+    saved.unwrap_or(0).max(from_history)
+}
+
+/// 启动时检查明显错误的配置：默认仅告警，strict_config_validation 时拒绝启动
+fn validate_config_or_exit(config: &AppConfig) {
+    if cfg!(not(feature = "scripting")) && !config.pricing_script_path.is_empty() {
+        warn!("⚠️ 配置了 pricing_script_path 但未启用 scripting 特性，定价脚本不会执行");
+    }
+    let warnings: Vec<String> = [
+        logic::PricingEngine::recovery_warning(config),
+        logic::PricingEngine::exp_table_warning(config),
+    ].into_iter().flatten().collect();
+    if warnings.is_empty() { return; }
+    if config.strict_config_validation {
+        for warning in &warnings {
+            error!("🚨 配置校验失败: {}", warning);
+        }
+        std::process::exit(1);
+    }
+    for warning in &warnings {
+        warn!("⚠️ 配置可疑: {}", warning);
+    }
+}
+
+/// 启动时读取文件后端的历史流水：旧版日志先原地转换为当前格式；
+/// 只读模式下不转换 (也不为空文件补写文件头)，旧版日志直接在内存中解析
+fn load_startup_history(file: &str, read_only: bool, budget: Duration) -> io::Result<VecDeque<TransactionRecord>> {
+    if !read_only {
+        Storage::upgrade_history_log(file)?;
+    } else if matches!(Storage::history_log_format(file)?, HistoryLogFormat::Legacy) {
+        let mut records = VecDeque::new();
+        Storage::read_legacy_history(file, |record| {
+            records.push_back(record);
+            if records.len() > MAX_CACHE_SIZE { records.pop_front(); }
+        })?;
+        warn!("⚠️ {} 为旧版格式，只读模式下未转换，已在内存中读取 {} 条流水", file, records.len());
+        return Ok(records);
+    }
+    Ok(Storage::load_history_tail(file, MAX_CACHE_SIZE, budget))
+}
+
+/// 读取配置；首次运行 (配置文件不存在) 时写出默认配置并提示运维检查关键项
+/// 只读模式下不写出，直接按默认配置运行
+fn load_config_first_run(file: &str, read_only: bool) -> AppConfig {
+    if let Some(config) = load_or_exit::<AppConfig>(file) {
+        return config;
+    }
+    let config = AppConfig::default();
+    if read_only {
+        warn!("⚠️ 未找到 {}，只读模式下不写出默认配置，按默认值运行", file);
+        return config;
+    }
+    match Storage::atomic_save(file, &config) {
+        Ok(()) => log_default_config_banner(file, &config),
+        Err(e) => warn!("⚠️ 首次运行写出默认配置 {} 失败: {:?}", file, e),
+    }
+    config
+}
+
+fn log_default_config_banner(file: &str, config: &AppConfig) {
+    warn!("==============================================================");
+    warn!("🆕 未找到 {}，已按默认值生成，请检查以下关键项后重启:", file);
+    warn!("   端口 port = {}", config.port);
+    warn!("   正版校验 is_online_mode = {}", config.is_online_mode);
+    warn!("   管理令牌 admin_token = {}", if config.admin_token.is_empty() { "(未设置，/api/admin/* 一律返回 401)" } else { "(已设置)" });
+    warn!("==============================================================");
+}
+
+/// `--write-default-config`：写出默认配置后退出 (已存在时拒绝覆盖)
+fn write_default_config_and_exit(file: &str) -> ! {
+    if fs::metadata(file).is_ok() {
+        error!("🚨 {} 已存在，为避免覆盖现有配置未写入", file);
+        std::process::exit(1);
+    }
+    let config = AppConfig::default();
+    if let Err(e) = Storage::atomic_save(file, &config) {
+        error!("🚨 默认配置写入失败: {:?}", e);
+        std::process::exit(1);
+    }
+    log_default_config_banner(file, &config);
+    std::process::exit(0);
+}
+
+/// 读取启动快照；文件存在但无法解析时拒绝启动，避免随后的保存用默认值覆盖原有数据
+fn load_or_exit<T: Persisted>(file: &str) -> Option<T> {
+    match Storage::load(file) {
+        Ok(value) => value,
+        Err(e) => {
+            error!("🚨 {} 存在但无法读取或解析 ({})，为避免覆盖原有数据已拒绝启动，请修复或移走该文件", file, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// 从 CSV 目录读取初始物品；文件不存在时为空，格式错误的行告警后跳过
+fn load_catalog_csv(path: &str, config: &AppConfig) -> Vec<MarketItem> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            warn!("⚠️ 目录文件 {} 读取失败: {}", path, e);
+            return Vec::new();
+        }
+    };
+
+    let mut items: Vec<MarketItem> = Vec::new();
+    for (line_no, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        // 跳过空行、注释与表头
+        if line.is_empty() || line.starts_with('#') || (line_no == 1 && line.starts_with("id,")) {
+            continue;
+        }
+        match parse_catalog_row(line, config) {
+            Ok(item) if items.iter().any(|i| i.id == item.id) => warn!("⚠️ {}:{} 物品 {} 重复，已跳过", path, line_no, item.id),
+            Ok(item) => items.push(item),
+            Err(reason) => warn!("⚠️ {}:{} {}，已跳过", path, line_no, reason),
+        }
+    }
+    items
+}
+
+fn parse_catalog_row(line: &str, config: &AppConfig) -> Result<MarketItem, String> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [id, name, base_price, lambda, n, iota] = fields[..] else {
+        return Err(format!("应为 6 列，实际 {} 列", fields.len()));
+    };
+    if id.is_empty() {
+        return Err("物品 ID 为空".into());
+    }
+    let number = |column: &str, value: &str| value.parse::<f64>().ok().filter(|v| v.is_finite())
+        .ok_or_else(|| format!("{} 不是有限数值: {:?}", column, value));
+    let item = MarketItem {
+        id: id.into(),
+        name: name.into(),
+        base_price: number("base_price", base_price)?,
+        lambda: number("lambda", lambda)?,
+        n: number("n", n)?,
+        iota: number("iota", iota)?,
+        ..Default::default()
+    };
+    // 与同步接口相同的参数约束
+    if item.base_price <= 0.0 {
+        return Err(format!("base_price 必须为正: {}", item.base_price));
+    }
+    if !(item.lambda > 0.0 && item.lambda <= config.lambda_max) {
+        return Err(format!("lambda 不在 (0, {}] 范围内: {}", config.lambda_max, item.lambda));
+    }
+    Ok(item)
+}
+
+/// 环境变量为 1 / true / yes 时以只读模式启动
+fn read_only_requested() -> bool {
+    std::env::var(READ_ONLY_ENV)
+        .is_ok_and(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
@@ -148,15 +1183,58 @@ async fn main() {
         total_trades: AtomicU64::new(0),
         write_failures: AtomicU64::new(0),
         channel_dropped: AtomicU64::new(0),
+        writer_restarts: AtomicU64::new(0),
         start_time: Local::now().timestamp(),
+        market_snapshot_latency: LatencyHistogram::default(),
+        trade_pricing_latency: LatencyHistogram::default(),
+        trade_value: ValueHistogram::default(),
+        request_rate: RateWindow::default(),
+        route_requests: RouteCounters::default(),
     });
 
+    let read_only = read_only_requested();
+    if read_only {
+        warn!("🛑🛑🛑 只读模式已开启 ({}): 拒绝成交、市场同步与管理端修改，不写任何快照 🛑🛑🛑", READ_ONLY_ENV);
+    }
+
     // --- 数据加载阶段 ---
-    let config_data = Storage::load::<AppConfig>(CONFIG_FILE).unwrap_or_default();
-    let initial_history = Storage::load::<VecDeque<TransactionRecord>>(HISTORY_FILE).unwrap_or_default();
+    if std::env::args().any(|arg| arg == "--write-default-config") {
+        write_default_config_and_exit(CONFIG_FILE);
+    }
+    let config_data = load_config_first_run(CONFIG_FILE, read_only);
+    validate_config_or_exit(&config_data);
+    if config_data.admin_token.is_empty() {
+        warn!("🔒 未配置 admin_token，管理接口 /api/admin/* 已关闭");
+    }
+    let mojang_permits = config_data.mojang_max_concurrency.max(1);
+    match storage::open_backend(&config_data, read_only) {
+        Ok(backend) => storage::install(backend),
+        Err(e) => {
+            error!("🚨 存储后端打开失败: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let initial_history = if config_data.storage_backend == StorageBackendKind::File {
+        let budget = Duration::from_millis(config_data.startup_history_load_budget_ms);
+        load_startup_history(HISTORY_FILE, read_only, budget).unwrap_or_else(|e| {
+            error!("🚨 历史日志转换失败 ({})，为避免覆盖原有数据已拒绝启动", e);
+            std::process::exit(1);
+        })
+    } else {
+        storage::backend().load_history(HISTORY_FILE, MAX_CACHE_SIZE).unwrap_or_else(|e| {
+            error!("🚨 历史流水读取失败 ({})，已拒绝启动", e);
+            std::process::exit(1);
+        })
+    };
     
     // [修复] 加载上次关闭时的市场状态（包含价格、热度等）
-    let initial_market = Storage::load::<Vec<MarketItem>>(MARKET_DATA_FILE).unwrap_or_default();
+    let mut initial_market = load_or_exit::<Vec<MarketItem>>(MARKET_DATA_FILE).unwrap_or_default();
+    if initial_market.is_empty() && !config_data.catalog_seed_path.is_empty() {
+        initial_market = load_catalog_csv(&config_data.catalog_seed_path, &config_data);
+        if !initial_market.is_empty() {
+            info!("🌱 已从 {} 播种 {} 个物品", config_data.catalog_seed_path, initial_market.len());
+        }
+    }
     if initial_market.is_empty() {
         warn!("⚠️ 未找到市场状态文件或为空，将使用默认初始化 (价格可能重置)");
     } else {
@@ -164,7 +1242,9 @@ async fn main() {
     }
 
     // [修复] 加载环境数据
-    let initial_env = Storage::load::<Option<EnvCache>>(ENV_DATA_FILE).unwrap_or(None);
+    let initial_env = load_or_exit::<Option<EnvCache>>(ENV_DATA_FILE).flatten();
+
+    let trade_seq = initial_trade_seq(load_or_exit(TRADE_SEQ_FILE), &initial_history);
 
     let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
     
@@ -176,32 +1256,41 @@ async fn main() {
         // [修改] 使用加载的数据初始化
         market_cache: Arc::new(RwLock::new(initial_market)),
         metrics: metrics.clone(),
-        player_histories: Arc::new(RwLock::new(Storage::load(PLAYER_DATA_FILE).unwrap_or_default())),
+        player_histories: Arc::new(RwLock::new(load_or_exit(PLAYER_DATA_FILE).unwrap_or_default())),
         http_client: reqwest::Client::builder()
             .timeout(Duration::from_secs(5))
             .build()
             .expect("HTTP Client 构建失败"),
         // [修改] 使用加载的数据初始化
         env_cache: Arc::new(RwLock::new(initial_env)),
+        price_events: broadcast::channel(PRICE_EVENT_CAPACITY).0,
+        published_prices: Arc::new(RwLock::new(FxHashMap::default())),
+        trade_sides: Arc::new(RwLock::new(FxHashMap::default())),
+        price_snapshot: Arc::new(RwLock::new(FxHashMap::default())),
+        item_breakers: Arc::new(RwLock::new(FxHashMap::default())),
+        player_balances: Arc::new(RwLock::new(load_or_exit(BALANCES_FILE).unwrap_or_default())),
+        trade_seq: Arc::new(AtomicU64::new(trade_seq)),
+        profile_names: Arc::new(RwLock::new(FxHashMap::default())),
+        archived_items: Arc::new(RwLock::new(load_or_exit(ARCHIVED_ITEMS_FILE).unwrap_or_default())),
+        price_ema: Arc::new(RwLock::new(FxHashMap::default())),
+        dirty: Arc::new(DirtyFlags::default()),
+        mojang_limiter: Arc::new(Semaphore::new(mojang_permits)),
+        sync_generation: Arc::new(AtomicU64::new(0)),
+        draining: Arc::new(AtomicBool::new(false)),
+        read_only: Arc::new(AtomicBool::new(read_only)),
+        env_history: Arc::new(RwLock::new(Default::default())),
     };
 
-    let writer_handle = tokio::spawn(background_writer_task(rx, state.history_cache.clone(), metrics));
+    let writer_handle = start_history_writer(&state, rx, HISTORY_FILE.to_string());
+    // 只读模式下不回放死信、不调参、不写定期快照
+    if !read_only {
+        replay_dead_letters(&state).await;
+        tokio::spawn(lambda_autotune_task(state.clone()));
+        tokio::spawn(periodic_snapshot_task(state.clone()));
+    }
+    tokio::spawn(env_history_task(state.clone()));
 
-    // Java 端需要的路由
-    let app = Router::new()
-        // 基础交易
-        .route("/calculate_sell", post(api::handle_sell))
-        .route("/calculate_buy", post(api::handle_buy))
-        // 批量交易
-        .route("/batch_sell", post(api::handle_batch_sell))
-        // 行情查询
-        .route("/api/market/prices", post(api::get_market_prices))
-        // 数据同步
-        .route("/api/market/sync", post(api::sync_market))
-        
-        .layer(CorsLayer::permissive())
-        .layer(TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(10)))
-        .with_state(state.clone());
+    let app = build_router(&state);
 
     let port = state.config.read().port;
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
@@ -210,29 +1299,102 @@ async fn main() {
     info!("✨ API 节点已上线: {}", addr);
 
     axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(drain_on_shutdown(state.draining.clone(), state.config.clone(), shutdown_signal()))
         .await
         .unwrap();
 
     perform_graceful_cleanup(state, writer_handle).await;
 }
 
+/// 组装全部路由；配置了 route_prefix 时整体挂在该前缀下
+fn build_router(state: &AppState) -> Router {
+    let (max_body_bytes, prefix, health_unprefixed) = {
+        let config = state.config.read();
+        (config.max_body_bytes, config.route_prefix.trim_end_matches('/').to_string(), config.health_unprefixed)
+    };
+
+    let read_only = || middleware::from_fn_with_state(state.clone(), api::reject_when_read_only);
+    let admin_routes = Router::new()
+        // 会修改状态的管理接口 (只读模式下拒绝)
+        .route("/api/admin/player/import", post(api::import_player_histories))
+        .route("/api/admin/recompute_prices", post(api::recompute_prices))
+        .route("/api/admin/balance/grant", post(api::grant_balance))
+        .route("/api/admin/player/rename", post(api::rename_player))
+        .route("/api/admin/item/{id}", delete(api::delete_item))
+        .route("/api/admin/item/{id}/restore", post(api::restore_item))
+        .route("/api/admin/item/{id}/price_override", post(api::set_price_override).delete(api::clear_price_override))
+        .route_layer(read_only())
+        .route("/api/admin/stats/items", get(api::get_item_stats))
+        .route("/api/admin/dump_state", get(api::dump_state))
+        .route("/api/admin/simulate_sequence", post(api::simulate_sequence))
+        .route_layer(middleware::from_fn_with_state(state.clone(), api::require_admin));
+
+    // Java 端需要的路由
+    let app = Router::new()
+        // 基础交易
+        .route("/calculate_sell", post(api::handle_sell))
+        .route("/calculate_buy", post(api::handle_buy))
+        // 批量交易
+        .route("/batch_sell", post(api::handle_batch_sell))
+        .route("/batch_buy", post(api::handle_batch_buy))
+        // 行情查询
+        .route("/api/market/prices", post(api::get_market_prices))
+        .route("/api/market/roundtrip", post(api::roundtrip_quote))
+        .route("/api/market/params", get(api::get_market_params))
+        .route("/api/market/health", get(api::get_market_health))
+        .route("/api/market/env_history", get(api::get_env_history))
+        .route("/api/market/item/{id}/neff", get(api::get_item_neff))
+        .route("/api/market/item/{id}/recovery", get(api::get_item_recovery))
+        .route("/api/market/env_token", post(api::issue_env_token))
+        // 数据同步
+        .route("/api/market/sync", post(api::sync_market).route_layer(read_only()))
+        // 玩家余额
+        .route("/api/player/{id}/balance", get(api::get_player_balance))
+        // 运行指标 (JSON)
+        .route("/api/metrics", get(api::get_metrics))
+        // 长连接指令通道
+        .route("/ws", get(ws::ws_handler))
+        // 管理接口
+        .merge(admin_routes);
+    // 健康检查与 Prometheus 指标
+    let health = Router::new()
+        .route("/readyz", get(api::readiness))
+        .route("/metrics", get(api::prometheus_metrics));
+
+    if prefix.is_empty() {
+        return with_common_layers(app.merge(health), max_body_bytes).with_state(state.clone());
+    }
+    let prefix = if prefix.starts_with('/') { prefix } else { format!("/{prefix}") };
+    info!("🧭 路由前缀: {}", prefix);
+    // 中间件挂在内层：嵌套路由看到的是去掉前缀后的路径，响应版本整形照常匹配
+    let (app, outer) = if health_unprefixed {
+        (app, with_common_layers(health, max_body_bytes))
+    } else {
+        (app.merge(health), Router::new())
+    };
+    outer.nest(&prefix, with_common_layers(app, max_body_bytes)).with_state(state.clone())
+}
+
 async fn perform_graceful_cleanup(state: AppState, writer_handle: task::JoinHandle<()>) {
     info!("💾 执行最终同步...");
     drop(state.tx); // 触发 background_writer 退出
     
     if let Err(_) = time::timeout(Duration::from_secs(10), writer_handle).await {
-        warn!("⏰ 刷盘任务超时，部分流水可能丢失。");
+        warn!("{}", state.config.read().log_lang.text(Msg::FlushTimeout));
+    }
+    if state.read_only.load(Ordering::Relaxed) {
+        info!("🛑 只读模式，跳过最终快照");
+        return;
     }
 
-    async fn save_with_retry<T: serde::Serialize>(name: &str, data: &T) {
+    async fn save_with_retry<T: serde::Serialize>(backend: &dyn StorageBackend, name: &str, data: &T, lang: LogLang) {
         for i in 1..=3 {
-            match Storage::atomic_save(name, data) {
+            match Storage::encode(data).and_then(|bytes| backend.save(name, &bytes)) {
                 Ok(_) => { 
                     info!("✅ {} 保存成功", name); 
                     return; 
                 }
-                Err(e) => warn!("⚠️ {} 保存失败 (第{}次重试): {:?}", name, i, e),
+                Err(e) => warn!("{}", lang.render(Msg::SaveRetry, &[&name, &i, &format!("{:?}", e)])),
             }
             time::sleep(Duration::from_millis(500)).await;
         }
@@ -243,18 +1405,36 @@ async fn perform_graceful_cleanup(state: AppState, writer_handle: task::JoinHand
     let final_config = state.config.read();
     let final_market = state.market_cache.read();
     let final_env = state.env_cache.read();
+    let final_balances = state.player_balances.read();
+    let final_archive = state.archived_items.read();
+    let lang = final_config.log_lang;
 
     // 执行保存
-    save_with_retry(PLAYER_DATA_FILE, &*final_histories).await;
-    save_with_retry(CONFIG_FILE, &*final_config).await;
+    let backend = storage::backend();
+    save_with_retry(backend, PLAYER_DATA_FILE, &*final_histories, lang).await;
+    // 配置决定了存储后端，始终写本地文件
+    save_with_retry(&FileBackend, CONFIG_FILE, &*final_config, lang).await;
     
     // [核心修复] 保存市场状态和环境数据
-    save_with_retry(MARKET_DATA_FILE, &*final_market).await;
-    save_with_retry(ENV_DATA_FILE, &*final_env).await;
+    save_with_retry(backend, MARKET_DATA_FILE, &*final_market, lang).await;
+    save_with_retry(backend, ENV_DATA_FILE, &*final_env, lang).await;
+    save_with_retry(backend, BALANCES_FILE, &*final_balances, lang).await;
+    save_with_retry(backend, ARCHIVED_ITEMS_FILE, &*final_archive, lang).await;
+    save_with_retry(backend, TRADE_SEQ_FILE, &state.trade_seq.load(Ordering::Relaxed), lang).await;
 
     info!("👋 所有数据已同步，系统安全退出。");
 }
 
+/// 收到关闭信号后先排空：新成交立即返回 503，等待 shutdown_drain_ms 让在途请求完成，
+/// 再交给 axum 停止接收连接；之后才关闭写入通道，避免请求向已关闭的通道发送
+async fn drain_on_shutdown(draining: Arc<AtomicBool>, config: Arc<RwLock<AppConfig>>, signal: impl std::future::Future<Output = ()>) {
+    signal.await;
+    draining.store(true, Ordering::Release);
+    let grace = Duration::from_millis(config.read().shutdown_drain_ms);
+    warn!("🚧 进入排空阶段：暂停接受新的成交，{:?} 后停止服务", grace);
+    time::sleep(grace).await;
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         signal::ctrl_c()
@@ -277,4 +1457,917 @@ async fn shutdown_signal() {
         _ = ctrl_c => {},
         _ = terminate => {},
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("economy-core-{}-{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn test_record(i: u64) -> TransactionRecord {
+        TransactionRecord::new(i as i64, 1.0, 10.0, 10.0, 1.0, "SELL".into(), "p1".into(), "Steve".into(), "stone".into())
+    }
+
+    fn write_history_log(path: &str, count: u64) {
+        let mut bytes = HISTORY_LOG_HEADER.to_vec();
+        for i in 0..count {
+            bytes.extend(postcard::to_stdvec_cobs(&test_record(i)).unwrap());
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn history_tail_returns_newest_records_in_order() {
+        let path = temp_path("tail.bin");
+        write_history_log(&path, 50_000);
+
+        let records = Storage::load_history_tail(&path, 100, Duration::from_secs(60));
+        let timestamps: Vec<i64> = records.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, (49_900..50_000).collect::<Vec<_>>());
+
+        // 读取到文件头即停止，不把文件头当作损坏帧
+        let all = Storage::load_history_tail(&path, usize::MAX, Duration::from_secs(60));
+        assert_eq!(all.len(), 50_000);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn history_tail_stops_at_tiny_budget() {
+        let path = temp_path("budget.bin");
+        write_history_log(&path, 200_000);
+
+        let none = Storage::load_history_tail(&path, usize::MAX, Duration::ZERO);
+        assert!(none.is_empty());
+
+        // 预算很小时只返回最新的一段连续流水
+        let partial = Storage::load_history_tail(&path, usize::MAX, Duration::from_micros(200));
+        assert!(partial.len() < 200_000);
+        let expected_first = 200_000 - partial.len() as i64;
+        assert!(partial.iter().map(|r| r.timestamp).eq(expected_first..200_000));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn legacy_history_log_is_converted_once() {
+        let path = temp_path("legacy.bin");
+        let mut bytes = Vec::new();
+        for i in 0..10i64 {
+            let old = v0::TransactionRecord {
+                timestamp: i, amount: 1.0, total_price: 5.0, avg_price: 5.0, env_index: 1.0,
+                action: "SELL".into(), player_id: "p1".into(), player_name: "Steve".into(),
+                item_id: "stone".into(), note: "".into(),
+            };
+            bytes.extend(postcard::to_stdvec(&old).unwrap());
+        }
+        // 崩溃时写了一半的记录
+        bytes.extend_from_slice(&[0x14, 0x02]);
+        fs::write(&path, &bytes).unwrap();
+
+        Storage::upgrade_history_log(&path).unwrap();
+        let backup = format!("{}.v0.bak", path);
+        assert_eq!(fs::read(&backup).unwrap(), bytes);
+        assert!(matches!(Storage::history_log_format(&path).unwrap(), HistoryLogFormat::Current));
+
+        let records = Storage::load_history_tail(&path, usize::MAX, Duration::from_secs(60));
+        let timestamps: Vec<i64> = records.iter().map(|r| r.timestamp).collect();
+        assert_eq!(timestamps, (0..10).collect::<Vec<_>>());
+
+        // 已是当前格式时不再转换
+        Storage::upgrade_history_log(&path).unwrap();
+        assert_eq!(Storage::load_history_tail(&path, usize::MAX, Duration::from_secs(60)).len(), 10);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_startup_leaves_the_data_dir_untouched() {
+        // 配置缺失时按默认值运行，不写出配置文件
+        let config_path = temp_path("ro-config.bin");
+        let _ = fs::remove_file(&config_path);
+        assert_eq!(load_config_first_run(&config_path, true).port, AppConfig::default().port);
+        assert!(!std::path::Path::new(&config_path).exists());
+
+        // 旧版日志在内存中读取，原文件不变且不生成备份
+        let path = temp_path("ro-legacy.bin");
+        let mut bytes = Vec::new();
+        for i in 0..5i64 {
+            let old = v0::TransactionRecord {
+                timestamp: i, amount: 1.0, total_price: 5.0, avg_price: 5.0, env_index: 1.0,
+                action: "SELL".into(), player_id: "p1".into(), player_name: "Steve".into(),
+                item_id: "stone".into(), note: "".into(),
+            };
+            bytes.extend(postcard::to_stdvec(&old).unwrap());
+        }
+        fs::write(&path, &bytes).unwrap();
+        let records = load_startup_history(&path, true, Duration::from_secs(60)).unwrap();
+        assert!(records.iter().map(|r| r.timestamp).eq(0..5));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+        assert!(!std::path::Path::new(&format!("{}.v0.bak", path)).exists());
+
+        // 空日志不补写文件头，写入任务也不创建日志
+        fs::write(&path, b"").unwrap();
+        assert!(load_startup_history(&path, true, Duration::from_secs(60)).unwrap().is_empty());
+        assert!(fs::read(&path).unwrap().is_empty());
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        state.read_only.store(true, Ordering::Relaxed);
+        let fresh = temp_path("ro-writer.bin");
+        let _ = fs::remove_file(&fresh);
+        let (_tx, rx) = mpsc::channel(8);
+        start_history_writer(&state, rx, fresh.clone()).await.unwrap();
+        assert!(!std::path::Path::new(&fresh).exists());
+        fs::remove_file(&path).unwrap();
+
+        // 只读打开不存在的数据库直接报错，不创建文件
+        #[cfg(feature = "sqlite")]
+        {
+            let db = temp_path("ro.db");
+            let _ = fs::remove_file(&db);
+            assert!(storage::SqliteBackend::open(&db, true).is_err());
+            assert!(!std::path::Path::new(&db).exists());
+        }
+    }
+
+    #[test]
+    fn history_frames_decode_across_metadata_layouts() {
+        let path = temp_path("cross_feature.bin");
+        // 引入 metadata 之前 (或未开启 trade-metadata 的构建) 写入的帧
+        let before = v1::TransactionRecord {
+            timestamp: 1, amount: 2.0, total_price: 10.0, avg_price: 5.0, env_index: 1.0,
+            action: "SELL".into(), player_id: "p1".into(), player_name: "Steve".into(),
+            item_id: "stone".into(), note: "".into(), seq: 41, payout: Some(1.0), payout_currency: Some("gems".into()),
+        };
+        let mut current = TransactionRecord { timestamp: 2, seq: 42, ..TransactionRecord::from(before.clone()) };
+        current.metadata.insert("auth".into(), "OnlineAuth".into());
+
+        let mut bytes = HISTORY_LOG_HEADER.to_vec();
+        bytes.extend(postcard::to_stdvec_cobs(&before).unwrap());
+        bytes.extend(postcard::to_stdvec_cobs(&current).unwrap());
+        fs::write(&path, &bytes).unwrap();
+
+        let records = Storage::load_history_tail(&path, usize::MAX, Duration::from_secs(60));
+        let seqs: Vec<u64> = records.iter().map(|r| r.seq).collect();
+        assert_eq!(seqs, [41, 42]);
+        assert_eq!(records[0].payout_currency.as_deref(), Some("gems"));
+        assert!(records[0].metadata.is_empty());
+        assert_eq!(records[1].metadata["auth"], "OnlineAuth");
+
+        // 单条 blob (SQLite 后端) 同样回退
+        let blob = postcard::to_stdvec(&before).unwrap();
+        assert_eq!(TransactionRecord::decode(&blob).unwrap().seq, 41);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_legacy_history_log_is_left_untouched() {
+        let path = temp_path("corrupt.bin");
+        let garbage = vec![0xffu8; 64];
+        fs::write(&path, &garbage).unwrap();
+
+        assert!(Storage::upgrade_history_log(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), garbage);
+        assert!(fs::metadata(format!("{}.migrating", path)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_roundtrips_with_versioned_header() {
+        let items = vec![MarketItem { id: "diamond".into(), base_price: 100.0, lambda: 0.01, target_daily_volume: Some(64.0), ..Default::default() }];
+        let bytes = Storage::encode(&items).unwrap();
+        assert!(bytes.starts_with(STORE_MAGIC));
+
+        let decoded: Vec<MarketItem> = Storage::decode(MARKET_DATA_FILE, &bytes).unwrap();
+        assert_eq!(decoded[0].id, "diamond");
+        assert_eq!(decoded[0].target_daily_volume, Some(64.0));
+    }
+
+    #[test]
+    fn legacy_headerless_snapshot_is_migrated() {
+        let old = v0::AppConfig {
+            global_iota: 0.5, base_env_index: 1.1, noise_std: 0.01, weekend_factor: 0.02,
+            holiday_factor: 0.15, public_holiday_factor: 0.1, buy_premium: 1.5,
+            recovery_delta: 0.05, recovery_tau: 7200.0, version: 1, port: 25600,
+            is_online_mode: true, winter_start: "01-15".into(), winter_end: "02-20".into(),
+            summer_start: "07-01".into(), summer_end: "08-31".into(),
+        };
+        let bytes = postcard::to_stdvec(&old).unwrap();
+
+        let config: AppConfig = Storage::decode(CONFIG_FILE, &bytes).unwrap();
+        assert_eq!(config.port, 25600);
+        assert_eq!(config.recovery_tau, 7200.0);
+        assert!(config.is_online_mode);
+        // v0 之后新增的字段取默认值
+        assert_eq!(config.lambda_max, AppConfig::default().lambda_max);
+
+        let items = vec![v0::MarketItem { id: "stone".into(), name: "Stone".into(), base_price: 10.0, lambda: 0.01, n: 3.0, iota: 1.0 }];
+        let market: Vec<MarketItem> = Storage::decode(MARKET_DATA_FILE, &postcard::to_stdvec(&items).unwrap()).unwrap();
+        assert_eq!((market[0].n, market[0].iota, market[0].target_daily_volume), (3.0, 1.0, None));
+    }
+
+    #[test]
+    fn undecodable_or_newer_snapshot_is_an_error() {
+        assert!(Storage::decode::<Vec<MarketItem>>(MARKET_DATA_FILE, &[0xff, 0xff, 0xff]).is_err());
+
+        let mut newer = STORE_MAGIC.to_vec();
+        newer.extend_from_slice(&(STORE_FORMAT_VERSION + 1).to_le_bytes());
+        newer.extend_from_slice(&postcard::to_stdvec(&Vec::<MarketItem>::new()).unwrap());
+        assert!(Storage::decode::<Vec<MarketItem>>(MARKET_DATA_FILE, &newer).is_err());
+
+        // 当前布局的数据后面多出字节同样视为损坏
+        let mut trailing = Storage::encode(&Vec::<MarketItem>::new()).unwrap();
+        trailing.push(0);
+        assert!(Storage::decode::<Vec<MarketItem>>(MARKET_DATA_FILE, &trailing).is_err());
+    }
+
+    #[test]
+    fn autotune_marks_the_market_dirty_and_drops_stale_price_snapshots() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { lambda_autotune_gain: 0.1, ..Default::default() });
+        let now = chrono::Utc::now().timestamp_millis();
+        *state.market_cache.write() = vec![
+            MarketItem { id: "stone".into(), base_price: 10.0, lambda: 0.01, target_daily_volume: Some(100.0), ..Default::default() },
+            MarketItem { id: "ore".into(), base_price: 10.0, lambda: 0.01, ..Default::default() },
+        ];
+        for id in ["stone", "ore"] {
+            state.price_snapshot.write().insert(id.into(), (now, MarketItemStatus::default()));
+        }
+
+        // 无成交：低于目标，lambda 下调
+        assert_eq!(run_lambda_autotune(&state, &state.config.read().clone(), now), 1);
+        assert!(state.market_cache.read()[0].lambda < 0.01);
+        assert!(state.dirty.market.load(Ordering::Relaxed));
+        let snapshot = state.price_snapshot.read();
+        assert!(!snapshot.contains_key("stone") && snapshot.contains_key("ore"));
+    }
+
+    #[test]
+    fn autotune_period_survives_restarts() {
+        let day = LAMBDA_AUTOTUNE_PERIOD_MS;
+        // 上次执行在 20 小时前：重启后只需再等 4 小时
+        assert_eq!(autotune_delay(0, 20 * 3_600_000), Duration::from_millis(4 * 3_600_000));
+        // 停机超过一个周期：立即执行
+        assert_eq!(autotune_delay(0, 3 * day), Duration::ZERO);
+        // 时钟回拨时最多等待一个周期
+        assert_eq!(autotune_delay(day * 2, 0), Duration::from_millis(day as u64));
+    }
+
+    /// 在本地随机端口上运行路由，返回基础 URL
+    async fn serve(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_rejected_with_413_envelope() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let app = Router::new().route("/batch_sell", post(api::handle_batch_sell));
+        let url = serve(with_common_layers(app, 1024).with_state(state)).await;
+
+        let resp = reqwest::Client::new().post(format!("{url}/batch_sell"))
+            .header("content-type", "application/json")
+            .body(format!("{{\"playerId\":\"{}\"}}", "x".repeat(4096)))
+            .send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 413);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"], api::ApiError::PayloadTooLarge.to_string());
+    }
+
+    #[tokio::test]
+    async fn dump_state_requires_admin_token_and_summarizes_histories() {
+        let (state, _handles) = AppState::new_for_test(AppConfig { admin_token: "secret".into(), ..Default::default() });
+        let sales = vec![SalesRecord { timestamp: 1, amount: 1.0, env_index: 1.0, price: 1.0, seq: 0 }; 3];
+        let history = PlayerSalesHistory { item_sales: [("stone".to_string(), sales)].into_iter().collect(), ..Default::default() };
+        state.player_histories.write().insert("p1".into(), history);
+        let app = Router::new()
+            .route("/api/admin/dump_state", get(api::dump_state))
+            .route_layer(middleware::from_fn_with_state(state.clone(), api::require_admin));
+        let url = serve(app.with_state(state)).await;
+
+        let client = reqwest::Client::new();
+        let denied = client.get(format!("{url}/api/admin/dump_state")).send().await.unwrap();
+        assert_eq!(denied.status().as_u16(), 401);
+
+        let dump: serde_json::Value = client.get(format!("{url}/api/admin/dump_state"))
+            .header("x-admin-token", "secret")
+            .send().await.unwrap().json().await.unwrap();
+        assert_eq!(dump["playerHistories"], serde_json::json!({ "players": 1, "itemSeries": 1, "records": 3 }));
+        assert!(dump["market"].as_array().is_some_and(|m| m.is_empty()));
+        assert_eq!(dump["config"]["adminToken"], "***");
+        assert_eq!(dump["config"]["envTokenSecret"], "");
+    }
+
+    #[tokio::test]
+    async fn admin_api_is_closed_when_no_token_is_configured() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let url = serve(build_router(&state)).await;
+        let client = reqwest::Client::new();
+
+        for path in ["/api/admin/dump_state", "/api/admin/stats/items"] {
+            let resp = client.get(format!("{url}{path}")).send().await.unwrap();
+            assert_eq!(resp.status().as_u16(), 401, "{path}");
+            // 空令牌头同样不能通过
+            let resp = client.get(format!("{url}{path}")).header("x-admin-token", "").send().await.unwrap();
+            assert_eq!(resp.status().as_u16(), 401, "{path}");
+        }
+    }
+
+    #[test]
+    fn nan_in_persisted_state_is_sanitized_on_load() {
+        let path = temp_path("nan_market.bin");
+        let items = vec![
+            MarketItem { id: "stone".into(), base_price: 10.0, lambda: 0.01, n: f64::NAN, iota: f64::INFINITY, ..Default::default() },
+            MarketItem { id: "dirt".into(), base_price: 1.0, lambda: 0.02, n: 4.0, ..Default::default() },
+        ];
+        Storage::atomic_save(&path, &items).unwrap();
+
+        let loaded: Vec<MarketItem> = Storage::load(&path).unwrap().unwrap();
+        assert_eq!((loaded[0].n, loaded[0].iota, loaded[0].base_price), (0.0, 0.0, 10.0));
+        assert_eq!(loaded[1].n, 4.0);
+        fs::remove_file(&path).unwrap();
+
+        let path = temp_path("nan_players.bin");
+        let records = vec![SalesRecord { amount: f64::NAN, ..Default::default() }, SalesRecord { timestamp: 1, amount: 2.0, ..Default::default() }];
+        let history = PlayerSalesHistory { item_sales: [("stone".to_string(), records)].into_iter().collect(), ..Default::default() };
+        Storage::atomic_save(&path, &HashMap::from([("p1".to_string(), history)])).unwrap();
+
+        let loaded: HashMap<String, PlayerSalesHistory> = Storage::load(&path).unwrap().unwrap();
+        assert_eq!(loaded["p1"].item_sales["stone"].len(), 1);
+        assert_eq!(loaded["p1"].item_sales["stone"][0].amount, 2.0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn v1_clients_get_the_trimmed_quote_shape() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() }];
+        let app = Router::new().route("/api/market/prices", post(api::get_market_prices));
+        let url = serve(with_common_layers(app, 1024 * 1024).with_state(state)).await;
+
+        let client = reqwest::Client::new();
+        let quote = |path: &'static str, header: Option<&'static str>| {
+            let mut req = client.post(format!("{url}{path}")).json(&serde_json::json!({ "itemIds": ["stone"] }));
+            if let Some(v) = header { req = req.header("x-api-version", v); }
+            async move { req.send().await.unwrap().json::<serde_json::Value>().await.unwrap() }
+        };
+
+        let latest = quote("/api/market/prices", None).await;
+        assert!(latest["items"]["stone"].get("effectiveLambda").is_some());
+        assert!(latest.get("stale").is_some());
+
+        for v1 in [quote("/api/market/prices?v=1", None).await, quote("/api/market/prices", Some("1")).await] {
+            assert!(v1["items"]["stone"].get("effectiveLambda").is_none(), "{v1}");
+            assert!(v1.get("stale").is_none());
+            assert!(v1["items"]["stone"]["price"].as_f64().is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn minimal_fields_mode_omits_the_extra_trade_fields() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        *state.market_cache.write() = vec![MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() }];
+        let app = Router::new().route("/calculate_sell", post(api::handle_sell));
+        let url = serve(with_common_layers(app, 1024 * 1024).with_state(state)).await;
+
+        let preview = serde_json::json!({
+            "playerId": "0123456789abcdef0123456789abcdef", "playerName": "Steve", "itemId": "stone",
+            "amount": 5.0, "basePrice": 100.0, "decayLambda": 0.01, "isPreview": true
+        });
+        let client = reqwest::Client::new();
+        let sell = |path: &'static str| {
+            let req = client.post(format!("{url}{path}")).json(&preview);
+            async move { req.send().await.unwrap().json::<serde_json::Value>().await.unwrap() }
+        };
+
+        let full = sell("/calculate_sell").await;
+        assert!(full.get("envIndex").is_some() && full.get("serverLoad").is_some());
+        let minimal = sell("/calculate_sell?fields=minimal").await;
+        let mut keys: Vec<&String> = minimal.as_object().unwrap().keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["message", "success", "totalPrice"]);
+        assert_eq!(minimal["totalPrice"], full["totalPrice"]);
+    }
+
+    #[tokio::test]
+    async fn misspelled_request_field_depends_on_strict_json() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let app = Router::new().route("/calculate_sell", post(api::handle_sell));
+        let url = serve(with_common_layers(app, 1024 * 1024).with_state(state)).await;
+
+        let typo = serde_json::json!({
+            "playerId": "0123456789abcdef0123456789abcdef", "playerName": "Steve", "itemId": "stone",
+            "amont": 5.0, "amount": 0.0, "basePrice": 100.0, "decayLambda": 0.01, "isPreview": true
+        });
+        let resp = reqwest::Client::new().post(format!("{url}/calculate_sell")).json(&typo).send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 400);
+        let error = resp.json::<serde_json::Value>().await.unwrap()["error"].as_str().unwrap().to_string();
+        if cfg!(feature = "strict-json") {
+            assert!(error.contains("unknown field `amont`"), "{error}");
+        } else {
+            // 默认宽松：拼错的字段被忽略，只剩交易量校验失败
+            assert!(error.contains("交易量"), "{error}");
+        }
+    }
+
+    #[test]
+    fn catalog_csv_seeds_valid_rows_and_skips_malformed_ones() {
+        let path = temp_path("catalog.csv");
+        fs::write(&path, [
+            "id,name,base_price,lambda,n,iota",
+            "minecraft:stone, Stone, 10, 0.01, 0, 0",
+            "minecraft:diamond,Diamond,500,0.05,12.5,1",
+            "",
+            "broken,Broken,abc,0.01,0,0",
+            "flat,Flat,10,0,0,0",
+            "short,Short,10",
+            "minecraft:stone,Stone again,20,0.01,0,0",
+        ].join("\n")).unwrap();
+
+        let items = load_catalog_csv(&path, &AppConfig::default());
+        let ids: Vec<&str> = items.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(ids, ["minecraft:stone", "minecraft:diamond"]);
+        assert_eq!((items[0].name.as_str(), items[0].base_price), ("Stone", 10.0));
+        assert_eq!((items[1].lambda, items[1].n, items[1].iota), (0.05, 12.5, 1.0));
+
+        fs::remove_file(&path).unwrap();
+        assert!(load_catalog_csv(&path, &AppConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn trade_value_histogram_fills_cumulative_buckets() {
+        let histogram = ValueHistogram::default();
+        for value in [0.5, 5.0, 50.0, 50.0, 2_000_000.0, f64::NAN] {
+            histogram.observe(value);
+        }
+        let text = histogram.render("economy_trade_value", "成交额分布");
+        for line in [
+            "# TYPE economy_trade_value histogram",
+            "economy_trade_value_bucket{le=\"1\"} 1",
+            "economy_trade_value_bucket{le=\"10\"} 2",
+            "economy_trade_value_bucket{le=\"100\"} 4",
+            "economy_trade_value_bucket{le=\"1000000\"} 4",
+            "economy_trade_value_bucket{le=\"+Inf\"} 5",
+            "economy_trade_value_sum 2000105.5",
+            "economy_trade_value_count 5",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line:?} in\n{text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn prometheus_exports_latency_histograms_after_requests() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let url = serve(build_router(&state)).await;
+        let client = reqwest::Client::new();
+
+        let trade = serde_json::json!({
+            "playerId": "0123456789abcdef0123456789abcdef", "playerName": "Steve", "itemId": "stone",
+            "amount": 1.0, "basePrice": 100.0, "decayLambda": 0.01, "manualEnvIndex": 1.0, "isPreview": true
+        });
+        assert!(client.post(format!("{url}/calculate_sell")).json(&trade).send().await.unwrap().status().is_success());
+        assert!(client.post(format!("{url}/api/market/prices")).json(&serde_json::json!({})).send().await.unwrap().status().is_success());
+
+        let text = client.get(format!("{url}/metrics")).send().await.unwrap().text().await.unwrap();
+        for name in ["economy_trade_pricing_seconds", "economy_market_snapshot_seconds"] {
+            assert!(text.contains(&format!("# TYPE {name} histogram")), "{text}");
+            assert!(text.lines().any(|l| l == format!("{name}_count 1")), "{text}");
+            assert!(text.lines().any(|l| l == format!("{name}_bucket{{le=\"+Inf\"}} 1")), "{text}");
+        }
+    }
+
+    #[tokio::test]
+    async fn trade_ids_increase_and_survive_restart() {
+        let sell = |state: AppState| async move {
+            let req = TradeRequest {
+                player_id: "0123456789abcdef0123456789abcdef".into(), item_id: "stone".into(),
+                amount: 1.0, base_price: 100.0, manual_env_index: Some(1.0), ..Default::default()
+            };
+            api::execute_trade(&state, req, TradeAction::Sell).await.unwrap().trade_id.unwrap()
+        };
+
+        let (state, mut handles) = AppState::new_for_test(AppConfig::default());
+        let ids = [sell(state.clone()).await, sell(state.clone()).await];
+        assert_eq!(ids, [1, 2]);
+        let mut history = VecDeque::new();
+        for _ in ids {
+            history.push_back(tokio::time::timeout(Duration::from_secs(1), handles.records.recv()).await.unwrap().unwrap());
+        }
+        assert_eq!(history.iter().map(|r| r.seq).collect::<Vec<_>>(), vec![1, 2]);
+
+        // 正常退出保存了计数；异常退出时从历史流水中恢复
+        let path = temp_path("trade_seq.bin");
+        Storage::atomic_save(&path, &state.trade_seq.load(Ordering::Relaxed)).unwrap();
+        let saved = Storage::load::<u64>(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(initial_trade_seq(saved, &VecDeque::new()), 2);
+        assert_eq!(initial_trade_seq(None, &history), 2);
+
+        let (restarted, _handles) = AppState::new_for_test(AppConfig::default());
+        restarted.trade_seq.store(initial_trade_seq(saved, &history), Ordering::Relaxed);
+        assert_eq!(sell(restarted).await, 3);
+    }
+
+    #[test]
+    fn snapshot_skips_components_without_changes() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let dir = std::path::PathBuf::from(temp_path("snapshots"));
+        fs::create_dir_all(&dir).unwrap();
+
+        *state.market_cache.write() = vec![MarketItem { id: "stone".into(), ..Default::default() }];
+        state.dirty.market.store(true, Ordering::Relaxed);
+        assert_eq!(write_dirty_snapshots(&state, &dir), [MARKET_DATA_FILE]);
+
+        // 无新成交或同步：不再写市场文件
+        let market_file = dir.join(MARKET_DATA_FILE);
+        fs::remove_file(&market_file).unwrap();
+        assert!(write_dirty_snapshots(&state, &dir).is_empty());
+        assert!(!market_file.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn archive_and_trade_seq_are_snapshotted_when_they_change() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let dir = std::path::PathBuf::from(temp_path("snapshots-archive"));
+        fs::create_dir_all(&dir).unwrap();
+        *state.market_cache.write() = vec![MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() }];
+
+        // 删除物品后定期快照即写出归档，不必等到正常关闭
+        let _ = api::delete_item(axum::extract::State(state.clone()), axum::extract::Path("stone".to_string())).await;
+        assert_eq!(write_dirty_snapshots(&state, &dir), [MARKET_DATA_FILE, ARCHIVED_ITEMS_FILE]);
+        let archived: Vec<MarketItem> = Storage::load(&dir.join(ARCHIVED_ITEMS_FILE).to_string_lossy()).unwrap().unwrap();
+        assert_eq!(archived[0].id, "stone");
+
+        // 成交分配序号后同样写出计数
+        let trade = TradeRequest {
+            player_id: "0123456789abcdef0123456789abcdef".into(), player_name: "Steve".into(), item_id: "dirt".into(),
+            amount: 1.0, base_price: 10.0, decay_lambda: 0.01, manual_env_index: Some(1.0), ..Default::default()
+        };
+        assert!(api::execute_trade(&state, trade, TradeAction::Sell).await.unwrap().trade_id.is_some());
+        // 玩家历史由异步任务更新，这里只关心序号文件
+        assert!(write_dirty_snapshots(&state, &dir).contains(&TRADE_SEQ_FILE));
+        let seq: u64 = Storage::load(&dir.join(TRADE_SEQ_FILE).to_string_lossy()).unwrap().unwrap();
+        assert_eq!(seq, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[derive(Default)]
+    struct CountingSink {
+        frames: usize,
+        syncs: usize,
+    }
+
+    impl HistorySink for CountingSink {
+        async fn write_frame(&mut self, _bytes: &[u8]) -> io::Result<()> { self.frames += 1; Ok(()) }
+        async fn flush_os(&mut self) -> io::Result<()> { Ok(()) }
+        async fn sync_disk(&mut self) -> io::Result<()> { self.syncs += 1; Ok(()) }
+    }
+
+    #[tokio::test]
+    async fn fsync_policy_controls_sync_calls() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        for (policy, expected) in [(FsyncPolicy::Never, 0), (FsyncPolicy::PerBatch, 1), (FsyncPolicy::PerRecord, 3)] {
+            state.config.write().fsync_policy = policy;
+            let mut sink = CountingSink::default();
+            let mut batch: Vec<_> = (0..3).map(test_record).collect();
+            flush_batch(&mut batch, &mut sink, &state.metrics, &state.config).await;
+            assert_eq!((sink.frames, sink.syncs), (3, expected), "{policy:?}");
+            assert!(batch.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn writer_failures_are_logged_in_the_configured_language() {
+        struct FailingSink;
+        impl HistorySink for FailingSink {
+            async fn write_frame(&mut self, _bytes: &[u8]) -> io::Result<()> { Err(io::Error::other("disk full")) }
+            async fn flush_os(&mut self) -> io::Result<()> { Ok(()) }
+            async fn sync_disk(&mut self) -> io::Result<()> { Err(io::Error::other("disk full")) }
+        }
+        #[derive(Clone, Default)]
+        struct Capture(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl io::Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (state, _handles) = AppState::new_for_test(AppConfig {
+            log_lang: LogLang::En, fsync_policy: FsyncPolicy::PerBatch, ..Default::default()
+        });
+        let mut batch = vec![test_record(1)];
+        flush_batch(&mut batch, &mut FailingSink, &state.metrics, &state.config).await;
+        // 目录不存在，快照写入必然失败
+        state.dirty.market.store(true, Ordering::Relaxed);
+        assert!(write_dirty_snapshots(&state, std::path::Path::new(&temp_path("no-such-dir"))).is_empty());
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("Failed to write a record in the batch"), "{output}");
+        assert!(output.contains("History log fsync failed"), "{output}");
+        assert!(output.contains("Failed to save snapshot"), "{output}");
+        assert!(!output.chars().any(|c| ('\u{4e00}'..='\u{9fff}').contains(&c)), "{output}");
+        // 切回 zh 后同一故障输出原有中文文案
+        state.config.write().log_lang = LogLang::Zh;
+        flush_batch(&mut vec![test_record(2)], &mut FailingSink, &state.metrics, &state.config).await;
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("批量写入中单条记录失败"), "{output}");
+    }
+
+    #[test]
+    fn missing_config_is_written_on_first_run() {
+        let path = temp_path("first-run-config.bin");
+        let _ = fs::remove_file(&path);
+
+        let config = load_config_first_run(&path, false);
+        assert_eq!(config.port, AppConfig::default().port);
+        let written: AppConfig = Storage::load(&path).unwrap().expect("默认配置应已写出");
+        assert_eq!((written.port, written.is_online_mode), (config.port, config.is_online_mode));
+
+        // 已有配置时原样读取，不再覆盖
+        Storage::atomic_save(&path, &AppConfig { port: 12345, ..Default::default() }).unwrap();
+        assert_eq!(load_config_first_run(&path, false).port, 12345);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn routes_are_served_under_the_configured_prefix() {
+        let config = AppConfig { route_prefix: "/economy/".into(), health_unprefixed: true, ..Default::default() };
+        let (state, _handles) = AppState::new_for_test(config);
+        let url = serve(build_router(&state)).await;
+        let client = reqwest::Client::new();
+
+        let trade = serde_json::json!({
+            "playerId": "0123456789abcdef0123456789abcdef", "playerName": "Steve", "itemId": "stone",
+            "amount": 5.0, "basePrice": 100.0, "decayLambda": 0.01, "manualEnvIndex": 1.0, "isPreview": true
+        });
+        let resp = client.post(format!("{url}/economy/calculate_sell")).json(&trade).send().await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        assert!(resp.json::<serde_json::Value>().await.unwrap()["success"].as_bool().unwrap());
+
+        let flat = client.post(format!("{url}/calculate_sell")).json(&trade).send().await.unwrap();
+        assert_eq!(flat.status().as_u16(), 404);
+        // 健康检查按配置留在根路径
+        assert_eq!(client.get(format!("{url}/metrics")).send().await.unwrap().status().as_u16(), 200);
+        assert_eq!(client.get(format!("{url}/economy/metrics")).send().await.unwrap().status().as_u16(), 404);
+    }
+
+    #[test]
+    fn rate_window_reports_the_previous_full_second() {
+        let window = RateWindow::default();
+        for _ in 0..5 { window.hit(100); }
+        assert_eq!(window.per_sec(100), 0);
+        window.hit(101);
+        assert_eq!(window.per_sec(101), 5);
+        // 跳过空闲的整秒后归零
+        assert_eq!(window.per_sec(103), 0);
+    }
+
+    #[tokio::test]
+    async fn panicking_writer_is_restarted_by_the_supervisor() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        let starts = Arc::new(AtomicU64::new(0));
+        let counter = starts.clone();
+        supervise_writer(move || {
+            let attempt = counter.fetch_add(1, Ordering::Relaxed);
+            async move {
+                if attempt == 0 { panic!("模拟磁盘错误"); }
+                Ok(())
+            }
+        }, state.metrics.clone(), state.config.clone(), Duration::from_millis(1)).await;
+
+        // 第一次 panic 后重启，第二次正常结束 (通道关闭) 即停止监督
+        assert_eq!(starts.load(Ordering::Relaxed), 2);
+        assert_eq!(state.metrics.writer_restarts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn writer_retries_when_the_history_file_cannot_be_opened() {
+        let (state, handles) = AppState::new_for_test(AppConfig::default());
+        let dir = temp_path("writer-missing-dir");
+        let _ = fs::remove_dir_all(&dir);
+        let path = format!("{}/history.bin", dir);
+
+        let rx = Arc::new(tokio::sync::Mutex::new(handles.records));
+        let (cache, metrics, config) = (state.history_cache.clone(), state.metrics.clone(), state.config.clone());
+        let writer = tokio::spawn(supervise_writer(
+            move || background_writer_task(rx.clone(), cache.clone(), metrics.clone(), config.clone(), path.clone()),
+            state.metrics.clone(),
+            state.config.clone(),
+            Duration::from_millis(5),
+        ));
+
+        // 目录不存在：打开失败被当作异常退出计数并重试，而不是当作正常结束
+        while state.metrics.writer_restarts.load(Ordering::Relaxed) == 0 {
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(!writer.is_finished());
+
+        // 目录恢复后重试成功，积压的流水正常落盘
+        fs::create_dir_all(&dir).unwrap();
+        state.tx.send(test_record(7)).await.unwrap();
+        let metrics = state.metrics.clone();
+        drop(state);
+        writer.await.unwrap();
+
+        let records = Storage::load_history_tail(&format!("{}/history.bin", dir), usize::MAX, Duration::from_secs(60));
+        assert_eq!(records.iter().map(|r| r.timestamp).collect::<Vec<_>>(), [7]);
+        assert!(metrics.writer_restarts.load(Ordering::Relaxed) >= 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn disabled_history_persistence_creates_no_file() {
+        let path = temp_path("no-history.bin");
+        let _ = fs::remove_file(&path);
+        let (state, handles) = AppState::new_for_test(AppConfig { persist_history: false, ..Default::default() });
+        let writer = start_history_writer(&state, handles.records, path.clone());
+
+        for i in 0..3 {
+            api::persist_transaction(state.clone(), test_record(i), false).await;
+        }
+        writer.await.unwrap();
+
+        assert!(!std::path::Path::new(&path).exists());
+        // 内存中的查询缓存与玩家历史照常更新
+        assert_eq!(state.history_cache.read().len(), 3);
+        assert_eq!(state.player_histories.read()["p1"].item_sales["stone"].len(), 3);
+    }
+
+    fn exercise_backend(backend: &dyn StorageBackend, log: &str) {
+        let items = vec![MarketItem { id: "stone".into(), base_price: 100.0, ..Default::default() }];
+        assert!(backend.load(MARKET_DATA_FILE).unwrap().is_none());
+        backend.save(MARKET_DATA_FILE, &Storage::encode(&items).unwrap()).unwrap();
+        // 再次保存覆盖旧快照
+        let items = vec![MarketItem { id: "dirt".into(), base_price: 5.0, ..Default::default() }];
+        backend.save(MARKET_DATA_FILE, &Storage::encode(&items).unwrap()).unwrap();
+        let bytes = backend.load(MARKET_DATA_FILE).unwrap().expect("快照应已保存");
+        let loaded: Vec<MarketItem> = Storage::decode(MARKET_DATA_FILE, &bytes).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, "dirt");
+
+        let records: Vec<_> = (1..=5).map(|i| TransactionRecord { seq: i, ..test_record(i) }).collect();
+        backend.append(log, &records[..3]).unwrap();
+        backend.append(log, &records[3..]).unwrap();
+        let recent = backend.load_history(log, 2).unwrap();
+        assert_eq!(recent.iter().map(|r| r.seq).collect::<Vec<_>>(), [4, 5]);
+        let all = backend.load_history(log, usize::MAX).unwrap();
+        assert_eq!(all.len(), 5);
+        assert_eq!(all[0].player_id, "p1");
+        assert_eq!(all[4].timestamp, 5);
+    }
+
+    #[test]
+    fn storage_backends_round_trip_the_same_data() {
+        let dir = std::path::PathBuf::from(temp_path("file-backend"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // 文件后端的快照名即相对路径，这里把名称换成临时目录下的路径
+        struct InDir<'a>(&'a std::path::Path);
+        impl StorageBackend for InDir<'_> {
+            fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>> { FileBackend.load(&self.0.join(name).to_string_lossy()) }
+            fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> { FileBackend.save(&self.0.join(name).to_string_lossy(), bytes) }
+            fn append(&self, log: &str, records: &[TransactionRecord]) -> io::Result<()> { FileBackend.append(&self.0.join(log).to_string_lossy(), records) }
+            fn load_history(&self, log: &str, limit: usize) -> io::Result<VecDeque<TransactionRecord>> { FileBackend.load_history(&self.0.join(log).to_string_lossy(), limit) }
+        }
+        exercise_backend(&InDir(&dir), HISTORY_FILE);
+        assert!(matches!(Storage::history_log_format(&dir.join(HISTORY_FILE).to_string_lossy()).unwrap(), HistoryLogFormat::Current));
+        fs::remove_dir_all(&dir).unwrap();
+
+        #[cfg(feature = "sqlite")]
+        {
+            let db = temp_path("backend.db");
+            let _ = fs::remove_file(&db);
+            exercise_backend(&storage::SqliteBackend::open(&db, false).unwrap(), HISTORY_FILE);
+            // 重新打开后数据仍在
+            let reopened = storage::SqliteBackend::open(&db, false).unwrap();
+            assert_eq!(reopened.load_history(HISTORY_FILE, usize::MAX).unwrap().len(), 5);
+            drop(reopened);
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = fs::remove_file(format!("{db}{suffix}"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_replayed_into_the_history_log_at_startup() {
+        let (log, dead) = (temp_path("replay-history.bin"), temp_path("replay-dead.bin"));
+        let _ = fs::remove_file(&log);
+        let records: Vec<_> = (1..=5).map(|i| TransactionRecord { seq: i, ..test_record(i) }).collect();
+        let _ = fs::remove_file(&dead);
+        FileBackend.append(&dead, &records).unwrap();
+
+        let config = AppConfig { dead_letter_path: dead.clone(), dead_letter_replay_max: 3, ..Default::default() };
+        let (state, handles) = AppState::new_for_test(config);
+        let writer = start_history_writer(&state, handles.records, log.clone());
+        assert_eq!(replay_dead_letters(&state).await, 3);
+        drop(state);
+        writer.await.unwrap();
+
+        // 最早的 3 条进入主日志，其余留在死信日志
+        let landed = Storage::load_history_tail(&log, usize::MAX, Duration::from_secs(60));
+        assert_eq!(landed.iter().map(|r| r.seq).collect::<Vec<_>>(), [1, 2, 3]);
+        let left = FileBackend.load_history(&dead, usize::MAX).unwrap();
+        assert_eq!(left.iter().map(|r| r.seq).collect::<Vec<_>>(), [4, 5]);
+        fs::remove_file(&log).unwrap();
+        fs::remove_file(&dead).unwrap();
+    }
+
+    #[tokio::test]
+    async fn draining_rejects_new_trades_and_lets_in_flight_ones_finish() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig { shutdown_drain_ms: 300, ..Default::default() });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (signal_tx, signal_rx) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = drain_on_shutdown(state.draining.clone(), state.config.clone(), async { let _ = signal_rx.await; });
+        let server = tokio::spawn(axum::serve(listener, build_router(&state)).with_graceful_shutdown(shutdown).into_future());
+
+        let client = reqwest::Client::new();
+        let trade = |is_preview| serde_json::json!({
+            "playerId": "0123456789abcdef0123456789abcdef", "playerName": "Steve", "itemId": "stone",
+            "amount": 5.0, "basePrice": 100.0, "decayLambda": 0.01, "manualEnvIndex": 1.0, "isPreview": is_preview
+        });
+        let sell = |is_preview| client.post(format!("{url}/calculate_sell")).json(&trade(is_preview)).send();
+
+        // 排空前发起的成交照常完成并进入写入通道
+        assert_eq!(sell(false).await.unwrap().status().as_u16(), 200);
+        signal_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let rejected = sell(false).await.unwrap();
+        assert_eq!(rejected.status().as_u16(), 503);
+        // 预览不写入，排空期间仍可用
+        assert_eq!(sell(true).await.unwrap().status().as_u16(), 200);
+        assert!(!server.is_finished());
+
+        server.await.unwrap().unwrap();
+        assert_eq!(handles.records.recv().await.unwrap().item_id, "stone");
+        assert!(handles.records.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_rejects_every_mutating_endpoint() {
+        let (state, mut handles) = AppState::new_for_test(AppConfig { admin_token: "secret".into(), ..Default::default() });
+        *state.market_cache.write() = vec![MarketItem { id: "stone".into(), base_price: 100.0, lambda: 0.01, ..Default::default() }];
+        state.read_only.store(true, Ordering::Relaxed);
+        let url = serve(build_router(&state)).await;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-admin-token", reqwest::header::HeaderValue::from_static("secret"));
+        let client = reqwest::Client::builder().default_headers(headers).build().unwrap();
+
+        let trade = serde_json::json!({
+            "playerId": "0123456789abcdef0123456789abcdef", "playerName": "Steve", "itemId": "stone",
+            "amount": 5.0, "basePrice": 100.0, "decayLambda": 0.01, "manualEnvIndex": 1.0, "isPreview": false
+        });
+        let batch = serde_json::json!({ "playerId": trade["playerId"], "playerName": "Steve", "requests": [trade] });
+        let empty = serde_json::json!({});
+        let mutating = [
+            (reqwest::Method::POST, "/calculate_sell", &trade),
+            (reqwest::Method::POST, "/calculate_buy", &trade),
+            (reqwest::Method::POST, "/batch_sell", &batch),
+            (reqwest::Method::POST, "/batch_buy", &batch),
+            (reqwest::Method::POST, "/api/market/sync", &empty),
+            (reqwest::Method::POST, "/api/admin/player/import", &empty),
+            (reqwest::Method::POST, "/api/admin/recompute_prices", &empty),
+            (reqwest::Method::POST, "/api/admin/balance/grant", &empty),
+            (reqwest::Method::POST, "/api/admin/player/rename", &empty),
+            (reqwest::Method::DELETE, "/api/admin/item/stone", &empty),
+            (reqwest::Method::POST, "/api/admin/item/stone/restore", &empty),
+            (reqwest::Method::POST, "/api/admin/item/stone/price_override", &empty),
+            (reqwest::Method::DELETE, "/api/admin/item/stone/price_override", &empty),
+        ];
+        for (method, path, body) in mutating {
+            let resp = client.request(method.clone(), format!("{url}{path}")).json(body).send().await.unwrap();
+            assert_eq!(resp.status().as_u16(), 503, "{method} {path}");
+        }
+        assert!(handles.records.try_recv().is_err());
+        assert_eq!(state.market_cache.read().len(), 1);
+
+        // 读取照常
+        for path in ["/api/market/params", "/api/market/health", "/api/admin/dump_state"] {
+            let resp = client.get(format!("{url}{path}")).send().await.unwrap();
+            assert_eq!(resp.status().as_u16(), 200, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn json_metrics_are_routed_and_report_write_failures() {
+        let (state, _handles) = AppState::new_for_test(AppConfig::default());
+        state.metrics.write_failures.fetch_add(3, Ordering::Relaxed);
+        let url = serve(build_router(&state)).await;
+
+        let resp = reqwest::get(format!("{url}/api/metrics")).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 200);
+        let metrics = resp.json::<serde_json::Value>().await.unwrap();
+        assert_eq!(metrics["writeFailures"].as_u64(), Some(3));
+        assert_eq!(metrics["totalTrades"].as_u64(), Some(0));
+    }
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashMap};
 use rustc_hash::FxHashMap;
 use validator::Validate;
 
@@ -18,10 +18,12 @@ macro_rules! serializable {
 }
 
 /// Web 模型宏：用于前端交互 (自带 Default 和 Validate)
+/// 启用 strict-json 特性时拒绝未知字段，拼错的字段名会直接报错而不是被忽略
 macro_rules! web_model {
     ($($item:tt)*) => {
         #[derive(Debug, Clone, Serialize, Deserialize, Default, Validate)]
         #[serde(rename_all = "camelCase")]
+        #[cfg_attr(feature = "strict-json", serde(deny_unknown_fields))]
         $($item)*
     };
 }
@@ -70,12 +72,19 @@ serializable! {
         pub global_iota: f64,
         pub base_env_index: f64,
         pub noise_std: f64,
+        // AR(1) 噪声持续度 ρ ∈ [0, 1)，0 表示每秒独立噪声
+        pub noise_persistence: f64,
         pub weekend_factor: f64,
         pub holiday_factor: f64,
         pub public_holiday_factor: f64,
         pub buy_premium: f64,
+        // 买入超出库存 (新铸造) 部分的额外稀缺附加费率
+        pub fresh_mint_surcharge: f64,
         pub recovery_delta: f64,
         pub recovery_tau: f64,
+        // 历史衰减模型：指数 (默认) 或线性，线性在 recovery_window_secs 后完全恢复
+        pub recovery_model: RecoveryModel,
+        pub recovery_window_secs: f64,
         pub version: u32,
         pub port: u16,
         pub is_online_mode: bool,
@@ -83,6 +92,137 @@ serializable! {
         pub winter_end: Cow<'static, str>,
         pub summer_start: Cow<'static, str>,
         pub summer_end: Cow<'static, str>,
+        // 启动时读取历史流水的时间预算 (毫秒)，防止超大日志拖慢启动
+        pub startup_history_load_budget_ms: u64,
+        // lambda 自动调参：按日成交量向目标值比例逼近，结果限制在 [lambda_min, lambda_max]
+        pub lambda_autotune_enabled: bool,
+        pub lambda_autotune_gain: f64,
+        pub lambda_min: f64,
+        // lambda 的合法上限：同步与管理端调整时 lambda 必须落在 (0, lambda_max]，否则整次请求被拒绝
+        pub lambda_max: f64,
+        // 成交总价低于 min_payout 时的处理策略
+        pub min_payout: f64,
+        pub sub_minimum_policy: SubMinimumPolicy,
+        // 请求体大小上限 (字节)
+        pub max_body_bytes: usize,
+        // 行情查询等待玩家历史读锁的上限 (毫秒)，超时则回退到价格快照
+        pub history_lock_timeout_ms: u64,
+        pub price_snapshot_ttl_secs: i64,
+        // 单笔交易使现价变动超过该百分比时触发物品熔断 (0 表示关闭)
+        pub max_price_move_pct: f64,
+        pub item_breaker_cooldown_secs: i64,
+        // 管理接口令牌 (请求头 X-Admin-Token)，为空表示不校验
+        pub admin_token: String,
+        // 成交额舍入：就近 (默认) 或偏向庄家 (卖出向下、买入向上)
+        pub rounding_policy: RoundingPolicy,
+        pub price_decimals: u32,
+        // 物品分类 -> 环境指数增量，叠加在全局环境指数之上 (如饥荒事件时 "food": 0.3)
+        pub category_env_deltas: HashMap<String, f64>,
+        // 预览请求不落盘，跳过 Mojang 身份校验 (省去最大的延迟来源)
+        pub skip_auth_on_preview: bool,
+        // 历史影响的最短合理恢复时间 (秒)：半衰期/线性窗口低于它时告警，strict 模式下拒绝启动
+        pub min_recovery_secs: f64,
+        pub strict_config_validation: bool,
+        // 服务端记账：卖出入账、买入扣款，余额不足的买入被拒绝 (402)
+        pub enforce_balances: bool,
+        // 全局价格倍率 (通胀调控)：作用于买卖成交额与行情报价
+        pub global_price_multiplier: f64,
+        // 是否直接采用客户端上报的玩家名；关闭时在线模式以 Mojang 档案为准，离线模式保留首次记录的名称
+        pub trust_client_name: bool,
+        // 行情报价用插值查表代替 exp()，绝对误差不超过 exp_table_tolerance；超出表范围时回退精确计算
+        // 容差限制在 [1e-9, 0.1]：低于 1e-9 时按 1e-9 建表，非正数或 NaN 时不查表 (启动时均会告警)
+        pub exp_table_enabled: bool,
+        pub exp_table_tolerance: f64,
+        // 展示价的指数移动平均系数 (0, 1]，每次行情查询或成交时更新；0 表示不返回 displayPrice
+        pub display_ema_alpha: f64,
+        // 卖出税率 [0, 1]，从税前总额中扣除；税前总额低于 tax_exempt_below 的小额卖出免税
+        pub sell_tax_rate: f64,
+        pub tax_exempt_below: f64,
+        // 定期快照间隔 (秒)，0 表示只在退出时保存；无变更的部分跳过写盘
+        pub snapshot_interval_secs: u64,
+        // 环境指数的计算粒度 (秒)：同一时间窗口内复用缓存，0 表示每次都重新计算
+        pub env_cache_granularity_secs: i64,
+        // 允许同步的物品 ID (支持 * 通配，如 "minecraft:*")；为空时接受所有物品
+        pub item_id_allowlist: Vec<String>,
+        // 玩家提示、成交备注与运行期告警日志的语言 (zh | en)；启动阶段日志仍为中文
+        pub log_lang: LogLang,
+        pub fsync_policy: FsyncPolicy,
+        // 同时进行的 Mojang 请求上限 (全局共享，启动时生效)
+        pub mojang_max_concurrency: usize,
+        // priceLabel 的千位分隔符与货币后缀 (如 "1,234.56 coins")
+        pub price_thousands_separator: String,
+        pub price_currency_suffix: String,
+        // 单次行情响应的物品数上限 (按 ID 排序分页)，0 表示不限
+        pub max_items_per_response: usize,
+        // Rhai 定价脚本路径 (需启用 scripting 特性)，为空表示不启用；脚本的操作数与耗时受限
+        pub pricing_script_path: String,
+        pub script_max_operations: u64,
+        pub script_timeout_ms: u64,
+        // 环境冻结令牌的签名密钥 (为空时不签发也不接受令牌) 与有效期
+        pub env_token_secret: String,
+        pub env_token_ttl_secs: i64,
+        // 令牌与 manual_env_index 同时出现且指数不一致时拒绝交易
+        pub strict_env_precedence: bool,
+        // 成交额超过该值且请求未带 confirmed 时返回 409 与报价，不成交；0 表示不启用
+        pub confirm_threshold: f64,
+        // 每个玩家每个物品保留的最大历史记录数 (物品可用 max_records 覆盖)
+        pub max_records_per_item: usize,
+        // 开市溢价：launch_at_ms 起额外叠加的 iota，按半衰期指数衰减到 0 (物品可用 launch_iota 覆盖)
+        pub launch_iota: f64,
+        pub launch_iota_half_life_secs: f64,
+        // 开市时间 (毫秒时间戳)，0 表示不启用
+        pub launch_at_ms: i64,
+        // 所有路由挂载的路径前缀 (如 "/economy")，空表示不加前缀
+        pub route_prefix: String,
+        // 设置了前缀时 /readyz 与 /metrics 仍保留在根路径
+        pub health_unprefixed: bool,
+        // 只允许交易已同步的物品，且请求的 base_price / decay_lambda 须与服务端一致
+        pub require_known_item: bool,
+        // 上述一致性校验的相对容差
+        pub catalog_tolerance: f64,
+        // serverLoad 中请求速率达到该值 (次/秒) 视为满载；0 表示只看写入通道占用
+        pub load_reference_rps: f64,
+        // 现价相对上次推送的变动超过该百分比才发布价格推送；0 表示每次变动都推送
+        pub price_event_min_delta_pct: f64,
+        // 单个批量请求允许的最大行数，超出返回 413
+        pub max_batch_size: usize,
+        // 是否把成交流水写入 history.bin；关闭时只保留有界的内存缓存，玩家历史衰减不受影响
+        pub persist_history: bool,
+        // 单笔定价 (含 Mojang 校验) 的超时：预览快速失败，成交允许更久；均受外层 10 秒请求超时约束
+        pub preview_timeout_ms: u64,
+        pub trade_timeout_ms: u64,
+        // 同一玩家同一物品买入后多久内不能卖出 (反之亦然)，防止利用环境噪声低买高卖；0 表示不限制
+        pub arbitrage_cooldown_ms: i64,
+        // 快照与成交流水的存储后端 (配置文件本身始终是本地文件)；切换后端不会迁移已有数据
+        pub storage_backend: StorageBackendKind,
+        // storage_backend = Sqlite 时的数据库路径
+        pub sqlite_path: String,
+        // 写入通道已满时被丢弃的流水改写到该死信日志 (本地文件)，为空表示直接丢弃
+        pub dead_letter_path: String,
+        // 启动时从死信日志重放的最大条数，超出部分保留到下次启动；0 表示不重放
+        pub dead_letter_replay_max: usize,
+        // 指定日期 (YYYY-MM-DD) 的一次性环境增量，直接加到指数上 (如 "2024-12-25": -0.2)，与季节/周末因子叠加
+        pub special_dates: HashMap<String, f64>,
+        // 收到关闭信号后的排空时长：期间新成交返回 503，在途请求继续完成，之后才停止服务并刷盘
+        pub shutdown_drain_ms: u64,
+        // 每次玩家身份校验都以 info 级别记录 UUID、结果来源与耗时 (排查“无法出售”)，可对 UUID 做哈希脱敏
+        pub log_validation: bool,
+        pub log_validation_hash_ids: bool,
+        // 允许有效库存为负：大量买入使近期历史之和为负时，n 随之为负，卖出价高于基准价，
+        // 表示该玩家 (或全服) 欠着市场的供给，需要先卖回这部分才回到基准价；关闭时 n 在 0 处截断。
+        // 开启后买入同样沿曲线积分进入负区间 (低于 0 的部分另加稀缺附加费)，买入后立即卖回不会获利
+        pub allow_negative_n: bool,
+        // 环境指数时间序列的采样间隔 (秒，0 表示不采样)，以及按条数与按时长 (0 表示不限) 的双重保留上限
+        pub env_history_interval_secs: u64,
+        pub env_history_max_len: usize,
+        pub env_history_max_age_secs: i64,
+        // 市场状态为空 (全新安装) 时从该 CSV 目录播种 (列: id,name,base_price,lambda,n,iota)，空字符串表示不播种；
+        // 之后插件的同步照常整体覆盖
+        pub catalog_seed_path: String,
+        // 空闲加速恢复：物品超过 idle_recovery_after_secs 秒无成交后，历史衰减随空闲时长成比例加快
+        // (空闲为阈值的 3 倍即按 3 倍速度恢复)，最多 idle_recovery_max_boost 倍；0 表示关闭
+        pub idle_recovery_after_secs: f64,
+        pub idle_recovery_max_boost: f64,
     }
 }
 
@@ -93,12 +233,16 @@ impl Default for AppConfig {
             global_iota: 0.0,
             base_env_index: 1.0,
             noise_std: 0.025,
+            noise_persistence: 0.0,
             weekend_factor: 0.02,
             holiday_factor: 0.15,
             public_holiday_factor: 0.10,
             buy_premium: defaults::BUY_PREMIUM,
+            fresh_mint_surcharge: 0.0,
             recovery_delta: 0.05,
             recovery_tau: 3600.0,
+            recovery_model: RecoveryModel::Exponential,
+            recovery_window_secs: 3600.0,
             version: 1,
             port: 9981,
             is_online_mode: false,
@@ -106,10 +250,241 @@ impl Default for AppConfig {
             winter_end: "02-20".into(),
             summer_start: "07-01".into(),
             summer_end: "08-31".into(),
+            startup_history_load_budget_ms: 2000,
+            lambda_autotune_enabled: false,
+            lambda_autotune_gain: 0.1,
+            lambda_min: 0.0001,
+            lambda_max: 1.0,
+            min_payout: 0.01,
+            sub_minimum_policy: SubMinimumPolicy::Reject,
+            max_body_bytes: 2 * 1024 * 1024,
+            history_lock_timeout_ms: 50,
+            price_snapshot_ttl_secs: 30,
+            max_price_move_pct: 0.0,
+            item_breaker_cooldown_secs: 60,
+            admin_token: String::new(),
+            rounding_policy: RoundingPolicy::Nearest,
+            price_decimals: 2,
+            category_env_deltas: HashMap::new(),
+            skip_auth_on_preview: true,
+            min_recovery_secs: 60.0,
+            strict_config_validation: false,
+            enforce_balances: false,
+            global_price_multiplier: 1.0,
+            trust_client_name: true,
+            exp_table_enabled: false,
+            exp_table_tolerance: 1e-6,
+            display_ema_alpha: 0.0,
+            sell_tax_rate: 0.0,
+            tax_exempt_below: 0.0,
+            snapshot_interval_secs: 300,
+            env_cache_granularity_secs: 1,
+            item_id_allowlist: Vec::new(),
+            log_lang: LogLang::Zh,
+            fsync_policy: FsyncPolicy::Never,
+            mojang_max_concurrency: 8,
+            price_thousands_separator: ",".into(),
+            price_currency_suffix: "coins".into(),
+            max_items_per_response: 0,
+            pricing_script_path: String::new(),
+            script_max_operations: 100_000,
+            script_timeout_ms: 20,
+            env_token_secret: String::new(),
+            env_token_ttl_secs: 60,
+            strict_env_precedence: false,
+            confirm_threshold: 0.0,
+            max_records_per_item: 100,
+            launch_iota: 0.0,
+            launch_iota_half_life_secs: 86_400.0,
+            launch_at_ms: 0,
+            route_prefix: String::new(),
+            health_unprefixed: false,
+            require_known_item: false,
+            catalog_tolerance: 1e-6,
+            load_reference_rps: 200.0,
+            price_event_min_delta_pct: 0.0,
+            max_batch_size: 100,
+            persist_history: true,
+            preview_timeout_ms: 2_000,
+            trade_timeout_ms: 8_000,
+            arbitrage_cooldown_ms: 0,
+            storage_backend: StorageBackendKind::File,
+            sqlite_path: "economy.db".to_string(),
+            dead_letter_path: "history.dead.bin".to_string(),
+            dead_letter_replay_max: 10_000,
+            special_dates: HashMap::new(),
+            shutdown_drain_ms: 2_000,
+            log_validation: false,
+            log_validation_hash_ids: true,
+            allow_negative_n: false,
+            env_history_interval_secs: 300,
+            env_history_max_len: 2_016,
+            env_history_max_age_secs: 604_800,
+            catalog_seed_path: "catalog.csv".into(),
+            idle_recovery_after_secs: 0.0,
+            idle_recovery_max_boost: 4.0,
         }
     }
 }
 
+serializable! {
+    #[derive(Default, Copy, PartialEq, Eq)]
+    pub enum RecoveryModel {
+        /// decay = exp(-δ·t/τ)
+        #[default]
+        Exponential,
+        /// decay = max(0, 1 - t/window)
+        Linear,
+    }
+}
+
+serializable! {
+    #[derive(Default, Copy, PartialEq, Eq)]
+    pub enum RoundingPolicy {
+        #[default]
+        Nearest,
+        /// 卖出收入向下取整，买入花费向上取整
+        HouseFavored,
+    }
+}
+
+serializable! {
+    #[derive(Default, Copy, PartialEq, Eq)]
+    pub enum SubMinimumPolicy {
+        /// 拒绝交易并返回明确提示
+        #[default]
+        Reject,
+        /// 按最低金额成交
+        PayMinimum,
+    }
+}
+
+serializable! {
+    /// 持久化后端
+    #[derive(Default, Copy, PartialEq, Eq)]
+    pub enum StorageBackendKind {
+        /// 工作目录下的 postcard 文件与追加日志
+        #[default]
+        File,
+        /// 单个 SQLite 数据库 (需启用 sqlite 特性)，流水存为可按列查询的表
+        Sqlite,
+    }
+}
+
+serializable! {
+    /// 流水落盘的 fsync 策略：越频繁越耐断电，吞吐越低
+    #[derive(Default, Copy, PartialEq, Eq)]
+    pub enum FsyncPolicy {
+        /// 只刷到操作系统缓存 (断电可能丢失最近已确认的流水，吞吐最高)
+        #[default]
+        Never,
+        /// 每批刷盘后 fsync 一次 (最多丢失一批内的数据)
+        PerBatch,
+        /// 每条记录写入后都 fsync (最耐久，磁盘压力最大)
+        PerRecord,
+    }
+}
+
+serializable! {
+    #[derive(Default, Copy, PartialEq, Eq)]
+    pub enum LogLang {
+        #[default]
+        Zh,
+        En,
+    }
+}
+
+/// 面向玩家与运维的文案 (zh 为原有输出)，模板中的 `{}` 依次替换为参数
+/// 覆盖玩家提示与运行期告警 (成交、同步、写入、快照、死信、WebSocket)；
+/// 读取配置之前的启动与迁移日志无从得知语言，保持中文
+#[derive(Debug, Clone, Copy)]
+pub enum Msg {
+    AuthFailed,
+    InvalidAmount,
+    TradeOk,
+    BelowMinPayout,
+    NameMismatch,
+    BreakerTripped,
+    ChannelFull,
+    ItemsNotAllowed,
+    MarketSynced,
+    StaleSync,
+    DeadLetterFailed,
+    ProbeFailed,
+    WriterRestarted,
+    BatchWriteFailed,
+    RecordWriteFailed,
+    FsyncFailed,
+    SnapshotFailed,
+    SaveRetry,
+    FlushTimeout,
+    WsSlowConsumer,
+    WsLagged,
+}
+
+impl LogLang {
+    pub fn text(self, msg: Msg) -> &'static str {
+        use Msg::*;
+        match (self, msg) {
+            (LogLang::Zh, AuthFailed) => "身份验证失败",
+            (LogLang::En, AuthFailed) => "Authentication failed",
+            (LogLang::Zh, InvalidAmount) => "交易量无效",
+            (LogLang::En, InvalidAmount) => "Invalid trade amount",
+            (LogLang::Zh, TradeOk) => "交易成功 ({})",
+            (LogLang::En, TradeOk) => "Trade succeeded ({})",
+            (LogLang::Zh, BelowMinPayout) => "成交额低于最低结算金额 {}",
+            (LogLang::En, BelowMinPayout) => "Trade value is below the minimum payout {}",
+            (LogLang::Zh, NameMismatch) => "🪪 玩家 {} 上报的名称 {} 与记录的 {} 不符，未采用",
+            (LogLang::En, NameMismatch) => "🪪 Player {} reported name {} which differs from recorded {}; not adopted",
+            (LogLang::Zh, BreakerTripped) => "🧯 物品 {} 单笔价格变动 {}% 触发熔断，冷却 {} 秒",
+            (LogLang::En, BreakerTripped) => "🧯 Item {} moved {}% in one trade; breaker tripped for {}s",
+            (LogLang::Zh, ChannelFull) => "🔥 写入通道背压过高，丢弃日志以保护 API 响应速度",
+            (LogLang::En, ChannelFull) => "🔥 Writer channel is full; dropping record to keep the API responsive",
+            (LogLang::Zh, ItemsNotAllowed) => "🚫 同步请求中 {} 个物品不在白名单内，已忽略: {}",
+            (LogLang::En, ItemsNotAllowed) => "🚫 Ignored {} synced items outside the allowlist: {}",
+            (LogLang::Zh, MarketSynced) => "♻️ 已智能同步 {} 个物品 (状态已保留)",
+            (LogLang::En, MarketSynced) => "♻️ Synced {} items (state preserved)",
+            (LogLang::Zh, StaleSync) => "🔁 拒绝过期的市场同步: 代次 {} <= {}",
+            (LogLang::En, StaleSync) => "🔁 Rejected stale market sync: generation {} <= {}",
+            (LogLang::Zh, DeadLetterFailed) => "❌ 死信日志写入失败，流水已丢失: {}",
+            (LogLang::En, DeadLetterFailed) => "❌ Failed to write dead-letter log; record lost: {}",
+            (LogLang::Zh, ProbeFailed) => "🩺 就绪探针写入失败: {}",
+            (LogLang::En, ProbeFailed) => "🩺 Readiness probe write failed: {}",
+            (LogLang::Zh, WriterRestarted) => "🚨 流水写入任务异常退出: {}，{} 后重启 (未刷盘的批次已丢失)",
+            (LogLang::En, WriterRestarted) => "🚨 History writer exited abnormally: {}; restarting in {} (unflushed batch lost)",
+            (LogLang::Zh, BatchWriteFailed) => "❌ 批量写入 {} 条流水失败: {}",
+            (LogLang::En, BatchWriteFailed) => "❌ Failed to write a batch of {} records: {}",
+            (LogLang::Zh, RecordWriteFailed) => "❌ 批量写入中单条记录失败: {}",
+            (LogLang::En, RecordWriteFailed) => "❌ Failed to write a record in the batch: {}",
+            (LogLang::Zh, FsyncFailed) => "❌ 历史日志 fsync 失败: {}",
+            (LogLang::En, FsyncFailed) => "❌ History log fsync failed: {}",
+            (LogLang::Zh, SnapshotFailed) => "⚠️ {} 快照保存失败: {}",
+            (LogLang::En, SnapshotFailed) => "⚠️ Failed to save snapshot {}: {}",
+            (LogLang::Zh, SaveRetry) => "⚠️ {} 保存失败 (第{}次重试): {}",
+            (LogLang::En, SaveRetry) => "⚠️ Failed to save {} (attempt {}): {}",
+            (LogLang::Zh, FlushTimeout) => "⏰ 刷盘任务超时，部分流水可能丢失。",
+            (LogLang::En, FlushTimeout) => "⏰ History flush timed out; some records may be lost.",
+            (LogLang::Zh, WsSlowConsumer) => "🐢 WebSocket 客户端消费过慢，丢弃一条价格推送",
+            (LogLang::En, WsSlowConsumer) => "🐢 WebSocket client is too slow; dropped a price update",
+            (LogLang::Zh, WsLagged) => "🐢 WebSocket 连接落后 {} 条价格事件",
+            (LogLang::En, WsLagged) => "🐢 WebSocket connection lagged behind by {} price events",
+        }
+    }
+
+    pub fn render(self, msg: Msg, args: &[&dyn std::fmt::Display]) -> String {
+        let mut out = String::new();
+        let mut args = args.iter();
+        let mut pieces = self.text(msg).split("{}").peekable();
+        while let Some(piece) = pieces.next() {
+            out.push_str(piece);
+            if pieces.peek().is_some() {
+                if let Some(arg) = args.next() { out.push_str(&arg.to_string()); }
+            }
+        }
+        out
+    }
+}
+
 serializable! {
     #[derive(Default)] // 其他结构体自动派生 Default
     pub struct MarketItem {
@@ -123,6 +498,60 @@ serializable! {
         pub n: f64,
         #[serde(default)]
         pub iota: f64,
+        // 设置后参与 lambda 自动调参 (每日目标成交量)
+        #[serde(default)]
+        pub target_daily_volume: Option<f64>,
+        // 物品分类 (food / ores / tools ...)，用于分类级环境增量
+        #[serde(default)]
+        pub category: Option<String>,
+        // 首次同步时的初始 n (以折价引入新物品)，已存在的物品忽略
+        #[serde(default)]
+        pub initial_n: Option<f64>,
+        // 结算币种，未设置时使用 DEFAULT_CURRENCY
+        #[serde(default)]
+        pub currency: Option<String>,
+        // 单笔交易量下限 (业务规则，如“至少 1 个”)，与数值保护用的 EPSILON 无关
+        #[serde(default)]
+        pub min_trade_amount: Option<f64>,
+        // 限时基准价 (价格, 到期毫秒)：到期前代替 base_price 参与定价
+        #[serde(default)]
+        pub base_price_override: Option<(f64, i64)>,
+        // 成交额超过该值时需要客户端确认 (覆盖全局 confirm_threshold)
+        #[serde(default)]
+        pub confirm_threshold: Option<f64>,
+        // 该物品每个玩家保留的历史记录数上限 (覆盖全局 max_records_per_item)
+        #[serde(default)]
+        pub max_records: Option<usize>,
+        // 该物品的开市初始 iota (覆盖全局 launch_iota)
+        #[serde(default)]
+        pub launch_iota: Option<f64>,
+        // 有效库存的持久下限 (不随成交消耗，区别于 initial_n)，0 表示不限制
+        #[serde(default)]
+        pub floor_n: f64,
+        // 有效库存上限：超过后价格不再继续下跌，防止无限倾销把价格压到接近 0
+        #[serde(default)]
+        pub max_n: Option<f64>,
+        // 结算币种 (区别于定价币种 currency)：成交额按 conversion_rate 换算后以该币种结算
+        #[serde(default)]
+        pub payout_currency: Option<String>,
+        #[serde(default)]
+        pub conversion_rate: Option<f64>,
+        // 单价上限 (定价币种)：卖出对曲线价封顶；买入对加上 buy_premium 与稀缺附加费之后的最终价封顶，
+        // 即溢价不能把买入价推过该上限。两侧共用同一个上限，买入价因此可能等于卖出价
+        #[serde(default)]
+        pub max_price: Option<f64>,
+        // 最近一次成交的毫秒时间戳 (服务端维护，0 表示尚无成交)
+        #[serde(default)]
+        pub last_trade_ms: i64,
+        // 环境指数中噪声分量的倍数 (投机品 > 1，必需品 < 1)，确定性部分全服共享；未设置为 1
+        #[serde(default)]
+        pub noise_multiplier: Option<f64>,
+        // 空闲加速恢复折算出的额外恢复时长 (毫秒，服务端维护)：对 idle_credit_before_ms 之前的
+        // 成交记录计入，成交记录本身的时间戳不做改动
+        #[serde(default)]
+        pub idle_credit_ms: i64,
+        #[serde(default)]
+        pub idle_credit_before_ms: i64,
     }
 }
 
@@ -132,16 +561,23 @@ web_model! {
         pub buy_price: f64,
         pub neff: f64,
         pub base_price: f64,
+        // 本次定价实际使用的 lambda (含自动调参结果)
+        pub effective_lambda: f64,
+        // 平滑后的展示价 (EMA)，仅用于展示，成交仍按曲线现价
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub display_price: Option<f64>,
     }
 }
 
 impl MarketItemStatus {
-    pub fn new(price: f64, buy_price: f64, neff: f64, base_price: f64) -> Self {
+    pub fn new(price: f64, buy_price: f64, neff: f64, base_price: f64, effective_lambda: f64) -> Self {
         Self {
             price: price.round_2(),
             buy_price: buy_price.round_2(),
             neff: neff.round_2(),
             base_price,
+            effective_lambda,
+            display_price: None,
         }
     }
 }
@@ -153,6 +589,12 @@ serializable! {
         pub last_update: i64,
         pub timestamp: i64,
         pub note: String,
+        // 上一次的噪声值，用于 AR(1) 平滑
+        #[serde(default)]
+        pub last_noise: f64,
+        // 叠加噪声之前的确定性指数 (同样钳制到下限)
+        #[serde(default)]
+        pub base_index: f64,
     }
 }
 
@@ -168,6 +610,9 @@ serializable! {
         pub env_index: f64,
         #[serde(default)]
         pub price: f64, // 对应 api.rs 中的使用
+        // 同一毫秒内的序号：(timestamp, seq) 在同一玩家同一物品下唯一
+        #[serde(default)]
+        pub seq: u32,
     }
 }
 
@@ -193,6 +638,17 @@ serializable! {
         pub player_name: String,
         pub item_id: String,
         pub note: Cow<'static, str>,
+        // 单调递增的成交序号 (即响应中的 tradeId)，供客户端对账去重；0 表示未分配
+        pub seq: u64,
+        // 换算后的结算金额与币种 (物品未设置 payout_currency 时为空，按定价币种的 total_price 结算)
+        #[serde(default)]
+        pub payout: Option<f64>,
+        #[serde(default)]
+        pub payout_currency: Option<String>,
+        // 结构化元数据，如 { "auth": "OnlineAuth", "env": "Holiday+Winter", "effective_n": "12.3" }
+        // 字段始终存在以保证各 feature 组合的落盘布局一致；未开启 trade-metadata 时为空表
+        #[serde(default)]
+        pub metadata: HashMap<String, String>,
     }
 }
 
@@ -205,15 +661,61 @@ impl TransactionRecord {
         Self {
             timestamp: ts, amount: amt, total_price: tp, avg_price: ap,
             env_index: ei, action: act, player_id: pid, player_name: pnm, item_id: iid,
-            note: "".into(),
+            note: "".into(), seq: 0, payout: None, payout_currency: None,
+            metadata: HashMap::new(),
         }
     }
+
+    /// 解析一条 postcard 流水，依次回退到缺少 metadata (v1) 与缺少 seq (v0) 的旧布局
+    pub fn decode(bytes: &[u8]) -> postcard::Result<Self> {
+        postcard::from_bytes::<Self>(bytes)
+            .or_else(|_| postcard::from_bytes::<v1::TransactionRecord>(bytes).map(Self::from))
+            .or_else(|_| postcard::from_bytes::<v0::TransactionRecord>(bytes).map(Self::from))
+    }
+
+    /// 同 [`Self::decode`]，输入为 COBS 分帧
+    pub fn decode_cobs(frame: &[u8]) -> postcard::Result<Self> {
+        postcard::from_bytes_cobs::<Self>(&mut frame.to_vec())
+            .or_else(|_| postcard::from_bytes_cobs::<v1::TransactionRecord>(&mut frame.to_vec()).map(Self::from))
+            .or_else(|_| postcard::from_bytes_cobs::<v0::TransactionRecord>(&mut frame.to_vec()).map(Self::from))
+    }
 }
 
 // =========================================================================
 // 5. API 请求/响应模型 (Strict Alignment)
 // =========================================================================
 
+/// 交易动作：BUY/SELL 对应原有行为，CUSTOM 允许自定义标签并选择定价分支
+/// JSON 示例: `"BUY"` 或 `{"CUSTOM": {"label": "AUCTION", "pricesAsBuy": false}}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE", rename_all_fields = "camelCase")]
+pub enum TradeAction {
+    Buy,
+    #[default]
+    Sell,
+    Custom { label: String, prices_as_buy: bool },
+}
+
+impl TradeAction {
+    /// 是否走买入定价分支
+    pub fn is_buy(&self) -> bool {
+        match self {
+            Self::Buy => true,
+            Self::Sell => false,
+            Self::Custom { prices_as_buy, .. } => *prices_as_buy,
+        }
+    }
+
+    /// 写入流水记录的动作标签
+    pub fn label(&self) -> &str {
+        match self {
+            Self::Buy => "BUY",
+            Self::Sell => "SELL",
+            Self::Custom { label, .. } => label,
+        }
+    }
+}
+
 web_model! {
     pub struct TradeRequest {
         pub player_id: String,
@@ -225,7 +727,16 @@ web_model! {
         pub decay_lambda: f64,
         pub iota: Option<f64>,
         pub manual_env_index: Option<f64>,
+        // 环境冻结令牌 (由 /api/market/env_token 签发)，有效期内按令牌中的指数定价
+        #[serde(default)]
+        pub env_token: Option<String>,
+        // 大额交易的二次确认 (见 confirm_threshold)
+        #[serde(default)]
+        pub confirmed: bool,
         pub is_preview: bool,
+        // 未提供时由路由决定 (calculate_buy -> BUY, calculate_sell -> SELL)
+        #[serde(default)]
+        pub action: Option<TradeAction>,
     }
 }
 
@@ -238,6 +749,20 @@ web_model! {
         pub unit_price_avg: f64,
         pub env_index: f64,
         pub effective_n: f64,
+        // 实际成交时分配的序号 (预览与失败的交易没有)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub trade_id: Option<u64>,
+        // 定价实际使用的环境指数 (未取整)，成交流水记录的即是该值
+        pub quote_env_index: f64,
+        // 环境指数已被压到下限 MIN_ENV_INDEX，价格不会再因环境更低
+        pub env_floored: bool,
+        // 服务端负载 0.0–1.0，客户端可据此放慢轮询
+        pub server_load: f64,
+        // 物品设置了 payout_currency 时，换算后的结算金额与币种
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub payout: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub payout_currency: Option<String>,
     }
 }
 
@@ -252,6 +777,18 @@ web_model! {
 web_model! {
     pub struct BatchTradeResponse {
         pub results: Vec<TradeResponse>,
+        pub succeeded: usize,
+        pub failed: usize,
+        // 成功行的成交总额
+        pub total_value: f64,
+    }
+}
+
+impl BatchTradeResponse {
+    pub fn new(results: Vec<TradeResponse>) -> Self {
+        let succeeded = results.iter().filter(|r| r.success).count();
+        let total_value: f64 = results.iter().filter(|r| r.success).map(|r| r.total_price).sum();
+        Self { failed: results.len() - succeeded, succeeded, total_value: total_value.round_2(), results }
     }
 }
 
@@ -259,6 +796,104 @@ web_model! {
     pub struct MarketPriceRequest {
         #[serde(default)]
         pub item_ids: Vec<String>,
+        // 阶梯报价数量 (如 1 / 16 / 64)，为空时不返回 tiers
+        #[serde(default)]
+        pub quantities: Vec<f64>,
+        // 为每个物品附带格式化好的价格文本 (priceLabel)
+        #[serde(default)]
+        pub format_price: bool,
+        // 分页游标：上一页返回的 nextCursor
+        #[serde(default)]
+        pub cursor: Option<String>,
+        // 为每个物品附带价格构成说明 (explanation)，供玩家提示使用
+        #[serde(default)]
+        pub explain: bool,
+    }
+}
+
+web_model! {
+    /// 按当前库存一次性成交 `quantity` 个的总价与均价
+    pub struct PriceTier {
+        pub quantity: f64,
+        pub sell_total: f64,
+        pub sell_avg: f64,
+        pub buy_total: f64,
+        pub buy_avg: f64,
+    }
+}
+
+/// 玩家余额：(玩家 ID, 币种) -> 余额
+pub type PlayerBalances = HashMap<(String, String), f64>;
+pub const DEFAULT_CURRENCY: &str = "default";
+
+web_model! {
+    pub struct BalanceGrantRequest {
+        pub player_id: String,
+        #[serde(default)]
+        pub currency: Option<String>,
+        // 可为负数 (扣款)
+        pub amount: f64,
+    }
+}
+
+web_model! {
+    pub struct BasePriceOverrideRequest {
+        pub price: f64,
+        // 到期时间 (毫秒时间戳)
+        pub expires_at: i64,
+    }
+}
+
+web_model! {
+    pub struct PlayerRenameRequest {
+        pub player_id: String,
+        pub player_name: String,
+    }
+}
+
+web_model! {
+    pub struct RecoveryQuery {
+        // 目标价格占基准价的比例，(0, 1]
+        #[serde(alias = "target_ratio")]
+        pub target_ratio: f64,
+    }
+}
+
+web_model! {
+    pub struct RoundTripRequest {
+        pub item_id: String,
+        pub amount: f64,
+    }
+}
+
+// 管理端价格轨迹模拟：按物品参数在临时历史上依次回放假设的成交
+web_model! {
+    pub struct SimulationStep {
+        pub action: TradeAction,
+        pub amount: f64,
+        // 距上一步 (首步为距起点) 的毫秒数，期间历史按恢复模型衰减
+        pub elapsed_ms: i64,
+    }
+}
+
+web_model! {
+    pub struct SimulateSequenceRequest {
+        pub item: MarketItem,
+        // 固定的环境指数，缺省为 1.0 (不受实时环境与噪声影响，结果可复现)
+        pub env_index: Option<f64>,
+        pub steps: Vec<SimulationStep>,
+    }
+}
+
+serializable! {
+    pub struct SimulationPoint {
+        // 距起点的毫秒数
+        pub time_ms: i64,
+        pub total_price: f64,
+        pub unit_price: f64,
+        // 成交前 (定价所用) 与成交后的有效库存
+        pub n_before: f64,
+        pub n_after: f64,
     }
 }
 
@@ -267,5 +902,328 @@ web_model! {
 web_model! {
     pub struct MarketSyncRequest {
         pub items: Vec<MarketItem>,
+        // 同步代次：携带时必须大于已应用的代次，否则视为过期同步被拒绝
+        #[serde(default)]
+        pub generation: Option<u64>,
+    }
+}
+
+// =========================================================================
+// 6. 存盘格式版本与旧版迁移
+// =========================================================================
+
+/// 存盘文件头魔数，其后为小端 u16 格式版本，再之后是 postcard 数据
+/// 没有文件头的文件视为 v0 (早期版本直接写出的 postcard 裸数据)
+pub const STORE_MAGIC: &[u8; 4] = b"ECOS";
+/// 当前存盘布局版本：持久化结构体增删字段或调整顺序时递增，并在 Persisted 中补充迁移
+pub const STORE_FORMAT_VERSION: u16 = 1;
+
+/// 可持久化的快照类型
+/// postcard 不自描述 (`#[serde(default)]` 对它无效)，布局变化后旧文件只能按旧布局解析再显式迁移
+pub trait Persisted: Serialize + serde::de::DeserializeOwned {
+    /// v0 (无文件头) 文件的布局
+    type V0: serde::de::DeserializeOwned;
+    fn from_v0(old: Self::V0) -> Self;
+    /// 加载后修正非有限数值 (历史 bug 写入的 NaN 会被 postcard 原样读回)，返回修正的条目数
+    fn sanitize(&mut self) -> usize { 0 }
+}
+
+/// 自 v0 以来布局未变 (或 v0 时尚不存在) 的类型
+macro_rules! persisted_unchanged {
+    ($($ty:ty),* $(,)?) => {
+        $(impl Persisted for $ty {
+            type V0 = Self;
+            fn from_v0(old: Self) -> Self { old }
+        })*
+    };
+}
+
+persisted_unchanged!(i64, u64, PlayerBalances);
+
+impl Persisted for AppConfig {
+    type V0 = v0::AppConfig;
+    fn from_v0(old: v0::AppConfig) -> Self {
+        Self {
+            global_iota: old.global_iota,
+            base_env_index: old.base_env_index,
+            noise_std: old.noise_std,
+            weekend_factor: old.weekend_factor,
+            holiday_factor: old.holiday_factor,
+            public_holiday_factor: old.public_holiday_factor,
+            buy_premium: old.buy_premium,
+            recovery_delta: old.recovery_delta,
+            recovery_tau: old.recovery_tau,
+            version: old.version,
+            port: old.port,
+            is_online_mode: old.is_online_mode,
+            winter_start: old.winter_start,
+            winter_end: old.winter_end,
+            summer_start: old.summer_start,
+            summer_end: old.summer_end,
+            ..Default::default()
+        }
+    }
+}
+
+impl Persisted for Vec<MarketItem> {
+    type V0 = Vec<v0::MarketItem>;
+    fn from_v0(old: Self::V0) -> Self {
+        old.into_iter().map(MarketItem::from).collect()
+    }
+    fn sanitize(&mut self) -> usize {
+        sanitize_market_items(self)
+    }
+}
+
+impl Persisted for HashMap<String, PlayerSalesHistory> {
+    type V0 = HashMap<String, v0::PlayerSalesHistory>;
+    fn from_v0(old: Self::V0) -> Self {
+        old.into_iter().map(|(id, h)| (id, h.into())).collect()
+    }
+    fn sanitize(&mut self) -> usize {
+        let mut dropped = 0;
+        for history in self.values_mut() {
+            for records in history.item_sales.values_mut() {
+                let before = records.len();
+                records.retain(SalesRecord::is_finite);
+                dropped += before - records.len();
+            }
+        }
+        dropped
+    }
+}
+
+impl Persisted for Option<EnvCache> {
+    type V0 = Option<v0::EnvCache>;
+    fn from_v0(old: Self::V0) -> Self {
+        old.map(|c| EnvCache {
+            index: c.index,
+            last_update: c.last_update,
+            timestamp: c.timestamp,
+            note: c.note,
+            // v0 没有保存噪声，从零开始平滑，整个指数视为确定性部分
+            base_index: c.index,
+            ..Default::default()
+        })
+    }
+}
+
+impl AppConfig {
+    /// 有效库存的通用下限：allow_negative_n 时不截断，否则不低于 0
+    pub fn floor_effective_n(&self, n: f64) -> f64 {
+        if self.allow_negative_n { n } else { n.max(0.0) }
+    }
+}
+
+impl MarketItem {
+    /// 尚未到期的限时基准价
+    pub fn active_base_price_override(&self, now_ms: i64) -> Option<f64> {
+        self.base_price_override.filter(|&(_, expires_at)| now_ms < expires_at).map(|(price, _)| price)
+    }
+
+    pub fn effective_base_price(&self, now_ms: i64) -> f64 {
+        self.active_base_price_override(now_ms).unwrap_or(self.base_price)
+    }
+
+    /// 将各项贡献之和约束到 [floor_n, max_n]；未开启 allow_negative_n 时下限至少为 0
+    pub fn clamp_n(&self, n: f64, config: &AppConfig) -> f64 {
+        let n = config.floor_effective_n(n);
+        let n = if self.floor_n != 0.0 { n.max(self.floor_n) } else { n };
+        self.max_n.map_or(n, |max| n.min(max))
+    }
+
+    /// 按 max_price 封顶成交总额 (上限 × 数量)，未设置时原样返回
+    pub fn cap_total(&self, total: f64, amount: f64) -> f64 {
+        self.max_price.map_or(total, |max| total.min(max * amount.abs()))
+    }
+
+    /// 换算后的 (结算金额, 结算币种)；未设置 payout_currency 时为 None，按定价币种原额结算
+    pub fn payout(&self, total_price: f64) -> Option<(f64, String)> {
+        let currency = self.payout_currency.clone()?;
+        Some(((total_price * self.conversion_rate.unwrap_or(1.0)).round_2(), currency))
+    }
+
+    /// 将非有限的 n / iota / base_price / lambda 重置为 0，返回被修正的字段名
+    /// base_price 归零后交易入口 (check_tradable) 拒绝该物品，避免以错误价格继续结算
+    pub fn sanitize(&mut self) -> Vec<&'static str> {
+        let mut fixed = Vec::new();
+        for (name, value) in [
+            ("n", &mut self.n), ("iota", &mut self.iota),
+            ("basePrice", &mut self.base_price), ("lambda", &mut self.lambda),
+        ] {
+            if !value.is_finite() {
+                *value = 0.0;
+                fixed.push(name);
+            }
+        }
+        fixed
+    }
+}
+
+/// 修正一组物品中的非有限数值并逐个告警，返回被修正的物品数
+pub fn sanitize_market_items(items: &mut [MarketItem]) -> usize {
+    let mut count = 0;
+    for item in items {
+        let fixed = item.sanitize();
+        if !fixed.is_empty() {
+            tracing::warn!("🧹 物品 {} 的 {:?} 为非有限数值，已重置为 0", item.id, fixed);
+            count += 1;
+        }
+    }
+    count
+}
+
+impl SalesRecord {
+    pub fn is_finite(&self) -> bool {
+        self.amount.is_finite() && self.price.is_finite() && self.env_index.is_finite()
+    }
+}
+
+impl From<v0::MarketItem> for MarketItem {
+    fn from(old: v0::MarketItem) -> Self {
+        Self {
+            id: old.id, name: old.name, base_price: old.base_price,
+            lambda: old.lambda, n: old.n, iota: old.iota,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<v0::TransactionRecord> for TransactionRecord {
+    fn from(old: v0::TransactionRecord) -> Self {
+        let mut record = TransactionRecord::new(
+            old.timestamp, old.amount, old.total_price, old.avg_price,
+            old.env_index, old.action, old.player_id, old.player_name, old.item_id,
+        );
+        record.note = old.note;
+        record
     }
-}
\ No newline at end of file
+}
+
+impl From<v1::TransactionRecord> for TransactionRecord {
+    fn from(old: v1::TransactionRecord) -> Self {
+        let v1::TransactionRecord {
+            timestamp, amount, total_price, avg_price, env_index, action,
+            player_id, player_name, item_id, note, seq, payout, payout_currency,
+        } = old;
+        Self {
+            timestamp, amount, total_price, avg_price, env_index, action,
+            player_id, player_name, item_id, note, seq, payout, payout_currency,
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+impl From<v0::PlayerSalesHistory> for PlayerSalesHistory {
+    fn from(old: v0::PlayerSalesHistory) -> Self {
+        let item_sales = old.item_sales.into_iter().map(|(item_id, records)| {
+            let migrated = records.into_iter().map(|r| SalesRecord {
+                timestamp: r.timestamp, amount: r.amount,
+                env_index: r.env_index, price: r.price, seq: 0,
+            }).collect();
+            (item_id, migrated)
+        }).collect();
+        Self { player_id: old.player_id, player_name: old.player_name, item_sales }
+    }
+}
+
+/// 引入 metadata 之前的流水布局 (当时 metadata 仅在 trade-metadata 构建中存在)
+pub mod v1 {
+    use super::*;
+
+    serializable! {
+        pub struct TransactionRecord {
+            pub timestamp: i64,
+            pub amount: f64,
+            pub total_price: f64,
+            pub avg_price: f64,
+            pub env_index: f64,
+            pub action: String,
+            pub player_id: String,
+            pub player_name: String,
+            pub item_id: String,
+            pub note: Cow<'static, str>,
+            pub seq: u64,
+            pub payout: Option<f64>,
+            pub payout_currency: Option<String>,
+        }
+    }
+}
+
+/// v0 布局 (字段顺序即 postcard 编码顺序，不得修改)
+pub mod v0 {
+    use super::*;
+
+    serializable! {
+        pub struct AppConfig {
+            pub global_iota: f64,
+            pub base_env_index: f64,
+            pub noise_std: f64,
+            pub weekend_factor: f64,
+            pub holiday_factor: f64,
+            pub public_holiday_factor: f64,
+            pub buy_premium: f64,
+            pub recovery_delta: f64,
+            pub recovery_tau: f64,
+            pub version: u32,
+            pub port: u16,
+            pub is_online_mode: bool,
+            pub winter_start: Cow<'static, str>,
+            pub winter_end: Cow<'static, str>,
+            pub summer_start: Cow<'static, str>,
+            pub summer_end: Cow<'static, str>,
+        }
+    }
+
+    serializable! {
+        pub struct MarketItem {
+            pub id: String,
+            pub name: Cow<'static, str>,
+            pub base_price: f64,
+            pub lambda: f64,
+            pub n: f64,
+            pub iota: f64,
+        }
+    }
+
+    serializable! {
+        pub struct EnvCache {
+            pub index: f64,
+            pub last_update: i64,
+            pub timestamp: i64,
+            pub note: String,
+        }
+    }
+
+    serializable! {
+        pub struct SalesRecord {
+            pub timestamp: i64,
+            pub amount: f64,
+            pub env_index: f64,
+            pub price: f64,
+        }
+    }
+
+    serializable! {
+        pub struct PlayerSalesHistory {
+            pub player_id: String,
+            pub player_name: String,
+            pub item_sales: FxHashMap<String, Vec<SalesRecord>>,
+        }
+    }
+
+    serializable! {
+        pub struct TransactionRecord {
+            pub timestamp: i64,
+            pub amount: f64,
+            pub total_price: f64,
+            pub avg_price: f64,
+            pub env_index: f64,
+            pub action: String,
+            pub player_id: String,
+            pub player_name: String,
+            pub item_id: String,
+            pub note: Cow<'static, str>,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! 可选的 Rhai 定价脚本钩子
+//!
+//! 脚本可读取 `total` / `amount` / `is_buy` / `item_id` / `env_index` / `n_eff` / `base_price`，
+//! 返回调整后的总额 (f64)。脚本出错、超出操作数或耗时限制、返回非有限或负数时沿用原总额。
+
+use crate::models::AppConfig;
+use parking_lot::RwLock;
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::{sync::Arc, time::{Duration, Instant}};
+
+pub struct PricingContext<'a> {
+    pub item_id: &'a str,
+    pub amount: f64,
+    pub is_buy: bool,
+    pub env_index: f64,
+    pub n_eff: f64,
+    pub base_price: f64,
+}
+
+// 已编译的脚本 (路径, AST)；修改脚本内容后需重启或更换路径
+static COMPILED: RwLock<Option<(String, Arc<AST>)>> = parking_lot::const_rwlock(None);
+
+fn compiled(path: &str) -> Result<Arc<AST>, String> {
+    if let Some((cached, ast)) = COMPILED.read().as_ref() {
+        if cached == path { return Ok(ast.clone()); }
+    }
+    let source = std::fs::read_to_string(path).map_err(|e| format!("读取失败: {}", e))?;
+    let ast = Arc::new(Engine::new().compile(source).map_err(|e| format!("编译失败: {}", e))?);
+    *COMPILED.write() = Some((path.to_string(), ast.clone()));
+    Ok(ast)
+}
+
+/// 受限的执行环境：操作数、调用深度、容器大小与墙钟时间均有上限
+fn sandboxed_engine(config: &AppConfig) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(config.script_max_operations.max(1));
+    engine.set_max_call_levels(16);
+    engine.set_max_string_size(4096);
+    engine.set_max_array_size(1024);
+    engine.set_max_map_size(1024);
+    let deadline = Instant::now() + Duration::from_millis(config.script_timeout_ms);
+    engine.on_progress(move |_| (Instant::now() > deadline).then_some(Dynamic::UNIT));
+    engine
+}
+
+/// 运行定价脚本，返回调整后的总额；未配置脚本或脚本失败时返回原值
+pub fn adjust_total(config: &AppConfig, ctx: &PricingContext, total: f64) -> f64 {
+    if config.pricing_script_path.is_empty() { return total; }
+
+    let result = compiled(&config.pricing_script_path).and_then(|ast| {
+        let mut scope = Scope::new();
+        scope.push("total", total)
+            .push("amount", ctx.amount)
+            .push("is_buy", ctx.is_buy)
+            .push("item_id", ctx.item_id.to_string())
+            .push("env_index", ctx.env_index)
+            .push("n_eff", ctx.n_eff)
+            .push("base_price", ctx.base_price);
+        sandboxed_engine(config)
+            .eval_ast_with_scope::<f64>(&mut scope, &ast)
+            .map_err(|e| format!("执行失败: {}", e))
+    });
+
+    match result {
+        Ok(adjusted) if adjusted.is_finite() && adjusted >= 0.0 => adjusted,
+        Ok(adjusted) => {
+            tracing::warn!("📜 定价脚本返回无效总额 {}，沿用原价 {:.2}", adjusted, total);
+            total
+        }
+        Err(e) => {
+            tracing::warn!("📜 定价脚本{}，沿用原价 {:.2}", e, total);
+            total
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx() -> PricingContext<'static> {
+        PricingContext { item_id: "stone", amount: 1.0, is_buy: false, env_index: 1.0, n_eff: 0.0, base_price: 100.0 }
+    }
+
+    fn script(name: &str, source: &str) -> AppConfig {
+        let path = std::env::temp_dir().join(format!("economy-core-{}-{}.rhai", std::process::id(), name));
+        std::fs::write(&path, source).unwrap();
+        AppConfig { pricing_script_path: path.to_string_lossy().into_owned(), ..Default::default() }
+    }
+
+    #[test]
+    fn script_can_halve_the_price() {
+        let config = script("halve", "if item_id == \"stone\" { total / 2.0 } else { total }");
+        assert_eq!(adjust_total(&config, &ctx(), 80.0), 40.0);
+    }
+
+    #[test]
+    fn runaway_script_is_stopped_and_ignored() {
+        let config = script("runaway", "let x = 0; loop { x += 1; } total");
+        let started = Instant::now();
+        assert_eq!(adjust_total(&config, &ctx(), 80.0), 80.0);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,195 @@
+//! 可替换的持久化后端
+//!
+//! 快照以 `Storage::encode` 的输出 (文件头 + postcard) 按名称存取，成交流水按批追加。
+//! 默认的文件后端即原有布局；SQLite 后端 (需启用 sqlite 特性) 把快照与流水放进同一个数据库，
+//! 流水按列存入 `history` 表，可直接用 SQL 按玩家、物品与时间过滤。
+//! 配置文件本身决定后端，始终是本地文件。
+
+use crate::models::{AppConfig, StorageBackendKind, TransactionRecord};
+use crate::{Storage, HISTORY_LOG_HEADER};
+use std::{collections::VecDeque, fs, io::{self, Write}, sync::OnceLock, time::Duration};
+
+pub trait StorageBackend: Send + Sync {
+    /// 读取已编码的快照，不存在为 Ok(None)
+    fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>>;
+    /// 整体替换快照 (原子写入)
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()>;
+    /// 向流水日志追加一批记录
+    fn append(&self, log: &str, records: &[TransactionRecord]) -> io::Result<()>;
+    /// 最近的 `limit` 条流水，按时间正序
+    fn load_history(&self, log: &str, limit: usize) -> io::Result<VecDeque<TransactionRecord>>;
+}
+
+// 启动时按配置安装；未安装 (如测试) 时使用文件后端
+static BACKEND: OnceLock<Box<dyn StorageBackend>> = OnceLock::new();
+
+pub fn install(backend: Box<dyn StorageBackend>) {
+    if BACKEND.set(backend).is_err() {
+        tracing::warn!("⚠️ 存储后端已安装，忽略重复安装");
+    }
+}
+
+pub fn backend() -> &'static dyn StorageBackend {
+    BACKEND.get().map_or(&FileBackend, |b| b.as_ref())
+}
+
+/// 按配置打开存储后端；选择了未编译进来的后端时返回错误
+/// `read_only` 时以只读方式打开 (SQLite 不建表、不切换 WAL)
+pub fn open_backend(config: &AppConfig, read_only: bool) -> io::Result<Box<dyn StorageBackend>> {
+    match config.storage_backend {
+        StorageBackendKind::File => Ok(Box::new(FileBackend)),
+        #[cfg(feature = "sqlite")]
+        StorageBackendKind::Sqlite => Ok(Box::new(SqliteBackend::open(&config.sqlite_path, read_only)?)),
+        #[cfg(not(feature = "sqlite"))]
+        StorageBackendKind::Sqlite => {
+            let _ = read_only;
+            Err(io::Error::new(io::ErrorKind::Unsupported, "storage_backend = sqlite 需要启用 sqlite 特性"))
+        }
+    }
+}
+
+/// 文件后端：快照名即路径 (相对工作目录)，流水为带文件头的 COBS 分帧日志
+pub struct FileBackend;
+
+impl StorageBackend for FileBackend {
+    fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(name) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let temp_path = format!("{}.tmp", name);
+        fs::write(&temp_path, bytes)?;
+        fs::rename(&temp_path, name)
+    }
+
+    /// 同步追加 (运行期的流水由异步写入任务负责，这里供工具与测试使用)
+    fn append(&self, log: &str, records: &[TransactionRecord]) -> io::Result<()> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(log)?;
+        let is_new = file.metadata()?.len() == 0;
+        let mut out = io::BufWriter::new(file);
+        if is_new {
+            out.write_all(&HISTORY_LOG_HEADER)?;
+        }
+        for record in records {
+            let bytes = postcard::to_stdvec_cobs(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            out.write_all(&bytes)?;
+        }
+        out.flush()
+    }
+
+    fn load_history(&self, log: &str, limit: usize) -> io::Result<VecDeque<TransactionRecord>> {
+        Ok(Storage::load_history_tail(log, limit, Duration::MAX))
+    }
+}
+
+/// SQLite 后端：`snapshots(name, data)` 保存快照，`history` 表每行一条流水
+/// 常用过滤列单独成列并建索引，`record` 列保存完整的 postcard 编码记录
+#[cfg(feature = "sqlite")]
+pub struct SqliteBackend {
+    conn: parking_lot::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteBackend {
+    /// `read_only` 时只读打开已有数据库：不存在时报错，不建表、不切换 journal 模式
+    pub fn open(path: &str, read_only: bool) -> io::Result<Self> {
+        if read_only {
+            let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX;
+            let conn = rusqlite::Connection::open_with_flags(path, flags).map_err(io::Error::other)?;
+            tracing::info!("🗄️ 已以只读方式打开 SQLite 存储: {}", path);
+            return Ok(Self { conn: parking_lot::Mutex::new(conn) });
+        }
+        let conn = rusqlite::Connection::open(path).map_err(io::Error::other)?;
+        // WAL 模式下外部只读查询不会阻塞写入
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(())).map_err(io::Error::other)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS snapshots (
+                name TEXT PRIMARY KEY,
+                data BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                seq INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                player_id TEXT NOT NULL,
+                item_id TEXT NOT NULL,
+                action TEXT NOT NULL,
+                amount REAL NOT NULL,
+                total_price REAL NOT NULL,
+                record BLOB NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS history_player ON history (player_id, timestamp);
+            CREATE INDEX IF NOT EXISTS history_item ON history (item_id, timestamp);",
+        ).map_err(io::Error::other)?;
+        tracing::info!("🗄️ 已打开 SQLite 存储: {}", path);
+        Ok(Self { conn: parking_lot::Mutex::new(conn) })
+    }
+
+    // 文件后端的快照名可能带目录 (如 ./market_data.bin)，数据库中只按文件名存取
+    fn key(name: &str) -> &str {
+        std::path::Path::new(name).file_name().and_then(|n| n.to_str()).unwrap_or(name)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl StorageBackend for SqliteBackend {
+    fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension;
+        self.conn.lock()
+            .query_row("SELECT data FROM snapshots WHERE name = ?1", [Self::key(name)], |row| row.get(0))
+            .optional()
+            .map_err(io::Error::other)
+    }
+
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        self.conn.lock().execute(
+            "INSERT INTO snapshots (name, data) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET data = excluded.data",
+            rusqlite::params![Self::key(name), bytes],
+        ).map(|_| ()).map_err(io::Error::other)
+    }
+
+    /// 整批在一个事务内写入，失败时整批回滚
+    fn append(&self, _log: &str, records: &[TransactionRecord]) -> io::Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(io::Error::other)?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO history (seq, timestamp, player_id, item_id, action, amount, total_price, record)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            ).map_err(io::Error::other)?;
+            for r in records {
+                let encoded = postcard::to_stdvec(r).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                insert.execute(rusqlite::params![
+                    r.seq as i64, r.timestamp, r.player_id, r.item_id, r.action, r.amount, r.total_price, encoded,
+                ]).map_err(io::Error::other)?;
+            }
+        }
+        tx.commit().map_err(io::Error::other)
+    }
+
+    fn load_history(&self, _log: &str, limit: usize) -> io::Result<VecDeque<TransactionRecord>> {
+        let conn = self.conn.lock();
+        let mut query = conn.prepare("SELECT record FROM history ORDER BY id DESC LIMIT ?1").map_err(io::Error::other)?;
+        let rows = query
+            .query_map([i64::try_from(limit).unwrap_or(i64::MAX)], |row| row.get::<_, Vec<u8>>(0))
+            .map_err(io::Error::other)?;
+
+        let mut records = VecDeque::new();
+        let mut corrupted = 0usize;
+        for row in rows {
+            match TransactionRecord::decode(&row.map_err(io::Error::other)?) {
+                Ok(record) => records.push_front(record),
+                Err(_) => corrupted += 1,
+            }
+        }
+        if corrupted > 0 {
+            tracing::warn!("⚠️ history 表中有 {} 行无法解析，已跳过", corrupted);
+        }
+        Ok(records)
+    }
+}
@@ -0,0 +1,155 @@
+use axum::{extract::{State, ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade}}, response::IntoResponse};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::{collections::HashSet, sync::Arc};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::AppState;
+use crate::api;
+use crate::models::{Msg, TradeAction, TradeRequest};
+
+// 每个连接的待发送消息上限，超出时丢弃价格推送 (指令回复仍会等待)
+const OUTBOX_CAPACITY: usize = 256;
+
+// =========================================================================
+// 1. 指令模型
+// =========================================================================
+
+/// Java 端通过 WebSocket 发送的指令，例如 `{"cmd": "subscribe", "itemIds": ["diamond"]}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "camelCase", rename_all_fields = "camelCase")]
+enum WsCommand {
+    Quote {
+        #[serde(default)]
+        item_ids: Vec<String>,
+        #[serde(default)]
+        quantities: Vec<f64>,
+        // 上一页返回的 nextCursor，与 HTTP 行情接口的分页一致
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+    Subscribe { item_ids: Vec<String> },
+    Unsubscribe { item_ids: Vec<String> },
+    Trade {
+        #[serde(default)]
+        is_buy: bool,
+        request: TradeRequest,
+    },
+}
+
+/// 物品价格推送：每个价格事件只构建并序列化一次，所有订阅连接共享同一份消息
+#[derive(Clone)]
+pub struct PriceUpdate {
+    pub item_id: Arc<str>,
+    pub message: Utf8Bytes,
+}
+
+/// 物品价格变动后发布推送 (没有连接时跳过)；报价在独立任务中计算，不占用成交路径
+/// 相对上次推送的变动不足 price_event_min_delta_pct 时不推送
+pub fn publish_price_update(state: &AppState, item_id: String) {
+    if state.price_events.receiver_count() == 0 { return; }
+    let state = state.clone();
+    tokio::spawn(async move {
+        // 历史繁忙时跳过本次推送，下一次价格事件会带上最新价格
+        let Ok(quote) = api::quote_market(&state, vec![item_id.clone()], &[]).await else { return };
+        if let Some(price) = quote["items"][item_id.as_str()]["price"].as_f64() {
+            let min_pct = state.config.read().price_event_min_delta_pct;
+            let mut published = state.published_prices.write();
+            let moved = |prev: f64| prev <= 0.0 || (price - prev).abs() / prev * 100.0 >= min_pct;
+            if min_pct > 0.0 && !published.get(&item_id).copied().is_none_or(moved) { return; }
+            published.insert(item_id.clone(), price);
+        }
+        let message = serde_json::json!({ "type": "priceUpdate", "data": quote }).to_string();
+        let _ = state.price_events.send(PriceUpdate { item_id: item_id.into(), message: message.into() });
+    });
+}
+
+// =========================================================================
+// 2. 连接生命周期
+// =========================================================================
+
+pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state))
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState) {
+    let (mut sink, mut stream) = socket.split();
+    let (out_tx, mut out_rx) = mpsc::channel::<Utf8Bytes>(OUTBOX_CAPACITY);
+    let mut events = state.price_events.subscribe();
+    let mut subscriptions: HashSet<String> = HashSet::new();
+
+    // 独立的发送任务，避免慢客户端阻塞指令处理
+    let send_task = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            if sink.send(Message::Text(msg)).await.is_err() { break; }
+        }
+        let _ = sink.close().await;
+    });
+
+    loop {
+        tokio::select! {
+            incoming = stream.next() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = handle_command(&state, &text, &mut subscriptions).await;
+                    if out_tx.send(reply.to_string().into()).await.is_err() { break; }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            event = events.recv() => match event {
+                Ok(update) if subscriptions.contains(&*update.item_id) => {
+                    match out_tx.try_send(update.message) {
+                        Err(mpsc::error::TrySendError::Closed(_)) => break,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            tracing::warn!("{}", state.config.read().log_lang.text(Msg::WsSlowConsumer));
+                        }
+                        Ok(_) => {}
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("{}", state.config.read().log_lang.render(Msg::WsLagged, &[&n]));
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+
+    drop(out_tx);
+    let _ = send_task.await;
+}
+
+// =========================================================================
+// 3. 指令分发
+// =========================================================================
+
+async fn handle_command(state: &AppState, text: &str, subscriptions: &mut HashSet<String>) -> serde_json::Value {
+    let cmd = match serde_json::from_str::<WsCommand>(text) {
+        Ok(c) => c,
+        Err(e) => return serde_json::json!({ "type": "error", "error": format!("无法解析指令: {}", e) }),
+    };
+
+    match cmd {
+        WsCommand::Quote { item_ids, quantities, cursor } => {
+            match api::quote_market_page(state, item_ids, &quantities, cursor.as_deref(), false).await {
+                Ok(quote) => serde_json::json!({ "type": "quote", "data": quote }),
+                Err(e) => serde_json::json!({ "type": "error", "error": e.to_string() }),
+            }
+        }
+        WsCommand::Subscribe { item_ids } => {
+            subscriptions.extend(item_ids);
+            serde_json::json!({ "type": "subscribed", "itemIds": subscriptions })
+        }
+        WsCommand::Unsubscribe { item_ids } => {
+            for id in &item_ids { subscriptions.remove(id); }
+            serde_json::json!({ "type": "subscribed", "itemIds": subscriptions })
+        }
+        WsCommand::Trade { is_buy, request } => {
+            let default_action = if is_buy { TradeAction::Buy } else { TradeAction::Sell };
+            match api::execute_trade(state, request, default_action).await {
+                Ok(resp) => serde_json::json!({ "type": "trade", "data": resp }),
+                Err(e) => serde_json::json!({ "type": "error", "error": e.to_string() }),
+            }
+        }
+    }
+}